@@ -0,0 +1,356 @@
+//! Incremental backups of a storage. The SSTs are immutable, so they are shared by all backups, and each backup
+//! only copies the SSTs that are not in the backup directory yet, straight from the storage directory. An SST is
+//! looked up in the backup directory by its id and size, without reading it. The layout of the backup directory is:
+//!
+//! ```text
+//! shared/{sst_id}_{checksum}_{size}.sst   SSTs referenced by one or more backups
+//! private/{backup_id}/                    WALs and manifest of each backup
+//! meta/{backup_id}                        the catalog entry of each backup, in JSON
+//! ```
+//!
+//! The backup directory is on the file system of the options the engine is opened with, which must be the one
+//! of the storage being backed up.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::env::FileSystem;
+use crate::lsm_storage::{LsmStorageOptions, MiniLsm};
+use crate::repair::verify_sst;
+
+/// A file of a backup.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackupFile {
+    /// The name of the file in the storage directory
+    pub name: String,
+    /// The path of the file relative to the backup directory
+    pub path: String,
+    pub size: u64,
+    /// The crc32 of the file content
+    pub checksum: u32,
+}
+
+/// The catalog entry of a backup.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackupInfo {
+    pub backup_id: usize,
+    /// Seconds since the unix epoch when the backup was created
+    pub timestamp: u64,
+    pub files: Vec<BackupFile>,
+}
+
+impl BackupInfo {
+    /// The total size of the files in the backup, including the shared ones.
+    pub fn size(&self) -> u64 {
+        self.files.iter().map(|file| file.size).sum()
+    }
+}
+
+pub struct BackupEngine {
+    dir: PathBuf,
+    fs: Arc<dyn FileSystem>,
+}
+
+/// The size of the chunks files are read in when they are copied or checksummed.
+const CHUNK_SIZE: u64 = 64 << 10;
+
+/// Read the first `len` bytes of the file, or the whole file if `len` is `None`, chunk by chunk into `write`,
+/// and return the size read and its checksum.
+fn checksum_of(
+    fs: &dyn FileSystem,
+    path: &Path,
+    len: Option<u64>,
+    mut write: impl FnMut(&[u8]) -> Result<()>,
+) -> Result<(u64, u32)> {
+    let file = fs.open(path)?;
+    let size = match len {
+        Some(len) => len,
+        None => file.size()?,
+    };
+    let mut hasher = crc32fast::Hasher::new();
+    for offset in (0..size).step_by(CHUNK_SIZE as usize) {
+        let chunk = file.read_at(offset, CHUNK_SIZE.min(size - offset) as usize)?;
+        hasher.update(&chunk);
+        write(&chunk)?;
+    }
+    Ok((size, hasher.finalize()))
+}
+
+/// Copy the first `len` bytes of the file, or the whole file if `len` is `None`, and return the size copied and
+/// its checksum.
+fn copy_to(fs: &dyn FileSystem, src: &Path, dst: &Path, len: Option<u64>) -> Result<(u64, u32)> {
+    let mut file = fs.create(dst)?;
+    let result = checksum_of(fs, src, len, |chunk| file.append(chunk))?;
+    file.sync()?;
+    Ok(result)
+}
+
+/// Copy the file and return its size and checksum. The copy is written to a temporary file and renamed, so
+/// that an interrupted copy never leaves a partial file at `dst`.
+fn copy_with_checksum(fs: &dyn FileSystem, src: &Path, dst: &Path) -> Result<(u64, u32)> {
+    let mut tmp_name = dst.file_name().unwrap().to_owned();
+    tmp_name.push(".tmp");
+    let tmp_path = dst.with_file_name(tmp_name);
+    let result = copy_to(fs, src, &tmp_path, None)?;
+    fs.rename(&tmp_path, dst)?;
+    Ok(result)
+}
+
+impl BackupEngine {
+    /// Open the backup directory on the file system of the options, creating it if it does not exist.
+    pub fn open(dir: impl AsRef<Path>, options: &LsmStorageOptions) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        let fs = options.fs.clone();
+        for sub_dir in ["shared", "private", "meta"] {
+            fs.create_dir_all(&dir.join(sub_dir))
+                .context("failed to create backup dir")?;
+        }
+        Ok(Self { dir, fs })
+    }
+
+    fn path_of_meta(&self, backup_id: usize) -> PathBuf {
+        self.dir.join("meta").join(backup_id.to_string())
+    }
+
+    fn path_of_private(&self, backup_id: usize) -> PathBuf {
+        self.dir.join("private").join(backup_id.to_string())
+    }
+
+    /// The backups in the catalog, from earliest to latest.
+    pub fn backups(&self) -> Result<Vec<BackupInfo>> {
+        let mut backups = Vec::new();
+        for name in self.fs.list(&self.dir.join("meta"))? {
+            // skip the catalog entries being written
+            if name.parse::<usize>().is_err() {
+                continue;
+            }
+            let path = self.dir.join("meta").join(name);
+            let data = self.fs.read(&path)?;
+            let backup: BackupInfo = serde_json::from_slice(&data)
+                .with_context(|| format!("invalid catalog entry {}", path.display()))?;
+            backups.push(backup);
+        }
+        backups.sort_by_key(|backup| backup.backup_id);
+        Ok(backups)
+    }
+
+    pub fn backup(&self, backup_id: usize) -> Result<BackupInfo> {
+        let path = self.path_of_meta(backup_id);
+        if !self.fs.exists(&path) {
+            bail!("backup {} does not exist", backup_id);
+        }
+        Ok(serde_json::from_slice(&self.fs.read(&path)?)?)
+    }
+
+    /// The paths and checksums of the SSTs in the backup directory, by SST id and size.
+    fn shared_ssts(&self) -> Result<HashMap<(usize, u64), (String, u32)>> {
+        let mut ssts = HashMap::new();
+        for name in self.fs.list(&self.dir.join("shared"))? {
+            let Some(stem) = name.strip_suffix(".sst") else {
+                continue;
+            };
+            let parts = stem.split('_').collect::<Vec<_>>();
+            let [sst_id, checksum, size] = parts[..] else {
+                continue;
+            };
+            let (Ok(sst_id), Ok(checksum), Ok(size)) =
+                (sst_id.parse(), checksum.parse(), size.parse())
+            else {
+                continue;
+            };
+            ssts.insert((sst_id, size), (format!("shared/{}", name), checksum));
+        }
+        Ok(ssts)
+    }
+
+    /// Back up the storage, copying only the SSTs not in the backup directory yet, which are looked up by SST id
+    /// and size. The files are copied from the storage directory without stopping writes.
+    pub fn create_backup(&self, storage: &MiniLsm) -> Result<BackupInfo> {
+        let inner = &storage.inner;
+        inner.check_read_only()?;
+        let backup_id = self
+            .backups()?
+            .last()
+            .map_or(1, |backup| backup.backup_id + 1);
+        let private_dir = self.path_of_private(backup_id);
+        if self.fs.exists(&private_dir) {
+            // left by an interrupted backup
+            self.fs.remove_dir_all(&private_dir)?;
+        }
+        self.fs.create_dir_all(&private_dir)?;
+
+        let live_files = inner.live_files()?;
+        live_files.write_manifest(inner, &private_dir)?;
+        let mut files = Vec::new();
+        for name in self.fs.list(&private_dir)? {
+            let path = format!("private/{}/{}", backup_id, name);
+            let (size, checksum) = checksum_of(&*self.fs, &self.dir.join(&path), None, |_| Ok(()))?;
+            files.push(BackupFile {
+                name,
+                path,
+                size,
+                checksum,
+            });
+        }
+        let mut shared_ssts = self.shared_ssts()?;
+        let mut num_copied_ssts = 0;
+        for (sst_id, size) in &live_files.ssts {
+            let src = inner.path_of_sst(*sst_id);
+            let name = src.file_name().unwrap().to_string_lossy().into_owned();
+            let (path, size, checksum) = match shared_ssts.remove(&(*sst_id, *size)) {
+                Some((path, checksum)) => (path, *size, checksum),
+                None => {
+                    let tmp_path = self.dir.join("shared").join(format!("{}.tmp", name));
+                    let (size, checksum) = copy_to(&*self.fs, &src, &tmp_path, None)?;
+                    let stem = name.strip_suffix(".sst").unwrap();
+                    let path = format!("shared/{}_{}_{}.sst", stem, checksum, size);
+                    self.fs.rename(&tmp_path, &self.dir.join(&path))?;
+                    num_copied_ssts += 1;
+                    (path, size, checksum)
+                }
+            };
+            files.push(BackupFile {
+                name,
+                path,
+                size,
+                checksum,
+            });
+        }
+        for (id, len) in &live_files.wals {
+            let src = inner.path_of_wal(*id);
+            let name = src.file_name().unwrap().to_string_lossy().into_owned();
+            let path = format!("private/{}/{}", backup_id, name);
+            let (size, checksum) = copy_to(&*self.fs, &src, &self.dir.join(&path), Some(*len))?;
+            files.push(BackupFile {
+                name,
+                path,
+                size,
+                checksum,
+            });
+        }
+        drop(live_files);
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        self.fs.sync_dir(&self.dir.join("shared"))?;
+        self.fs.sync_dir(&private_dir)?;
+
+        let backup = BackupInfo {
+            backup_id,
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            files,
+        };
+        // the backup exists once its catalog entry is in place
+        let meta_path = self.path_of_meta(backup_id);
+        let tmp_path = meta_path.with_extension("tmp");
+        self.fs.write(&tmp_path, &serde_json::to_vec(&backup)?)?;
+        self.fs.rename(&tmp_path, &meta_path)?;
+        self.fs.sync_dir(&self.dir.join("meta"))?;
+        println!(
+            "backup {} created with {} files, {} new SSTs copied",
+            backup_id,
+            backup.files.len(),
+            num_copied_ssts
+        );
+        Ok(backup)
+    }
+
+    /// Delete the backup, and the shared SSTs no longer referenced by any backup.
+    pub fn delete_backup(&self, backup_id: usize) -> Result<()> {
+        self.backup(backup_id)?;
+        self.fs.remove_file(&self.path_of_meta(backup_id))?;
+        self.fs.sync_dir(&self.dir.join("meta"))?;
+        self.garbage_collect()
+    }
+
+    /// Delete all backups but the latest `num_backups_to_keep` ones.
+    pub fn purge_old_backups(&self, num_backups_to_keep: usize) -> Result<()> {
+        let backups = self.backups()?;
+        let num_to_purge = backups.len().saturating_sub(num_backups_to_keep);
+        for backup in &backups[..num_to_purge] {
+            self.fs.remove_file(&self.path_of_meta(backup.backup_id))?;
+        }
+        self.fs.sync_dir(&self.dir.join("meta"))?;
+        self.garbage_collect()
+    }
+
+    /// Delete the shared SSTs and private directories not referenced by the catalog.
+    fn garbage_collect(&self) -> Result<()> {
+        let backups = self.backups()?;
+        let referenced = backups
+            .iter()
+            .flat_map(|backup| backup.files.iter().map(|file| self.dir.join(&file.path)))
+            .collect::<std::collections::HashSet<_>>();
+        for name in self.fs.list(&self.dir.join("shared"))? {
+            let path = self.dir.join("shared").join(name);
+            if !referenced.contains(&path) {
+                self.fs.remove_file(&path)?;
+            }
+        }
+        for name in self.fs.list_dirs(&self.dir.join("private"))? {
+            let is_live = name
+                .parse::<usize>()
+                .is_ok_and(|id| backups.iter().any(|backup| backup.backup_id == id));
+            if !is_live {
+                self.fs
+                    .remove_dir_all(&self.dir.join("private").join(name))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Verify the files of the backup against the checksums in the catalog, and the block and meta checksums
+    /// of the SSTs, without restoring it.
+    pub fn verify_backup(&self, backup_id: usize) -> Result<()> {
+        let backup = self.backup(backup_id)?;
+        for file in &backup.files {
+            let path = self.dir.join(&file.path);
+            let (size, checksum) = checksum_of(&*self.fs, &path, None, |_| Ok(()))
+                .with_context(|| format!("failed to read {} of backup {}", file.path, backup_id))?;
+            ensure!(
+                size == file.size && checksum == file.checksum,
+                "{} of backup {} is corrupted",
+                file.path,
+                backup_id
+            );
+            if let Some(sst_id) = file.name.strip_suffix(".sst") {
+                verify_sst(&*self.fs, sst_id.parse()?, &path).with_context(|| {
+                    format!("{} of backup {} is corrupted", file.path, backup_id)
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Restore the backup into `db_dir`, which must not exist or be empty. The restored directory can be opened
+    /// as an independent storage.
+    pub fn restore_backup(&self, backup_id: usize, db_dir: impl AsRef<Path>) -> Result<()> {
+        let backup = self.backup(backup_id)?;
+        let db_dir = db_dir.as_ref();
+        self.fs
+            .create_dir_all(db_dir)
+            .context("failed to create DB dir")?;
+        if !self.fs.list(db_dir)?.is_empty() || !self.fs.list_dirs(db_dir)?.is_empty() {
+            bail!("DB dir {} is not empty", db_dir.display());
+        }
+        for file in &backup.files {
+            let (size, checksum) = copy_with_checksum(
+                &*self.fs,
+                &self.dir.join(&file.path),
+                &db_dir.join(&file.name),
+            )?;
+            ensure!(
+                size == file.size && checksum == file.checksum,
+                "{} of backup {} is corrupted",
+                file.path,
+                backup_id
+            );
+        }
+        self.fs.sync_dir(db_dir)?;
+        println!("backup {} restored to {}", backup_id, db_dir.display());
+        Ok(())
+    }
+}
@@ -0,0 +1,174 @@
+mod wrapper;
+
+use std::path::PathBuf;
+
+use anyhow::Result;
+use clap::{Parser, ValueEnum};
+use wrapper::mini_lsm_wrapper;
+
+use mini_lsm_wrapper::backup::BackupEngine;
+use mini_lsm_wrapper::compact::{
+    CompactionOptions, LeveledCompactionOptions, SimpleLeveledCompactionOptions,
+    TieredCompactionOptions,
+};
+use mini_lsm_wrapper::lsm_storage::{LsmStorageOptions, MiniLsm};
+use mini_lsm_wrapper::repair::repair_db;
+
+#[derive(Debug, Clone, ValueEnum)]
+enum CompactionStrategy {
+    Simple,
+    Leveled,
+    Tiered,
+    None,
+}
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+enum Args {
+    /// Rebuild the manifest from the SST and WAL files
+    Repair {
+        #[arg(long, default_value = "lsm.db")]
+        path: PathBuf,
+        #[arg(long, default_value = "leveled")]
+        compaction: CompactionStrategy,
+    },
+    /// Back up the storage, copying only the SSTs not backed up yet
+    Backup {
+        #[arg(long, default_value = "lsm.db")]
+        path: PathBuf,
+        #[arg(long, default_value = "lsm.backup")]
+        backup_dir: PathBuf,
+        #[arg(long, default_value = "leveled")]
+        compaction: CompactionStrategy,
+        #[arg(long)]
+        enable_wal: bool,
+    },
+    /// List the backups in the backup directory
+    ListBackups {
+        #[arg(long, default_value = "lsm.backup")]
+        backup_dir: PathBuf,
+    },
+    /// Verify the checksums of the files of a backup
+    VerifyBackup {
+        #[arg(long, default_value = "lsm.backup")]
+        backup_dir: PathBuf,
+        #[arg(long)]
+        backup_id: usize,
+    },
+    /// Restore a backup into an empty directory
+    RestoreBackup {
+        #[arg(long, default_value = "lsm.backup")]
+        backup_dir: PathBuf,
+        #[arg(long)]
+        backup_id: usize,
+        #[arg(long)]
+        path: PathBuf,
+    },
+    /// Delete all backups but the latest ones
+    PurgeBackups {
+        #[arg(long, default_value = "lsm.backup")]
+        backup_dir: PathBuf,
+        #[arg(long)]
+        keep: usize,
+    },
+}
+
+fn compaction_options(compaction: &CompactionStrategy) -> CompactionOptions {
+    match compaction {
+        CompactionStrategy::None => CompactionOptions::NoCompaction,
+        CompactionStrategy::Simple => CompactionOptions::Simple(SimpleLeveledCompactionOptions {
+            size_ratio_percent: 200,
+            level0_file_num_compaction_trigger: 2,
+            max_levels: 4,
+            level0_file_num_intra_compaction_trigger: None,
+        }),
+        CompactionStrategy::Tiered => CompactionOptions::Tiered(TieredCompactionOptions {
+            num_tiers: 3,
+            max_size_amplification_percent: 200,
+            size_ratio: 1,
+            min_merge_width: 2,
+        }),
+        CompactionStrategy::Leveled => CompactionOptions::Leveled(LeveledCompactionOptions {
+            level0_file_num_compaction_trigger: 2,
+            max_levels: 4,
+            base_level_size_mb: 128,
+            level_size_multiplier: 2,
+            level0_file_num_intra_compaction_trigger: None,
+        }),
+    }
+}
+
+fn main() -> Result<()> {
+    match Args::parse() {
+        Args::Repair { path, compaction } => {
+            let report = repair_db(
+                &path,
+                &LsmStorageOptions {
+                    compaction_options: compaction_options(&compaction),
+                    ..LsmStorageOptions::default_for_week1_test()
+                },
+            )?;
+            println!("SSTs: {:?}", report.sst_ids);
+            println!("WALs: {:?}", report.wal_ids);
+            if !report.corrupted_sst_ids.is_empty() || !report.corrupted_wal_ids.is_empty() {
+                println!(
+                    "quarantined corrupted SSTs {:?} and WALs {:?}",
+                    report.corrupted_sst_ids, report.corrupted_wal_ids
+                );
+            }
+        }
+        Args::Backup {
+            path,
+            backup_dir,
+            compaction,
+            enable_wal,
+        } => {
+            let lsm = MiniLsm::open(
+                path,
+                LsmStorageOptions {
+                    compaction_options: compaction_options(&compaction),
+                    enable_wal,
+                    ..LsmStorageOptions::default_for_week1_test()
+                },
+            )?;
+            BackupEngine::open(backup_dir, &LsmStorageOptions::default_for_week1_test())?
+                .create_backup(&lsm)?;
+            lsm.close()?;
+        }
+        Args::ListBackups { backup_dir } => {
+            for backup in
+                BackupEngine::open(backup_dir, &LsmStorageOptions::default_for_week1_test())?
+                    .backups()?
+            {
+                println!(
+                    "backup {}: timestamp={} files={} size={}",
+                    backup.backup_id,
+                    backup.timestamp,
+                    backup.files.len(),
+                    backup.size()
+                );
+            }
+        }
+        Args::VerifyBackup {
+            backup_dir,
+            backup_id,
+        } => {
+            BackupEngine::open(backup_dir, &LsmStorageOptions::default_for_week1_test())?
+                .verify_backup(backup_id)?;
+            println!("backup {} verified", backup_id);
+        }
+        Args::RestoreBackup {
+            backup_dir,
+            backup_id,
+            path,
+        } => {
+            BackupEngine::open(backup_dir, &LsmStorageOptions::default_for_week1_test())?
+                .restore_backup(backup_id, path)?;
+        }
+        Args::PurgeBackups { backup_dir, keep } => {
+            BackupEngine::open(backup_dir, &LsmStorageOptions::default_for_week1_test())?
+                .purge_old_backups(keep)?;
+        }
+    }
+    Ok(())
+}
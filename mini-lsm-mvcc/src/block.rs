@@ -1,8 +1,10 @@
 mod builder;
+mod cache;
 mod iterator;
 
 pub use builder::BlockBuilder;
 use bytes::{Buf, BufMut, Bytes};
+pub use cache::BlockCache;
 pub use iterator::BlockIterator;
 
 pub(crate) const SIZEOF_U16: usize = std::mem::size_of::<u16>();
@@ -10,13 +12,13 @@ pub(crate) const SIZEOF_U16: usize = std::mem::size_of::<u16>();
 /// A block is the smallest unit of read and caching in LSM tree. It is a collection of sorted
 /// key-value pairs.
 pub struct Block {
-    pub(crate) data: Vec<u8>,
+    pub(crate) data: Bytes,
     pub(crate) offsets: Vec<u16>,
 }
 
 impl Block {
     pub fn encode(&self) -> Bytes {
-        let mut buf = self.data.clone();
+        let mut buf = self.data.to_vec();
         let offsets_len = self.offsets.len();
         for offset in &self.offsets {
             buf.put_u16(*offset);
@@ -26,7 +28,17 @@ impl Block {
         buf.into()
     }
 
+    /// The size of the block in memory, which is charged to the block cache.
+    pub fn size(&self) -> usize {
+        self.data.len() + self.offsets.len() * SIZEOF_U16
+    }
+
     pub fn decode(data: &[u8]) -> Self {
+        Self::decode_bytes(Bytes::copy_from_slice(data))
+    }
+
+    /// Decode the block without copying, so that the block shares the buffer of `data`.
+    pub fn decode_bytes(data: Bytes) -> Self {
         // get number of elements in the block
         let entry_offsets_len = (&data[data.len() - SIZEOF_U16..]).get_u16() as usize;
         let data_end = data.len() - SIZEOF_U16 - entry_offsets_len * SIZEOF_U16;
@@ -37,7 +49,7 @@ impl Block {
             .map(|mut x| x.get_u16())
             .collect();
         // retrieve data
-        let data = data[0..data_end].to_vec();
+        let data = data.slice(0..data_end);
         Self { data, offsets }
     }
 }
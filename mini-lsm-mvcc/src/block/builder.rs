@@ -89,7 +89,7 @@ impl BlockBuilder {
             panic!("block should not be empty");
         }
         Block {
-            data: self.data,
+            data: self.data.into(),
             offsets: self.offsets,
         }
     }
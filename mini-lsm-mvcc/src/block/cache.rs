@@ -0,0 +1,65 @@
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use moka::sync::ConcurrentCacheExt;
+
+use super::Block;
+
+/// A cache of data blocks, bounded by the total size of the blocks in bytes. A cache can be shared by
+/// several storages, as the blocks are keyed by the cache ids of the SSTs, which are unique in the process.
+pub struct BlockCache {
+    cache: moka::sync::Cache<(usize, usize), Arc<Block>>,
+    capacity: u64,
+}
+
+impl BlockCache {
+    /// Create a cache holding up to `capacity` bytes of blocks.
+    pub fn new(capacity: u64) -> Self {
+        let cache = moka::sync::Cache::builder()
+            .max_capacity(capacity)
+            .weigher(|_, block: &Arc<Block>| block.size().try_into().unwrap_or(u32::MAX))
+            .build();
+        Self { cache, capacity }
+    }
+
+    /// Get the block of the SST, or read it with `init` and cache it. Concurrent reads of the same block
+    /// wait for a single `init`.
+    pub fn try_get_with(
+        &self,
+        cache_id: usize,
+        block_idx: usize,
+        init: impl FnOnce() -> Result<Arc<Block>>,
+    ) -> Result<Arc<Block>> {
+        self.cache
+            .try_get_with((cache_id, block_idx), init)
+            .map_err(|e| anyhow!("{}", e))
+    }
+
+    /// Drop the blocks of the SST. A block read through a memory mapping is a slice of the mapping of the
+    /// whole file, so a cached block of a deleted SST would keep its disk space and mapping alive.
+    pub fn invalidate_sst(&self, cache_id: usize, num_blocks: usize) {
+        for block_idx in 0..num_blocks {
+            self.cache.invalidate(&(cache_id, block_idx));
+        }
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    /// The total size in bytes of the cached blocks.
+    pub fn size(&self) -> u64 {
+        // apply the pending insertions and evictions first
+        self.cache.sync();
+        self.cache.weighted_size()
+    }
+}
+
+impl Debug for BlockCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BlockCache")
+            .field("capacity", &self.capacity)
+            .finish_non_exhaustive()
+    }
+}
@@ -1,10 +1,10 @@
 use std::sync::Arc;
 
-use bytes::Buf;
+use bytes::{Buf, Bytes};
 
 use crate::{
     block::SIZEOF_U16,
-    key::{KeySlice, KeyVec},
+    key::{KeyBytes, KeySlice, KeyVec},
 };
 
 use super::Block;
@@ -19,18 +19,18 @@ pub struct BlockIterator {
     value_range: (usize, usize),
     /// the current index at the iterator position
     idx: usize,
-    /// the first key in the block
-    first_key: KeyVec,
+    /// the first key in the block, sharing the buffer of the block
+    first_key: KeyBytes,
 }
 
 impl Block {
-    fn get_first_key(&self) -> KeyVec {
+    fn get_first_key(&self) -> KeyBytes {
         let mut buf = &self.data[..];
         buf.get_u16();
         let key_len = buf.get_u16() as usize;
-        let key = &buf[..key_len];
+        let key = self.data.slice(2 * SIZEOF_U16..2 * SIZEOF_U16 + key_len);
         buf.advance(key_len);
-        KeyVec::from_vec_with_ts(key.to_vec(), buf.get_u64())
+        KeyBytes::from_bytes_with_ts(key, buf.get_u64())
     }
 }
 
@@ -71,6 +71,15 @@ impl BlockIterator {
         &self.block.data[self.value_range.0..self.value_range.1]
     }
 
+    /// Returns the value of the current entry, sharing the buffer of the block. The value keeps the
+    /// whole block alive, or the whole file if the block was read through a memory mapping.
+    pub fn value_bytes(&self) -> Bytes {
+        debug_assert!(!self.key.is_empty(), "invalid iterator");
+        self.block
+            .data
+            .slice(self.value_range.0..self.value_range.1)
+    }
+
     /// Returns true if the iterator is valid.
     pub fn is_valid(&self) -> bool {
         !self.key.is_empty()
@@ -108,6 +117,7 @@ impl BlockIterator {
         let overlap_len = entry.get_u16() as usize;
         let key_len = entry.get_u16() as usize;
         let key = &entry[..key_len];
+        // reuse the buffer of the previous key
         self.key.clear();
         self.key.append(&self.first_key.key_ref()[..overlap_len]);
         self.key.append(key);
@@ -123,18 +133,40 @@ impl BlockIterator {
         entry.advance(value_len);
     }
 
+    /// Compare the key of the idx-th entry with `key` without decoding the entry key.
+    fn cmp_key_at(&self, idx: usize, key: KeySlice) -> std::cmp::Ordering {
+        let mut entry = &self.block.data[self.block.offsets[idx] as usize..];
+        let overlap_len = entry.get_u16() as usize;
+        let key_len = entry.get_u16() as usize;
+        let prefix = &self.first_key.key_ref()[..overlap_len];
+        let suffix = &entry[..key_len];
+        entry.advance(key_len);
+        let ts = entry.get_u64();
+        let target = key.key_ref();
+        let ord = if target.len() < prefix.len() {
+            prefix.cmp(target)
+        } else {
+            prefix
+                .cmp(&target[..prefix.len()])
+                .then_with(|| suffix.cmp(&target[prefix.len()..]))
+        };
+        // a larger timestamp sorts first
+        ord.then_with(|| key.ts().cmp(&ts))
+    }
+
     /// Seek to the first key that is >= `key`.
     pub fn seek_to_key(&mut self, key: KeySlice) {
         let mut low = 0;
         let mut high = self.block.offsets.len();
         while low < high {
             let mid = low + (high - low) / 2;
-            self.seek_to(mid);
-            assert!(self.is_valid());
-            match self.key().cmp(&key) {
+            match self.cmp_key_at(mid, key) {
                 std::cmp::Ordering::Less => low = mid + 1,
                 std::cmp::Ordering::Greater => high = mid,
-                std::cmp::Ordering::Equal => return,
+                std::cmp::Ordering::Equal => {
+                    low = mid;
+                    break;
+                }
             }
         }
         self.seek_to(low);
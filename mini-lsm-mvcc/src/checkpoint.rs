@@ -0,0 +1,141 @@
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use parking_lot::RwLockWriteGuard;
+
+use crate::lsm_storage::LsmStorageInner;
+use crate::manifest::{Manifest, ManifestRecord, ManifestSnapshot};
+
+/// The files of a consistent view of the storage, which are not deleted until it is dropped.
+pub(crate) struct LiveFiles<'a> {
+    /// The state of the view, with the memtables of the WALs
+    pub(crate) snapshot: ManifestSnapshot,
+    /// The id and file size of each SST
+    pub(crate) ssts: Vec<(usize, u64)>,
+    /// The id of each WAL and its length at the time of the view
+    pub(crate) wals: Vec<(usize, u64)>,
+    _file_deletion_lock: RwLockWriteGuard<'a, ()>,
+}
+
+impl LiveFiles<'_> {
+    /// Write a manifest with a snapshot of the view into `dir`.
+    pub(crate) fn write_manifest(&self, inner: &LsmStorageInner, dir: &Path) -> Result<()> {
+        let manifest = Manifest::create(inner.options.fs.clone(), dir)?;
+        manifest.add_record_when_init(ManifestRecord::Snapshot(self.snapshot.clone()))
+    }
+}
+
+impl LsmStorageInner {
+    /// Take a consistent view of the files of the storage without stopping writes. Without WAL, the memtables
+    /// are flushed first so that the SSTs have all writes.
+    pub(crate) fn live_files(&self) -> Result<LiveFiles<'_>> {
+        if !self.options.enable_wal {
+            {
+                let state_lock = self.state_lock.lock();
+                if !self.state.read().memtable.is_empty() {
+                    self.force_freeze_memtable(&state_lock)?;
+                }
+            }
+            self.force_flush_imm_memtables()?;
+        }
+
+        // SSTs and WALs in the snapshot are not deleted until the view is dropped. The deletion lock is taken
+        // with the state lock held, as flushes retire WALs with the state lock held.
+        let (snapshot, state, file_deletion_lock) = {
+            let _state_lock = self.state_lock.lock();
+            let file_deletion_lock = self.file_deletion_lock.write();
+            (
+                self.manifest_snapshot(),
+                self.state.read().clone(),
+                file_deletion_lock,
+            )
+        };
+
+        let ssts = snapshot
+            .l0_sstables
+            .iter()
+            .chain(snapshot.levels.iter().flat_map(|(_, ssts)| ssts))
+            .map(|id| (*id, state.sstables[id].table_size()))
+            .collect::<Vec<_>>();
+        let mut wals = Vec::new();
+        if self.options.enable_wal {
+            for memtable in state
+                .imm_memtables
+                .iter()
+                .rev()
+                .chain(std::iter::once(&state.memtable))
+            {
+                // the active WAL may grow after the view is taken, so it is cut at the current length
+                wals.push((memtable.id(), memtable.wal_len()?.unwrap_or_default()));
+            }
+        }
+        Ok(LiveFiles {
+            snapshot: ManifestSnapshot {
+                memtables: wals.iter().map(|(id, _)| *id).collect(),
+                ..snapshot
+            },
+            ssts,
+            wals,
+            _file_deletion_lock: file_deletion_lock,
+        })
+    }
+
+    /// Create a checkpoint of the storage in `dir`, which must not exist, without stopping writes. The
+    /// checkpoint is a directory that can be opened as an independent storage with the same options.
+    ///
+    /// The SSTs are hard-linked (or copied if `dir` is on another file system), the WALs of the memtables are
+    /// copied up to the last write, and a new manifest is written with a snapshot of the current state. Without
+    /// WAL, the memtables are flushed first so that the checkpoint has all writes.
+    pub fn create_checkpoint(&self, dir: impl AsRef<Path>) -> Result<()> {
+        self.check_read_only()?;
+        let dir = dir.as_ref();
+        let fs = &*self.options.fs;
+        if fs.exists(dir) {
+            bail!("checkpoint dir {} already exists", dir.display());
+        }
+
+        // The checkpoint is built in a temporary dir and renamed when complete, so that a crash never leaves
+        // a partial checkpoint in `dir`.
+        let mut tmp_name = dir
+            .file_name()
+            .context("invalid checkpoint dir")?
+            .to_owned();
+        tmp_name.push(".tmp");
+        let tmp_dir = dir.with_file_name(tmp_name);
+        if fs.exists(&tmp_dir) {
+            fs.remove_dir_all(&tmp_dir)?;
+        }
+        fs.create_dir_all(&tmp_dir)
+            .context("failed to create checkpoint dir")?;
+
+        let live_files = self.live_files()?;
+        for (id, _) in &live_files.ssts {
+            let src = self.path_of_sst(*id);
+            let dst = Self::path_of_sst_static(&tmp_dir, *id);
+            if fs.hard_link(&src, &dst).is_err() {
+                fs.copy(&src, &dst)?;
+            }
+        }
+        for (id, len) in &live_files.wals {
+            let dst = Self::path_of_wal_static(&tmp_dir, *id);
+            fs.copy(&self.path_of_wal(*id), &dst)?;
+            fs.truncate(&dst, *len)?;
+        }
+
+        let (num_ssts, num_wals) = (live_files.ssts.len(), live_files.wals.len());
+        live_files.write_manifest(self, &tmp_dir)?;
+        drop(live_files);
+        fs.sync_dir(&tmp_dir)?;
+        fs.rename(&tmp_dir, dir)?;
+        if let Some(parent) = dir.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            fs.sync_dir(parent)?;
+        }
+        println!(
+            "checkpoint created at {} with {} SSTs and {} WALs",
+            dir.display(),
+            num_ssts,
+            num_wals
+        );
+        Ok(())
+    }
+}
@@ -6,7 +6,7 @@ use std::collections::HashSet;
 use std::sync::Arc;
 use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{bail, ensure, Result};
 pub use leveled::{LeveledCompactionController, LeveledCompactionOptions, LeveledCompactionTask};
 use serde::{Deserialize, Serialize};
 pub use simple_leveled::{
@@ -19,11 +19,13 @@ use crate::iterators::merge_iterator::MergeIterator;
 use crate::iterators::two_merge_iterator::TwoMergeIterator;
 use crate::iterators::StorageIterator;
 use crate::key::KeySlice;
-use crate::lsm_storage::{CompactionFilter, LsmStorageInner, LsmStorageState};
-use crate::manifest::ManifestRecord;
+use crate::lsm_storage::{
+    BackgroundErrorReason, CompactionFilter, LsmStorageInner, LsmStorageState,
+};
+use crate::manifest::{ManifestRecord, VersionEdit};
 use crate::table::{SsTable, SsTableBuilder, SsTableIterator};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum CompactionTask {
     Leveled(LeveledCompactionTask),
     Tiered(TieredCompactionTask),
@@ -32,6 +34,10 @@ pub enum CompactionTask {
         l0_sstables: Vec<usize>,
         l1_sstables: Vec<usize>,
     },
+    /// Merges a run of adjacent L0 SSTs into L0, used by leveled and simple leveled compaction when L0 is congested.
+    IntraL0 {
+        l0_sstables: Vec<usize>,
+    },
 }
 
 impl CompactionTask {
@@ -41,6 +47,7 @@ impl CompactionTask {
             CompactionTask::Leveled(task) => task.is_lower_level_bottom_level,
             CompactionTask::Simple(task) => task.is_lower_level_bottom_level,
             CompactionTask::Tiered(task) => task.bottom_tier_included,
+            CompactionTask::IntraL0 { .. } => false,
         }
     }
 }
@@ -53,14 +60,39 @@ pub(crate) enum CompactionController {
 }
 
 impl CompactionController {
+    pub fn new(options: &CompactionOptions) -> Self {
+        match options {
+            CompactionOptions::Leveled(options) => {
+                CompactionController::Leveled(LeveledCompactionController::new(options.clone()))
+            }
+            CompactionOptions::Tiered(options) => {
+                CompactionController::Tiered(TieredCompactionController::new(options.clone()))
+            }
+            CompactionOptions::Simple(options) => CompactionController::Simple(
+                SimpleLeveledCompactionController::new(options.clone()),
+            ),
+            CompactionOptions::NoCompaction => CompactionController::NoCompaction,
+        }
+    }
+
     pub fn generate_compaction_task(&self, snapshot: &LsmStorageState) -> Option<CompactionTask> {
+        // Compacting L0 into the next level also relieves L0, so intra-L0 compaction only runs when L0 is
+        // congested but the controller compacts another level or none at all.
         match self {
-            CompactionController::Leveled(ctrl) => ctrl
-                .generate_compaction_task(snapshot)
-                .map(CompactionTask::Leveled),
-            CompactionController::Simple(ctrl) => ctrl
-                .generate_compaction_task(snapshot)
-                .map(CompactionTask::Simple),
+            CompactionController::Leveled(ctrl) => match ctrl.generate_compaction_task(snapshot) {
+                Some(task) if task.upper_level.is_none() => Some(CompactionTask::Leveled(task)),
+                task => ctrl
+                    .generate_intra_l0_compaction_task(snapshot)
+                    .map(|l0_sstables| CompactionTask::IntraL0 { l0_sstables })
+                    .or(task.map(CompactionTask::Leveled)),
+            },
+            CompactionController::Simple(ctrl) => match ctrl.generate_compaction_task(snapshot) {
+                Some(task) if task.upper_level.is_none() => Some(CompactionTask::Simple(task)),
+                task => ctrl
+                    .generate_intra_l0_compaction_task(snapshot)
+                    .map(|l0_sstables| CompactionTask::IntraL0 { l0_sstables })
+                    .or(task.map(CompactionTask::Simple)),
+            },
             CompactionController::Tiered(ctrl) => ctrl
                 .generate_compaction_task(snapshot)
                 .map(CompactionTask::Tiered),
@@ -84,11 +116,56 @@ impl CompactionController {
             (CompactionController::Tiered(ctrl), CompactionTask::Tiered(task)) => {
                 ctrl.apply_compaction_result(snapshot, task, output)
             }
+            (
+                CompactionController::Leveled(_) | CompactionController::Simple(_),
+                CompactionTask::IntraL0 { l0_sstables },
+            ) => apply_intra_l0_compaction_result(snapshot, l0_sstables, output),
             _ => unreachable!(),
         }
     }
 }
 
+/// Picks the newest run of adjacent L0 SSTs for an intra-L0 compaction, at most `max_ssts` of them. The run stops
+/// before an SST larger than the SSTs picked so far together, which is usually the output of an earlier intra-L0
+/// compaction, so that the same data is not rewritten by every intra-L0 compaction.
+fn pick_intra_l0_sstables(snapshot: &LsmStorageState, max_ssts: usize) -> Vec<usize> {
+    let mut picked = Vec::new();
+    let mut picked_size = 0;
+    for sst_id in &snapshot.l0_sstables {
+        let size = snapshot.sstables[sst_id].table_size();
+        if picked.len() >= max_ssts.max(2) || (picked.len() >= 2 && size > picked_size) {
+            break;
+        }
+        picked.push(*sst_id);
+        picked_size += size;
+    }
+    picked
+}
+
+/// Replaces the compacted L0 SSTs with the output SSTs at the position of the latest compacted SST, so that L0 SSTs
+/// flushed during the compaction stay in front of the output.
+fn apply_intra_l0_compaction_result(
+    snapshot: &LsmStorageState,
+    l0_sstables: &[usize],
+    output: &[usize],
+) -> (LsmStorageState, Vec<usize>) {
+    let mut snapshot = snapshot.clone();
+    let mut l0_ssts_compacted = l0_sstables.iter().copied().collect::<HashSet<_>>();
+    let mut new_l0_sstables = Vec::with_capacity(snapshot.l0_sstables.len());
+    let mut output_added = false;
+    for sst_id in &snapshot.l0_sstables {
+        if !l0_ssts_compacted.remove(sst_id) {
+            new_l0_sstables.push(*sst_id);
+        } else if !output_added {
+            new_l0_sstables.extend(output);
+            output_added = true;
+        }
+    }
+    assert!(l0_ssts_compacted.is_empty());
+    snapshot.l0_sstables = new_l0_sstables;
+    (snapshot, l0_sstables.to_vec())
+}
+
 impl CompactionController {
     pub fn flush_to_l0(&self) -> bool {
         matches!(
@@ -111,70 +188,195 @@ pub enum CompactionOptions {
     NoCompaction,
 }
 
+impl CompactionOptions {
+    /// Checks the options for values the compaction controllers cannot work with.
+    pub fn validate(&self) -> Result<()> {
+        let (l0_trigger, intra_l0_trigger) = match self {
+            CompactionOptions::Leveled(options) => (
+                options.level0_file_num_compaction_trigger,
+                options.level0_file_num_intra_compaction_trigger,
+            ),
+            CompactionOptions::Simple(options) => (
+                options.level0_file_num_compaction_trigger,
+                options.level0_file_num_intra_compaction_trigger,
+            ),
+            CompactionOptions::Tiered(_) | CompactionOptions::NoCompaction => return Ok(()),
+        };
+        if let Some(intra_l0_trigger) = intra_l0_trigger {
+            ensure!(
+                intra_l0_trigger > l0_trigger,
+                "level0_file_num_intra_compaction_trigger ({}) must be larger than level0_file_num_compaction_trigger ({})",
+                intra_l0_trigger,
+                l0_trigger
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Decides which versions to keep when writing sorted key-value pairs into SSTs in compaction and memtable flush.
+///
+/// For each key, the versions above the watermark and the latest version below the watermark are kept, unless the
+/// latest version below the watermark is removed by a compaction filter, or is a delete tombstone when compacting to
+/// the bottom level.
+pub(crate) struct VersionGc {
+    watermark: u64,
+    compact_to_bottom_level: bool,
+    compaction_filters: Vec<CompactionFilter>,
+    last_key: Vec<u8>,
+    first_key_below_watermark: bool,
+}
+
+impl VersionGc {
+    pub(crate) fn new(
+        watermark: u64,
+        compact_to_bottom_level: bool,
+        compaction_filters: Vec<CompactionFilter>,
+    ) -> Self {
+        Self {
+            watermark,
+            compact_to_bottom_level,
+            compaction_filters,
+            last_key: Vec::new(),
+            first_key_below_watermark: false,
+        }
+    }
+
+    /// Whether `key` is another version of the last key passed to `retain`.
+    pub(crate) fn is_same_as_last_key(&self, key: KeySlice) -> bool {
+        key.key_ref() == self.last_key
+    }
+
+    /// Returns whether the key-value pair should be kept. Keys must be passed in order.
+    pub(crate) fn retain(&mut self, key: KeySlice, value: &[u8]) -> bool {
+        let same_as_last_key = self.is_same_as_last_key(key);
+        if !same_as_last_key {
+            self.first_key_below_watermark = true;
+        }
+
+        if self.compact_to_bottom_level
+            && !same_as_last_key
+            && key.ts() <= self.watermark
+            && value.is_empty()
+        {
+            self.last_key.clear();
+            self.last_key.extend(key.key_ref());
+            self.first_key_below_watermark = false;
+            return false;
+        }
+
+        if key.ts() <= self.watermark {
+            if same_as_last_key && !self.first_key_below_watermark {
+                return false;
+            }
+
+            self.first_key_below_watermark = false;
+
+            for filter in &self.compaction_filters {
+                match filter {
+                    CompactionFilter::Prefix(x) => {
+                        if key.key_ref().starts_with(x) {
+                            return false;
+                        }
+                    }
+                }
+            }
+        }
+
+        if !same_as_last_key {
+            self.last_key.clear();
+            self.last_key.extend(key.key_ref());
+        }
+        true
+    }
+}
+
+/// Tracks how many bytes of the grandparent level (the level below the compaction output level) the current output
+/// SST overlaps with. The grandparent SSTs must be sorted by key range.
+struct GrandparentOverlap<'a> {
+    ssts: &'a [Arc<SsTable>],
+    idx: usize,
+    overlapped_bytes: u64,
+    max_overlapped_bytes: u64,
+    has_key: bool,
+}
+
+impl<'a> GrandparentOverlap<'a> {
+    fn new(ssts: &'a [Arc<SsTable>], max_overlapped_bytes: usize) -> Self {
+        Self {
+            ssts,
+            idx: 0,
+            overlapped_bytes: 0,
+            max_overlapped_bytes: max_overlapped_bytes as u64,
+            has_key: false,
+        }
+    }
+
+    /// Advances to `key`, which will be added to the current output SST, and returns whether the output SST should be
+    /// cut before `key`. As the overlap only grows when `key` passes a grandparent SST, the cut points are aligned with
+    /// the grandparent SST boundaries.
+    fn should_cut_before(&mut self, key: &[u8]) -> bool {
+        while self.idx < self.ssts.len() && key > self.ssts[self.idx].last_key().key_ref() {
+            if self.has_key {
+                self.overlapped_bytes += self.ssts[self.idx].table_size();
+            }
+            self.idx += 1;
+        }
+        self.has_key = true;
+        if self.max_overlapped_bytes != 0 && self.overlapped_bytes > self.max_overlapped_bytes {
+            self.overlapped_bytes = 0;
+            return true;
+        }
+        false
+    }
+
+    /// Resets the overlap when the output SST is cut for other reasons.
+    fn reset(&mut self) {
+        self.overlapped_bytes = 0;
+    }
+}
+
 impl LsmStorageInner {
-    fn compact_generate_sst_from_iter(
+    pub(crate) fn compact_generate_sst_from_iter(
         &self,
         mut iter: impl for<'a> StorageIterator<KeyType<'a> = KeySlice<'a>>,
         compact_to_bottom_level: bool,
+        grandparent_ssts: &[Arc<SsTable>],
     ) -> Result<Vec<Arc<SsTable>>> {
         let mut builder = None;
         let mut new_sst = Vec::new();
-        let watermark = self.mvcc().watermark();
-        let mut last_key = Vec::<u8>::new();
-        let mut first_key_below_watermark = false;
-        let compaction_filters = self.compaction_filters.lock().clone();
-        'outer: while iter.is_valid() {
-            if builder.is_none() {
-                builder = Some(SsTableBuilder::new(self.options.block_size));
-            }
-
-            let same_as_last_key = iter.key().key_ref() == last_key;
-            if !same_as_last_key {
-                first_key_below_watermark = true;
-            }
-
-            if compact_to_bottom_level
-                && !same_as_last_key
-                && iter.key().ts() <= watermark
-                && iter.value().is_empty()
-            {
-                last_key.clear();
-                last_key.extend(iter.key().key_ref());
+        let mut grandparent_overlap =
+            GrandparentOverlap::new(grandparent_ssts, self.options.max_grandparent_overlap_size);
+        let mut version_gc = VersionGc::new(
+            self.mvcc().watermark(),
+            compact_to_bottom_level,
+            self.compaction_filters.lock().clone(),
+        );
+        while iter.is_valid() {
+            let same_as_last_key = version_gc.is_same_as_last_key(iter.key());
+            if !version_gc.retain(iter.key(), iter.value()) {
                 iter.next()?;
-                first_key_below_watermark = false;
                 continue;
             }
 
-            if iter.key().ts() <= watermark {
-                if same_as_last_key && !first_key_below_watermark {
-                    iter.next()?;
-                    continue;
-                }
-
-                first_key_below_watermark = false;
-
-                if !compaction_filters.is_empty() {
-                    for filter in &compaction_filters {
-                        match filter {
-                            CompactionFilter::Prefix(x) => {
-                                if iter.key().key_ref().starts_with(x) {
-                                    iter.next()?;
-                                    continue 'outer;
-                                }
-                            }
-                        }
-                    }
-                }
+            if builder.is_none() {
+                builder = Some(SsTableBuilder::new(self.options.block_size));
             }
-
             let builder_inner = builder.as_mut().unwrap();
 
-            if builder_inner.estimated_size() >= self.options.target_sst_size && !same_as_last_key {
+            let cut_by_grandparent =
+                !same_as_last_key && grandparent_overlap.should_cut_before(iter.key().key_ref());
+            if (builder_inner.estimated_size() >= self.options.target_sst_size
+                || cut_by_grandparent)
+                && !same_as_last_key
+            {
+                grandparent_overlap.reset();
                 let sst_id = self.next_sst_id();
                 let old_builder = builder.take().unwrap();
-                let sst = Arc::new(old_builder.build(
+                let sst = Arc::new(old_builder.build_with_options(
                     sst_id,
                     Some(self.block_cache.clone()),
+                    &self.options,
                     self.path_of_sst(sst_id),
                 )?);
                 new_sst.push(sst);
@@ -184,18 +386,14 @@ impl LsmStorageInner {
             let builder_inner = builder.as_mut().unwrap();
             builder_inner.add(iter.key(), iter.value());
 
-            if !same_as_last_key {
-                last_key.clear();
-                last_key.extend(iter.key().key_ref());
-            }
-
             iter.next()?;
         }
         if let Some(builder) = builder {
             let sst_id = self.next_sst_id(); // lock dropped here
-            let sst = Arc::new(builder.build(
+            let sst = Arc::new(builder.build_with_options(
                 sst_id,
                 Some(self.block_cache.clone()),
+                &self.options,
                 self.path_of_sst(sst_id),
             )?);
             new_sst.push(sst);
@@ -227,57 +425,85 @@ impl LsmStorageInner {
                     MergeIterator::create(l0_iters),
                     SstConcatIterator::create_and_seek_to_first(l1_iters)?,
                 )?;
-                self.compact_generate_sst_from_iter(iter, task.compact_to_bottom_level())
+                self.compact_generate_sst_from_iter(iter, task.compact_to_bottom_level(), &[])
             }
             CompactionTask::Simple(SimpleLeveledCompactionTask {
                 upper_level,
                 upper_level_sst_ids,
-                lower_level: _,
+                lower_level,
                 lower_level_sst_ids,
                 ..
             })
             | CompactionTask::Leveled(LeveledCompactionTask {
                 upper_level,
                 upper_level_sst_ids,
-                lower_level: _,
+                lower_level,
                 lower_level_sst_ids,
                 ..
-            }) => match upper_level {
-                Some(_) => {
-                    let mut upper_ssts = Vec::with_capacity(upper_level_sst_ids.len());
-                    for id in upper_level_sst_ids.iter() {
-                        upper_ssts.push(snapshot.sstables.get(id).unwrap().clone());
+            }) => {
+                // the level below `lower_level` is `levels[lower_level]` as levels start from L1
+                let grandparent_ssts = snapshot
+                    .levels
+                    .get(*lower_level)
+                    .map(|(_, sst_ids)| {
+                        sst_ids
+                            .iter()
+                            .map(|id| snapshot.sstables[id].clone())
+                            .collect::<Vec<_>>()
+                    })
+                    .unwrap_or_default();
+                match upper_level {
+                    Some(_) => {
+                        let mut upper_ssts = Vec::with_capacity(upper_level_sst_ids.len());
+                        for id in upper_level_sst_ids.iter() {
+                            upper_ssts.push(snapshot.sstables.get(id).unwrap().clone());
+                        }
+                        let upper_iter = SstConcatIterator::create_and_seek_to_first(upper_ssts)?;
+                        let mut lower_ssts = Vec::with_capacity(lower_level_sst_ids.len());
+                        for id in lower_level_sst_ids.iter() {
+                            lower_ssts.push(snapshot.sstables.get(id).unwrap().clone());
+                        }
+                        let lower_iter = SstConcatIterator::create_and_seek_to_first(lower_ssts)?;
+                        self.compact_generate_sst_from_iter(
+                            TwoMergeIterator::create(upper_iter, lower_iter)?,
+                            task.compact_to_bottom_level(),
+                            &grandparent_ssts,
+                        )
                     }
-                    let upper_iter = SstConcatIterator::create_and_seek_to_first(upper_ssts)?;
-                    let mut lower_ssts = Vec::with_capacity(lower_level_sst_ids.len());
-                    for id in lower_level_sst_ids.iter() {
-                        lower_ssts.push(snapshot.sstables.get(id).unwrap().clone());
+                    None => {
+                        let mut upper_iters = Vec::with_capacity(upper_level_sst_ids.len());
+                        for id in upper_level_sst_ids.iter() {
+                            upper_iters.push(Box::new(SsTableIterator::create_and_seek_to_first(
+                                snapshot.sstables.get(id).unwrap().clone(),
+                            )?));
+                        }
+                        let upper_iter = MergeIterator::create(upper_iters);
+                        let mut lower_ssts = Vec::with_capacity(lower_level_sst_ids.len());
+                        for id in lower_level_sst_ids.iter() {
+                            lower_ssts.push(snapshot.sstables.get(id).unwrap().clone());
+                        }
+                        let lower_iter = SstConcatIterator::create_and_seek_to_first(lower_ssts)?;
+                        self.compact_generate_sst_from_iter(
+                            TwoMergeIterator::create(upper_iter, lower_iter)?,
+                            task.compact_to_bottom_level(),
+                            &grandparent_ssts,
+                        )
                     }
-                    let lower_iter = SstConcatIterator::create_and_seek_to_first(lower_ssts)?;
-                    self.compact_generate_sst_from_iter(
-                        TwoMergeIterator::create(upper_iter, lower_iter)?,
-                        task.compact_to_bottom_level(),
-                    )
                 }
-                None => {
-                    let mut upper_iters = Vec::with_capacity(upper_level_sst_ids.len());
-                    for id in upper_level_sst_ids.iter() {
-                        upper_iters.push(Box::new(SsTableIterator::create_and_seek_to_first(
-                            snapshot.sstables.get(id).unwrap().clone(),
-                        )?));
-                    }
-                    let upper_iter = MergeIterator::create(upper_iters);
-                    let mut lower_ssts = Vec::with_capacity(lower_level_sst_ids.len());
-                    for id in lower_level_sst_ids.iter() {
-                        lower_ssts.push(snapshot.sstables.get(id).unwrap().clone());
-                    }
-                    let lower_iter = SstConcatIterator::create_and_seek_to_first(lower_ssts)?;
-                    self.compact_generate_sst_from_iter(
-                        TwoMergeIterator::create(upper_iter, lower_iter)?,
-                        task.compact_to_bottom_level(),
-                    )
+            }
+            CompactionTask::IntraL0 { l0_sstables } => {
+                let mut l0_iters = Vec::with_capacity(l0_sstables.len());
+                for id in l0_sstables.iter() {
+                    l0_iters.push(Box::new(SsTableIterator::create_and_seek_to_first(
+                        snapshot.sstables.get(id).unwrap().clone(),
+                    )?));
                 }
-            },
+                self.compact_generate_sst_from_iter(
+                    MergeIterator::create(l0_iters),
+                    task.compact_to_bottom_level(),
+                    &[],
+                )
+            }
             CompactionTask::Tiered(TieredCompactionTask { tiers, .. }) => {
                 let mut iters = Vec::with_capacity(tiers.len());
                 for (_, tier_sst_ids) in tiers {
@@ -290,6 +516,7 @@ impl LsmStorageInner {
                 self.compact_generate_sst_from_iter(
                     MergeIterator::create(iters),
                     task.compact_to_bottom_level(),
+                    &[],
                 )
             }
         }
@@ -299,6 +526,8 @@ impl LsmStorageInner {
         let CompactionOptions::NoCompaction = self.options.compaction_options else {
             panic!("full compaction can only be called with compaction is not enabled")
         };
+        self.check_read_only()?;
+        let _compaction_lock = self.compaction_lock.lock();
 
         let snapshot = {
             let state = self.state.read();
@@ -319,7 +548,8 @@ impl LsmStorageInner {
 
         {
             let state_lock = self.state_lock.lock();
-            let mut state = self.state.read().as_ref().clone();
+            let old_state = self.state.read().clone();
+            let mut state = old_state.as_ref().clone();
             for sst in l0_sstables.iter().chain(l1_sstables.iter()) {
                 let result = state.sstables.remove(sst);
                 assert!(result.is_some());
@@ -339,15 +569,14 @@ impl LsmStorageInner {
                 .copied()
                 .collect::<Vec<_>>();
             assert!(l0_sstables_map.is_empty());
+            let edit = VersionEdit::diff(&old_state, &state);
             *self.state.write() = Arc::new(state);
             self.sync_dir()?;
-            self.manifest.as_ref().unwrap().add_record(
-                &state_lock,
-                ManifestRecord::Compaction(compaction_task, ids.clone()),
-            )?;
+            self.add_manifest_record(&state_lock, ManifestRecord::Compaction(edit))?;
         }
+        let _file_deletion_lock = self.file_deletion_lock.read();
         for sst in l0_sstables.iter().chain(l1_sstables.iter()) {
-            std::fs::remove_file(self.path_of_sst(*sst))?;
+            self.options.fs.remove_file(&self.path_of_sst(*sst))?;
         }
 
         println!("force full compaction done, new SSTs: {:?}", ids);
@@ -355,7 +584,9 @@ impl LsmStorageInner {
         Ok(())
     }
 
-    fn trigger_compaction(&self) -> Result<()> {
+    pub(crate) fn trigger_compaction(&self) -> Result<()> {
+        self.check_read_only()?;
+        let _compaction_lock = self.compaction_lock.lock();
         let snapshot = {
             let state = self.state.read();
             state.clone()
@@ -372,28 +603,34 @@ impl LsmStorageInner {
         let output = sstables.iter().map(|x| x.sst_id()).collect::<Vec<_>>();
         let ssts_to_remove = {
             let state_lock = self.state_lock.lock();
-            let mut snapshot = self.state.read().as_ref().clone();
+            let old_snapshot = self.state.read().clone();
+            let mut snapshot = old_snapshot.as_ref().clone();
             let mut new_sst_ids = Vec::new();
             for file_to_add in sstables {
                 new_sst_ids.push(file_to_add.sst_id());
                 let result = snapshot.sstables.insert(file_to_add.sst_id(), file_to_add);
-                assert!(result.is_none());
+                ensure!(
+                    result.is_none(),
+                    "{}.sst already exists",
+                    new_sst_ids.last().unwrap()
+                );
             }
             let (mut snapshot, files_to_remove) = self
                 .compaction_controller
                 .apply_compaction_result(&snapshot, &task, &output);
             let mut ssts_to_remove = Vec::with_capacity(files_to_remove.len());
             for file_to_remove in &files_to_remove {
-                let result = snapshot.sstables.remove(file_to_remove);
-                assert!(result.is_some(), "cannot remove {}.sst", file_to_remove);
-                ssts_to_remove.push(result.unwrap());
+                let Some(sst) = snapshot.sstables.remove(file_to_remove) else {
+                    bail!("cannot remove {}.sst", file_to_remove);
+                };
+                ssts_to_remove.push(sst);
             }
+            let edit = VersionEdit::diff(&old_snapshot, &snapshot);
             let mut state = self.state.write();
             *state = Arc::new(snapshot);
             drop(state);
             self.sync_dir()?;
-            self.manifest()
-                .add_record(&state_lock, ManifestRecord::Compaction(task, new_sst_ids))?;
+            self.add_manifest_record(&state_lock, ManifestRecord::Compaction(edit))?;
             ssts_to_remove
         };
         println!(
@@ -402,8 +639,13 @@ impl LsmStorageInner {
             output.len(),
             output
         );
-        for sst in ssts_to_remove {
-            std::fs::remove_file(self.path_of_sst(sst.sst_id()))?;
+        {
+            let _file_deletion_lock = self.file_deletion_lock.read();
+            for sst in ssts_to_remove {
+                self.options
+                    .fs
+                    .remove_file(&self.path_of_sst(sst.sst_id()))?;
+            }
         }
         self.sync_dir()?;
 
@@ -423,8 +665,10 @@ impl LsmStorageInner {
                 let ticker = crossbeam_channel::tick(Duration::from_millis(50));
                 loop {
                     crossbeam_channel::select! {
-                        recv(ticker) -> _ => if let Err(e) = this.trigger_compaction() {
-                            eprintln!("compaction failed: {}", e);
+                        recv(ticker) -> _ => if this.background_error().is_none() {
+                            if let Err(e) = this.trigger_compaction() {
+                                this.set_background_error(BackgroundErrorReason::Compaction, e);
+                            }
                         },
                         recv(rx) -> _ => return
                     }
@@ -441,7 +685,7 @@ impl LsmStorageInner {
             state.imm_memtables.len() >= self.options.num_memtable_limit
         };
         if res {
-            self.force_flush_next_imm_memtable()?;
+            self.force_flush_imm_memtables()?;
         }
 
         Ok(())
@@ -456,8 +700,10 @@ impl LsmStorageInner {
             let ticker = crossbeam_channel::tick(Duration::from_millis(50));
             loop {
                 crossbeam_channel::select! {
-                    recv(ticker) -> _ => if let Err(e) = this.trigger_flush() {
-                        eprintln!("flush failed: {}", e);
+                    recv(ticker) -> _ => if this.background_error().is_none() {
+                        if let Err(e) = this.trigger_flush() {
+                            this.set_background_error(BackgroundErrorReason::Flush, e);
+                        }
                     },
                     recv(rx) -> _ => return
                 }
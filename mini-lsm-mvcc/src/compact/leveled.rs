@@ -2,9 +2,10 @@ use std::collections::HashSet;
 
 use serde::{Deserialize, Serialize};
 
+use crate::compact::pick_intra_l0_sstables;
 use crate::lsm_storage::LsmStorageState;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LeveledCompactionTask {
     // if upper_level is `None`, then it is L0 compaction
     pub upper_level: Option<usize>,
@@ -20,6 +21,10 @@ pub struct LeveledCompactionOptions {
     pub level0_file_num_compaction_trigger: usize,
     pub max_levels: usize,
     pub base_level_size_mb: usize,
+    /// Merge the newest L0 SSTs into a single L0 SST when the number of L0 SSTs reaches this number and L0 is
+    /// not compacted into the next level, which happens while the base level is over its target size. It must be larger than `level0_file_num_compaction_trigger`. `None`
+    /// disables intra-L0 compaction.
+    pub level0_file_num_intra_compaction_trigger: Option<usize>,
 }
 
 pub struct LeveledCompactionController {
@@ -61,6 +66,27 @@ impl LeveledCompactionController {
         overlap_ssts
     }
 
+    /// Generates an intra-L0 compaction task that merges the newest L0 SSTs into one sorted run.
+    ///
+    /// Returns `None` if intra-L0 compaction is disabled or L0 is not congested. The returned SST ids are ordered from
+    /// latest to earliest, the same as `l0_sstables`.
+    pub fn generate_intra_l0_compaction_task(
+        &self,
+        snapshot: &LsmStorageState,
+    ) -> Option<Vec<usize>> {
+        let trigger = self.options.level0_file_num_intra_compaction_trigger?;
+        if snapshot.l0_sstables.len() < trigger.max(2) {
+            return None;
+        }
+        let l0_sstables = pick_intra_l0_sstables(snapshot, trigger);
+        println!(
+            "intra-L0 compaction triggered with {} of {} L0 SSTs",
+            l0_sstables.len(),
+            snapshot.l0_sstables.len()
+        );
+        Some(l0_sstables)
+    }
+
     pub fn generate_compaction_task(
         &self,
         snapshot: &LsmStorageState,
@@ -94,8 +120,16 @@ impl LeveledCompactionController {
             }
         }
 
-        // Flush L0 SST is the top priority
-        if snapshot.l0_sstables.len() >= self.options.level0_file_num_compaction_trigger {
+        // Flush L0 SST is the top priority, unless intra-L0 compaction is enabled and the base level is over its
+        // target size, in which case the base level is compacted first and L0 is relieved by intra-L0 compaction
+        let base_level_congested = self
+            .options
+            .level0_file_num_intra_compaction_trigger
+            .is_some()
+            && real_level_size[base_level - 1] > target_level_size[base_level - 1];
+        if snapshot.l0_sstables.len() >= self.options.level0_file_num_compaction_trigger
+            && !base_level_congested
+        {
             println!("flush L0 SST to base level {}", base_level);
             return Some(LeveledCompactionTask {
                 upper_level: None,
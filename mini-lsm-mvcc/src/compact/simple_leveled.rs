@@ -2,6 +2,7 @@ use std::collections::HashSet;
 
 use serde::{Deserialize, Serialize};
 
+use crate::compact::pick_intra_l0_sstables;
 use crate::lsm_storage::LsmStorageState;
 
 #[derive(Debug, Clone)]
@@ -9,9 +10,13 @@ pub struct SimpleLeveledCompactionOptions {
     pub size_ratio_percent: usize,
     pub level0_file_num_compaction_trigger: usize,
     pub max_levels: usize,
+    /// Merge the newest L0 SSTs into a single L0 SST when the number of L0 SSTs reaches this number and L0 is
+    /// not compacted into the next level. It must be larger than `level0_file_num_compaction_trigger`. `None`
+    /// disables intra-L0 compaction.
+    pub level0_file_num_intra_compaction_trigger: Option<usize>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SimpleLeveledCompactionTask {
     // if upper_level is `None`, then it is L0 compaction
     pub upper_level: Option<usize>,
@@ -30,6 +35,27 @@ impl SimpleLeveledCompactionController {
         Self { options }
     }
 
+    /// Generates an intra-L0 compaction task that merges the newest L0 SSTs into one sorted run.
+    ///
+    /// Returns `None` if intra-L0 compaction is disabled or L0 is not congested. The returned SST ids are ordered from
+    /// latest to earliest, the same as `l0_sstables`.
+    pub fn generate_intra_l0_compaction_task(
+        &self,
+        snapshot: &LsmStorageState,
+    ) -> Option<Vec<usize>> {
+        let trigger = self.options.level0_file_num_intra_compaction_trigger?;
+        if snapshot.l0_sstables.len() < trigger.max(2) {
+            return None;
+        }
+        let l0_sstables = pick_intra_l0_sstables(snapshot, trigger);
+        println!(
+            "intra-L0 compaction triggered with {} of {} L0 SSTs",
+            l0_sstables.len(),
+            snapshot.l0_sstables.len()
+        );
+        Some(l0_sstables)
+    }
+
     /// Generates a compaction task.
     ///
     /// Returns `None` if no compaction needs to be scheduled. The order of SSTs in the compaction task id vector matters.
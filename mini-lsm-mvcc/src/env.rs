@@ -0,0 +1,577 @@
+//! The file system used by the storage for all its files. `PosixFileSystem` is the local file system, and
+//! `MemFileSystem` keeps the files in memory for tests.
+
+pub mod fault_injection;
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
+use std::fs::{File, TryLockError};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, ensure, Result};
+use bytes::Bytes;
+use memmap2::Mmap;
+use parking_lot::{Mutex, RwLock};
+
+/// A file opened for appending.
+pub trait WritableFile: Send {
+    /// Append the data to the file. The data may be buffered until `flush` or `sync`.
+    fn append(&mut self, data: &[u8]) -> Result<()>;
+
+    /// Make the appended data visible to readers of the file.
+    fn flush(&mut self) -> Result<()>;
+
+    /// Make the appended data durable.
+    fn sync(&mut self) -> Result<()>;
+
+    /// The size of the file, including the data not flushed yet.
+    fn size(&self) -> u64;
+}
+
+/// A file opened for reading at any offset.
+pub trait RandomAccessFile: Send + Sync {
+    /// Read exactly `len` bytes at `offset`.
+    fn read_at(&self, offset: u64, len: usize) -> Result<Vec<u8>>;
+
+    /// Like `read_at`, but memory-mapped files return a slice of the mapping instead of a copy.
+    fn read_bytes_at(&self, offset: u64, len: usize) -> Result<Bytes> {
+        Ok(self.read_at(offset, len)?.into())
+    }
+
+    /// The current size of the file, which grows if the file is being appended to.
+    fn size(&self) -> Result<u64>;
+}
+
+/// An exclusive lock on a file, released when dropped.
+pub trait FileLock: Send + Sync {}
+
+/// The file operations of the storage. Paths are the same as on the local file system, and a file opened for
+/// reading stays readable after it is removed or renamed.
+pub trait FileSystem: Debug + Send + Sync {
+    fn create_dir_all(&self, path: &Path) -> Result<()>;
+
+    fn exists(&self, path: &Path) -> bool;
+
+    /// Create the file for appending, truncating it if it exists.
+    fn create(&self, path: &Path) -> Result<Box<dyn WritableFile>>;
+
+    /// Create the file for appending, failing if it exists. Checking and creating are one operation, so two
+    /// writers never both get the file.
+    fn create_new(&self, path: &Path) -> Result<Box<dyn WritableFile>>;
+
+    /// Open an existing file for appending at its end.
+    fn open_append(&self, path: &Path) -> Result<Box<dyn WritableFile>>;
+
+    fn open(&self, path: &Path) -> Result<Arc<dyn RandomAccessFile>>;
+
+    /// Open an existing file that is never modified again through a memory mapping, so that `read_bytes_at`
+    /// does not copy. File systems without memory mappings open the file as `open` does.
+    fn open_mmap(&self, path: &Path) -> Result<Arc<dyn RandomAccessFile>> {
+        self.open(path)
+    }
+
+    fn file_size(&self, path: &Path) -> Result<u64>;
+
+    /// Cut the file to `len` bytes and make the new size durable.
+    fn truncate(&self, path: &Path, len: u64) -> Result<()>;
+
+    /// Rename the file or directory, replacing the file at `to` if it exists.
+    fn rename(&self, from: &Path, to: &Path) -> Result<()>;
+
+    /// Make `to` another name of the file at `from`.
+    fn hard_link(&self, from: &Path, to: &Path) -> Result<()>;
+
+    fn remove_file(&self, path: &Path) -> Result<()>;
+
+    fn remove_dir_all(&self, path: &Path) -> Result<()>;
+
+    /// The names of the files (not directories) in the directory, in any order.
+    fn list(&self, dir: &Path) -> Result<Vec<String>>;
+
+    /// The names of the directories in the directory, in any order.
+    fn list_dirs(&self, dir: &Path) -> Result<Vec<String>>;
+
+    /// Make the creations, renames and removals of files in the directory durable.
+    fn sync_dir(&self, dir: &Path) -> Result<()>;
+
+    /// Take the exclusive lock of the file, creating it if it does not exist. Returns `None` if the lock is
+    /// held by someone else.
+    fn try_lock(&self, path: &Path) -> Result<Option<Box<dyn FileLock>>>;
+
+    fn read(&self, path: &Path) -> Result<Vec<u8>> {
+        let file = self.open(path)?;
+        file.read_at(0, file.size()? as usize)
+    }
+
+    /// Write the data into a new file (or replace the file) and sync it.
+    fn write(&self, path: &Path, data: &[u8]) -> Result<()> {
+        let mut file = self.create(path)?;
+        file.append(data)?;
+        file.sync()
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> Result<()> {
+        self.write(to, &self.read(from)?)
+    }
+}
+
+/// The local file system.
+#[derive(Debug, Default)]
+pub struct PosixFileSystem;
+
+struct PosixWritableFile {
+    file: BufWriter<File>,
+    size: u64,
+}
+
+impl WritableFile for PosixWritableFile {
+    fn append(&mut self, data: &[u8]) -> Result<()> {
+        self.file.write_all(data)?;
+        self.size += data.len() as u64;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.file.flush()?;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.file.flush()?;
+        self.file.get_ref().sync_all()?;
+        Ok(())
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl RandomAccessFile for File {
+    fn read_at(&self, offset: u64, len: usize) -> Result<Vec<u8>> {
+        use std::os::unix::fs::FileExt;
+        let mut data = vec![0; len];
+        self.read_exact_at(&mut data[..], offset)?;
+        Ok(data)
+    }
+
+    fn size(&self) -> Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+}
+
+impl FileLock for File {}
+
+/// A file mapped into memory. Reads are slices of the mapping, which is unmapped when the last of them is
+/// dropped.
+struct MmapFile(Bytes);
+
+impl RandomAccessFile for MmapFile {
+    fn read_at(&self, offset: u64, len: usize) -> Result<Vec<u8>> {
+        Ok(self.read_bytes_at(offset, len)?.to_vec())
+    }
+
+    fn read_bytes_at(&self, offset: u64, len: usize) -> Result<Bytes> {
+        let offset = offset as usize;
+        ensure!(offset + len <= self.0.len(), "failed to fill whole buffer");
+        Ok(self.0.slice(offset..offset + len))
+    }
+
+    fn size(&self) -> Result<u64> {
+        Ok(self.0.len() as u64)
+    }
+}
+
+impl FileSystem for PosixFileSystem {
+    fn create_dir_all(&self, path: &Path) -> Result<()> {
+        std::fs::create_dir_all(path)?;
+        Ok(())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn create(&self, path: &Path) -> Result<Box<dyn WritableFile>> {
+        let file = File::create(path)?;
+        Ok(Box::new(PosixWritableFile {
+            file: BufWriter::new(file),
+            size: 0,
+        }))
+    }
+
+    fn create_new(&self, path: &Path) -> Result<Box<dyn WritableFile>> {
+        let file = File::options().write(true).create_new(true).open(path)?;
+        Ok(Box::new(PosixWritableFile {
+            file: BufWriter::new(file),
+            size: 0,
+        }))
+    }
+
+    fn open_append(&self, path: &Path) -> Result<Box<dyn WritableFile>> {
+        let file = File::options().append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Box::new(PosixWritableFile {
+            file: BufWriter::new(file),
+            size,
+        }))
+    }
+
+    fn open(&self, path: &Path) -> Result<Arc<dyn RandomAccessFile>> {
+        Ok(Arc::new(File::open(path)?))
+    }
+
+    fn open_mmap(&self, path: &Path) -> Result<Arc<dyn RandomAccessFile>> {
+        let file = File::open(path)?;
+        // SAFETY: the caller never modifies the file again. Removing or renaming the file keeps the mapping
+        // valid.
+        let mmap = unsafe { Mmap::map(&file)? };
+        Ok(Arc::new(MmapFile(Bytes::from_owner(mmap))))
+    }
+
+    fn file_size(&self, path: &Path) -> Result<u64> {
+        Ok(std::fs::metadata(path)?.len())
+    }
+
+    fn truncate(&self, path: &Path, len: u64) -> Result<()> {
+        let file = File::options().write(true).open(path)?;
+        file.set_len(len)?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        std::fs::rename(from, to)?;
+        Ok(())
+    }
+
+    fn hard_link(&self, from: &Path, to: &Path) -> Result<()> {
+        std::fs::hard_link(from, to)?;
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<()> {
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> Result<()> {
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    fn list(&self, dir: &Path) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            if let Some(name) = entry.file_name().to_str() {
+                names.push(name.to_string());
+            }
+        }
+        Ok(names)
+    }
+
+    fn list_dirs(&self, dir: &Path) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            if let Some(name) = entry.file_name().to_str() {
+                names.push(name.to_string());
+            }
+        }
+        Ok(names)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> Result<()> {
+        File::open(dir)?.sync_all()?;
+        Ok(())
+    }
+
+    fn try_lock(&self, path: &Path) -> Result<Option<Box<dyn FileLock>>> {
+        let file = File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)?;
+        // an advisory lock, released by the OS when the file is closed, including when the process exits
+        match file.try_lock() {
+            Ok(()) => Ok(Some(Box::new(file))),
+            Err(TryLockError::WouldBlock) => Ok(None),
+            Err(TryLockError::Error(e)) => Err(e.into()),
+        }
+    }
+}
+
+type MemFile = Arc<RwLock<Vec<u8>>>;
+
+#[derive(Default)]
+struct MemFileSystemState {
+    files: HashMap<PathBuf, MemFile>,
+    dirs: HashSet<PathBuf>,
+    locks: HashSet<PathBuf>,
+}
+
+/// A file system that keeps the files in memory. Every write is durable as soon as it is appended, and the
+/// files are shared by the clones of the file system.
+#[derive(Clone, Default)]
+pub struct MemFileSystem {
+    state: Arc<Mutex<MemFileSystemState>>,
+}
+
+impl Debug for MemFileSystem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MemFileSystem").finish_non_exhaustive()
+    }
+}
+
+struct MemWritableFile(MemFile);
+
+impl WritableFile for MemWritableFile {
+    fn append(&mut self, data: &[u8]) -> Result<()> {
+        self.0.write().extend_from_slice(data);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn size(&self) -> u64 {
+        self.0.read().len() as u64
+    }
+}
+
+struct MemRandomAccessFile(MemFile);
+
+impl RandomAccessFile for MemRandomAccessFile {
+    fn read_at(&self, offset: u64, len: usize) -> Result<Vec<u8>> {
+        let data = self.0.read();
+        let offset = offset as usize;
+        ensure!(offset + len <= data.len(), "failed to fill whole buffer");
+        Ok(data[offset..offset + len].to_vec())
+    }
+
+    fn size(&self) -> Result<u64> {
+        Ok(self.0.read().len() as u64)
+    }
+}
+
+struct MemFileLock {
+    state: Arc<Mutex<MemFileSystemState>>,
+    path: PathBuf,
+}
+
+impl FileLock for MemFileLock {}
+
+impl Drop for MemFileLock {
+    fn drop(&mut self) {
+        self.state.lock().locks.remove(&self.path);
+    }
+}
+
+fn not_found(path: &Path) -> anyhow::Error {
+    anyhow!("{} not found", path.display())
+}
+
+impl MemFileSystemState {
+    fn file(&self, path: &Path) -> Result<MemFile> {
+        self.files.get(path).cloned().ok_or_else(|| not_found(path))
+    }
+
+    fn check_parent(&self, path: &Path) -> Result<()> {
+        match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() && !self.dirs.contains(parent) => {
+                Err(not_found(parent))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl MemFileSystem {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl FileSystem for MemFileSystem {
+    fn create_dir_all(&self, path: &Path) -> Result<()> {
+        let mut state = self.state.lock();
+        for dir in path.ancestors().filter(|dir| !dir.as_os_str().is_empty()) {
+            if state.files.contains_key(dir) {
+                bail!("{} is a file", dir.display());
+            }
+            state.dirs.insert(dir.to_path_buf());
+        }
+        Ok(())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        let state = self.state.lock();
+        state.files.contains_key(path) || state.dirs.contains(path)
+    }
+
+    fn create(&self, path: &Path) -> Result<Box<dyn WritableFile>> {
+        let mut state = self.state.lock();
+        state.check_parent(path)?;
+        let file = MemFile::default();
+        state.files.insert(path.to_path_buf(), file.clone());
+        Ok(Box::new(MemWritableFile(file)))
+    }
+
+    fn create_new(&self, path: &Path) -> Result<Box<dyn WritableFile>> {
+        let mut state = self.state.lock();
+        state.check_parent(path)?;
+        if state.files.contains_key(path) || state.dirs.contains(path) {
+            bail!("{} already exists", path.display());
+        }
+        let file = MemFile::default();
+        state.files.insert(path.to_path_buf(), file.clone());
+        Ok(Box::new(MemWritableFile(file)))
+    }
+
+    fn open_append(&self, path: &Path) -> Result<Box<dyn WritableFile>> {
+        Ok(Box::new(MemWritableFile(self.state.lock().file(path)?)))
+    }
+
+    fn open(&self, path: &Path) -> Result<Arc<dyn RandomAccessFile>> {
+        Ok(Arc::new(MemRandomAccessFile(self.state.lock().file(path)?)))
+    }
+
+    fn file_size(&self, path: &Path) -> Result<u64> {
+        Ok(self.state.lock().file(path)?.read().len() as u64)
+    }
+
+    fn truncate(&self, path: &Path, len: u64) -> Result<()> {
+        let file = self.state.lock().file(path)?;
+        file.write().truncate(len as usize);
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        let mut state = self.state.lock();
+        state.check_parent(to)?;
+        if let Some(file) = state.files.remove(from) {
+            state.files.insert(to.to_path_buf(), file);
+            return Ok(());
+        }
+        if !state.dirs.contains(from) {
+            return Err(not_found(from));
+        }
+        if state.dirs.contains(to) || state.files.contains_key(to) {
+            bail!("{} already exists", to.display());
+        }
+        let renamed = |path: &Path| to.join(path.strip_prefix(from).unwrap());
+        state.dirs = std::mem::take(&mut state.dirs)
+            .into_iter()
+            .map(|dir| {
+                if dir.starts_with(from) {
+                    renamed(&dir)
+                } else {
+                    dir
+                }
+            })
+            .collect();
+        state.files = std::mem::take(&mut state.files)
+            .into_iter()
+            .map(|(path, file)| {
+                if path.starts_with(from) {
+                    (renamed(&path), file)
+                } else {
+                    (path, file)
+                }
+            })
+            .collect();
+        Ok(())
+    }
+
+    fn hard_link(&self, from: &Path, to: &Path) -> Result<()> {
+        let mut state = self.state.lock();
+        state.check_parent(to)?;
+        if state.files.contains_key(to) {
+            bail!("{} already exists", to.display());
+        }
+        let file = state.file(from)?;
+        state.files.insert(to.to_path_buf(), file);
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<()> {
+        self.state
+            .lock()
+            .files
+            .remove(path)
+            .map(|_| ())
+            .ok_or_else(|| not_found(path))
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> Result<()> {
+        let mut state = self.state.lock();
+        if !state.dirs.contains(path) {
+            return Err(not_found(path));
+        }
+        state.dirs.retain(|dir| !dir.starts_with(path));
+        state.files.retain(|file, _| !file.starts_with(path));
+        Ok(())
+    }
+
+    fn list(&self, dir: &Path) -> Result<Vec<String>> {
+        let state = self.state.lock();
+        if !state.dirs.contains(dir) {
+            return Err(not_found(dir));
+        }
+        Ok(state
+            .files
+            .keys()
+            .filter(|path| path.parent() == Some(dir))
+            .filter_map(|path| Some(path.file_name()?.to_str()?.to_string()))
+            .collect())
+    }
+
+    fn list_dirs(&self, dir: &Path) -> Result<Vec<String>> {
+        let state = self.state.lock();
+        if !state.dirs.contains(dir) {
+            return Err(not_found(dir));
+        }
+        Ok(state
+            .dirs
+            .iter()
+            .filter(|path| path.parent() == Some(dir))
+            .filter_map(|path| Some(path.file_name()?.to_str()?.to_string()))
+            .collect())
+    }
+
+    fn sync_dir(&self, dir: &Path) -> Result<()> {
+        if !self.exists(dir) {
+            return Err(not_found(dir));
+        }
+        Ok(())
+    }
+
+    fn try_lock(&self, path: &Path) -> Result<Option<Box<dyn FileLock>>> {
+        let mut state = self.state.lock();
+        if !state.files.contains_key(path) {
+            state.check_parent(path)?;
+            state.files.insert(path.to_path_buf(), MemFile::default());
+        }
+        if !state.locks.insert(path.to_path_buf()) {
+            return Ok(None);
+        }
+        Ok(Some(Box::new(MemFileLock {
+            state: self.state.clone(),
+            path: path.to_path_buf(),
+        })))
+    }
+}
@@ -0,0 +1,447 @@
+//! A file system that simulates power loss and I/O errors, for testing that the storage never loses synced
+//! writes. Appended data is durable once the file is synced, and the creation, rename and removal of a file
+//! are durable once its directory is synced. Directories themselves are durable as soon as they are created.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{bail, ensure, Result};
+use parking_lot::Mutex;
+
+use super::{not_found, FileLock, FileSystem, RandomAccessFile, WritableFile};
+
+#[derive(Default)]
+struct FaultFile {
+    data: Vec<u8>,
+    /// The data at the last sync, which is what is left after a crash
+    synced: Vec<u8>,
+}
+
+type FaultFileRef = Arc<Mutex<FaultFile>>;
+
+#[derive(Default)]
+struct FaultState {
+    /// Increased by each restart. Handles of an earlier generation belong to the crashed process and fail.
+    generation: u64,
+    files: HashMap<PathBuf, FaultFileRef>,
+    /// The files that survive a crash, as of the last sync of their directories
+    durable_files: HashMap<PathBuf, FaultFileRef>,
+    dirs: HashSet<PathBuf>,
+    locks: HashSet<PathBuf>,
+    crashed: bool,
+    ops_before_crash: Option<usize>,
+    fail_sync: bool,
+    no_space: bool,
+}
+
+/// A file system in memory that injects faults. Clones share the files and the faults.
+#[derive(Clone, Default)]
+pub struct FaultInjectionFileSystem {
+    state: Arc<Mutex<FaultState>>,
+    generation: u64,
+}
+
+impl Debug for FaultInjectionFileSystem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FaultInjectionFileSystem")
+            .field("generation", &self.generation)
+            .finish_non_exhaustive()
+    }
+}
+
+fn no_space() -> anyhow::Error {
+    std::io::Error::from_raw_os_error(28).into()
+}
+
+impl FaultState {
+    fn file(&self, path: &Path) -> Result<FaultFileRef> {
+        self.files.get(path).cloned().ok_or_else(|| not_found(path))
+    }
+
+    fn check_parent(&self, path: &Path) -> Result<()> {
+        match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() && !self.dirs.contains(parent) => {
+                Err(not_found(parent))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl FaultInjectionFileSystem {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Lock the state for an operation, failing if the process of this handle has crashed.
+    fn check(&self) -> Result<parking_lot::MutexGuard<'_, FaultState>> {
+        let state = self.state.lock();
+        if state.crashed || state.generation != self.generation {
+            bail!("simulated crash");
+        }
+        Ok(state)
+    }
+
+    /// Like `check`, and count the operation towards `crash_after`.
+    fn check_write(&self) -> Result<parking_lot::MutexGuard<'_, FaultState>> {
+        let mut state = self.check()?;
+        if let Some(ops) = state.ops_before_crash.as_mut() {
+            if *ops == 0 {
+                state.crashed = true;
+                bail!("simulated crash");
+            }
+            *ops -= 1;
+        }
+        Ok(state)
+    }
+
+    fn create_file(&self, path: &Path, create_new: bool) -> Result<Box<dyn WritableFile>> {
+        let mut state = self.check_write()?;
+        if state.no_space {
+            return Err(no_space());
+        }
+        state.check_parent(path)?;
+        if create_new && (state.files.contains_key(path) || state.dirs.contains(path)) {
+            bail!("{} already exists", path.display());
+        }
+        let file = FaultFileRef::default();
+        state.files.insert(path.to_path_buf(), file.clone());
+        Ok(Box::new(FaultWritableFile {
+            fs: self.clone(),
+            file,
+        }))
+    }
+
+    /// Simulate a power loss: all later operations fail until `restart`.
+    pub fn crash(&self) {
+        self.state.lock().crashed = true;
+    }
+
+    /// Simulate a power loss after `ops` more writes, syncs, creations, renames or removals.
+    pub fn crash_after(&self, ops: usize) {
+        self.state.lock().ops_before_crash = Some(ops);
+    }
+
+    /// Whether a power loss has been simulated.
+    pub fn crashed(&self) -> bool {
+        self.state.lock().crashed
+    }
+
+    /// Restart after a power loss (or kill the process if there was none), dropping everything not synced.
+    /// Returns the file system for the new process, while the handles of the old process keep failing.
+    pub fn restart(&self) -> Self {
+        let mut state = self.state.lock();
+        state.generation += 1;
+        state.crashed = false;
+        state.ops_before_crash = None;
+        state.locks.clear();
+        state.files = state.durable_files.clone();
+        for file in state.files.values() {
+            let mut file = file.lock();
+            file.data = file.synced.clone();
+        }
+        Self {
+            state: self.state.clone(),
+            generation: state.generation,
+        }
+    }
+
+    /// Make syncs of files and directories fail, leaving the data not synced.
+    pub fn set_fail_sync(&self, fail_sync: bool) {
+        self.state.lock().fail_sync = fail_sync;
+    }
+
+    /// Make appends and file creations fail with ENOSPC.
+    pub fn set_no_space(&self, no_space: bool) {
+        self.state.lock().no_space = no_space;
+    }
+
+    /// Flip the bits of the byte at `offset`, both in the file and in what survives a crash.
+    pub fn corrupt(&self, path: impl AsRef<Path>, offset: usize) -> Result<()> {
+        let path = path.as_ref();
+        let file = self.state.lock().file(path)?;
+        let mut file = file.lock();
+        ensure!(offset < file.data.len(), "offset out of {}", path.display());
+        file.data[offset] ^= 0xff;
+        if offset < file.synced.len() {
+            file.synced[offset] ^= 0xff;
+        }
+        Ok(())
+    }
+}
+
+struct FaultWritableFile {
+    fs: FaultInjectionFileSystem,
+    file: FaultFileRef,
+}
+
+impl WritableFile for FaultWritableFile {
+    fn append(&mut self, data: &[u8]) -> Result<()> {
+        let state = self.fs.check_write()?;
+        if state.no_space {
+            return Err(no_space());
+        }
+        self.file.lock().data.extend_from_slice(data);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        drop(self.fs.check()?);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        let state = self.fs.check_write()?;
+        if state.fail_sync {
+            bail!("simulated sync failure");
+        }
+        let mut file = self.file.lock();
+        file.synced = file.data.clone();
+        Ok(())
+    }
+
+    fn size(&self) -> u64 {
+        self.file.lock().data.len() as u64
+    }
+}
+
+struct FaultRandomAccessFile {
+    fs: FaultInjectionFileSystem,
+    file: FaultFileRef,
+}
+
+impl RandomAccessFile for FaultRandomAccessFile {
+    fn read_at(&self, offset: u64, len: usize) -> Result<Vec<u8>> {
+        drop(self.fs.check()?);
+        let file = self.file.lock();
+        let offset = offset as usize;
+        ensure!(
+            offset + len <= file.data.len(),
+            "failed to fill whole buffer"
+        );
+        Ok(file.data[offset..offset + len].to_vec())
+    }
+
+    fn size(&self) -> Result<u64> {
+        drop(self.fs.check()?);
+        Ok(self.file.lock().data.len() as u64)
+    }
+}
+
+struct FaultFileLock {
+    fs: FaultInjectionFileSystem,
+    path: PathBuf,
+}
+
+impl FileLock for FaultFileLock {}
+
+impl Drop for FaultFileLock {
+    fn drop(&mut self) {
+        let mut state = self.fs.state.lock();
+        // the locks of a crashed process are already released
+        if state.generation == self.fs.generation {
+            state.locks.remove(&self.path);
+        }
+    }
+}
+
+impl FileSystem for FaultInjectionFileSystem {
+    fn create_dir_all(&self, path: &Path) -> Result<()> {
+        let mut state = self.check_write()?;
+        for dir in path.ancestors().filter(|dir| !dir.as_os_str().is_empty()) {
+            if state.files.contains_key(dir) {
+                bail!("{} is a file", dir.display());
+            }
+            state.dirs.insert(dir.to_path_buf());
+        }
+        Ok(())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        let Ok(state) = self.check() else {
+            return false;
+        };
+        state.files.contains_key(path) || state.dirs.contains(path)
+    }
+
+    fn create(&self, path: &Path) -> Result<Box<dyn WritableFile>> {
+        self.create_file(path, false)
+    }
+
+    fn create_new(&self, path: &Path) -> Result<Box<dyn WritableFile>> {
+        self.create_file(path, true)
+    }
+
+    fn open_append(&self, path: &Path) -> Result<Box<dyn WritableFile>> {
+        let file = self.check()?.file(path)?;
+        Ok(Box::new(FaultWritableFile {
+            fs: self.clone(),
+            file,
+        }))
+    }
+
+    fn open(&self, path: &Path) -> Result<Arc<dyn RandomAccessFile>> {
+        let file = self.check()?.file(path)?;
+        Ok(Arc::new(FaultRandomAccessFile {
+            fs: self.clone(),
+            file,
+        }))
+    }
+
+    fn file_size(&self, path: &Path) -> Result<u64> {
+        let file = self.check()?.file(path)?;
+        let size = file.lock().data.len() as u64;
+        Ok(size)
+    }
+
+    fn truncate(&self, path: &Path, len: u64) -> Result<()> {
+        let state = self.check_write()?;
+        if state.fail_sync {
+            bail!("simulated sync failure");
+        }
+        let file = state.file(path)?;
+        let mut file = file.lock();
+        file.data.truncate(len as usize);
+        file.synced = file.data.clone();
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        let mut state = self.check_write()?;
+        state.check_parent(to)?;
+        if let Some(file) = state.files.remove(from) {
+            state.files.insert(to.to_path_buf(), file);
+            return Ok(());
+        }
+        if !state.dirs.contains(from) {
+            return Err(not_found(from));
+        }
+        if state.dirs.contains(to) || state.files.contains_key(to) {
+            bail!("{} already exists", to.display());
+        }
+        // directories are durable as soon as they are renamed, along with the files in them
+        let renamed = |path: &Path| to.join(path.strip_prefix(from).unwrap());
+        let rename_files = |files: HashMap<PathBuf, FaultFileRef>| {
+            files
+                .into_iter()
+                .map(|(path, file)| {
+                    if path.starts_with(from) {
+                        (renamed(&path), file)
+                    } else {
+                        (path, file)
+                    }
+                })
+                .collect()
+        };
+        state.dirs = std::mem::take(&mut state.dirs)
+            .into_iter()
+            .map(|dir| {
+                if dir.starts_with(from) {
+                    renamed(&dir)
+                } else {
+                    dir
+                }
+            })
+            .collect();
+        state.files = rename_files(std::mem::take(&mut state.files));
+        state.durable_files = rename_files(std::mem::take(&mut state.durable_files));
+        Ok(())
+    }
+
+    fn hard_link(&self, from: &Path, to: &Path) -> Result<()> {
+        let mut state = self.check_write()?;
+        state.check_parent(to)?;
+        if state.files.contains_key(to) {
+            bail!("{} already exists", to.display());
+        }
+        let file = state.file(from)?;
+        state.files.insert(to.to_path_buf(), file);
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<()> {
+        self.check_write()?
+            .files
+            .remove(path)
+            .map(|_| ())
+            .ok_or_else(|| not_found(path))
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> Result<()> {
+        let mut state = self.check_write()?;
+        if !state.dirs.contains(path) {
+            return Err(not_found(path));
+        }
+        state.dirs.retain(|dir| !dir.starts_with(path));
+        state.files.retain(|file, _| !file.starts_with(path));
+        state
+            .durable_files
+            .retain(|file, _| !file.starts_with(path));
+        Ok(())
+    }
+
+    fn list(&self, dir: &Path) -> Result<Vec<String>> {
+        let state = self.check()?;
+        if !state.dirs.contains(dir) {
+            return Err(not_found(dir));
+        }
+        Ok(state
+            .files
+            .keys()
+            .filter(|path| path.parent() == Some(dir))
+            .filter_map(|path| Some(path.file_name()?.to_str()?.to_string()))
+            .collect())
+    }
+
+    fn list_dirs(&self, dir: &Path) -> Result<Vec<String>> {
+        let state = self.check()?;
+        if !state.dirs.contains(dir) {
+            return Err(not_found(dir));
+        }
+        Ok(state
+            .dirs
+            .iter()
+            .filter(|path| path.parent() == Some(dir))
+            .filter_map(|path| Some(path.file_name()?.to_str()?.to_string()))
+            .collect())
+    }
+
+    fn sync_dir(&self, dir: &Path) -> Result<()> {
+        let mut state = self.check_write()?;
+        if !state.dirs.contains(dir) {
+            return Err(not_found(dir));
+        }
+        if state.fail_sync {
+            bail!("simulated sync failure");
+        }
+        let in_dir = |path: &PathBuf| path.parent() == Some(dir);
+        state.durable_files.retain(|path, _| !in_dir(path));
+        let files = state
+            .files
+            .iter()
+            .filter(|(path, _)| in_dir(path))
+            .map(|(path, file)| (path.clone(), file.clone()))
+            .collect::<Vec<_>>();
+        state.durable_files.extend(files);
+        Ok(())
+    }
+
+    fn try_lock(&self, path: &Path) -> Result<Option<Box<dyn FileLock>>> {
+        let mut state = self.check_write()?;
+        if !state.files.contains_key(path) {
+            state.check_parent(path)?;
+            state
+                .files
+                .insert(path.to_path_buf(), FaultFileRef::default());
+        }
+        if !state.locks.insert(path.to_path_buf()) {
+            return Ok(None);
+        }
+        Ok(Some(Box::new(FaultFileLock {
+            fs: self.clone(),
+            path: path.to_path_buf(),
+        })))
+    }
+}
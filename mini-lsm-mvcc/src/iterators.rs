@@ -2,6 +2,8 @@ pub mod concat_iterator;
 pub mod merge_iterator;
 pub mod two_merge_iterator;
 
+use bytes::Bytes;
+
 pub trait StorageIterator {
     type KeyType<'a>: PartialEq + Eq + PartialOrd + Ord
     where
@@ -10,6 +12,12 @@ pub trait StorageIterator {
     /// Get the current value.
     fn value(&self) -> &[u8];
 
+    /// Get the current value as `Bytes`. Iterators over `Bytes` buffers return a slice sharing the buffer
+    /// instead of a copy.
+    fn value_bytes(&self) -> Bytes {
+        Bytes::copy_from_slice(self.value())
+    }
+
     /// Get the current key.
     fn key(&self) -> Self::KeyType<'_>;
 
@@ -1,6 +1,7 @@
 use std::sync::Arc;
 
 use anyhow::Result;
+use bytes::Bytes;
 
 use crate::{
     key::KeySlice,
@@ -102,6 +103,10 @@ impl StorageIterator for SstConcatIterator {
         self.current.as_ref().unwrap().value()
     }
 
+    fn value_bytes(&self) -> Bytes {
+        self.current.as_ref().unwrap().value_bytes()
+    }
+
     fn is_valid(&self) -> bool {
         if let Some(current) = &self.current {
             assert!(current.is_valid());
@@ -3,6 +3,7 @@ use std::collections::binary_heap::PeekMut;
 use std::collections::BinaryHeap;
 
 use anyhow::Result;
+use bytes::Bytes;
 
 use crate::key::KeySlice;
 
@@ -90,6 +91,10 @@ impl<I: 'static + for<'a> StorageIterator<KeyType<'a> = KeySlice<'a>>> StorageIt
         self.current.as_ref().unwrap().1.value()
     }
 
+    fn value_bytes(&self) -> Bytes {
+        self.current.as_ref().unwrap().1.value_bytes()
+    }
+
     fn is_valid(&self) -> bool {
         self.current
             .as_ref()
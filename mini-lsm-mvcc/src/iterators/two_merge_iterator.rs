@@ -1,4 +1,5 @@
 use anyhow::Result;
+use bytes::Bytes;
 
 use super::StorageIterator;
 
@@ -69,6 +70,14 @@ impl<
         }
     }
 
+    fn value_bytes(&self) -> Bytes {
+        if self.choose_a {
+            self.a.value_bytes()
+        } else {
+            self.b.value_bytes()
+        }
+    }
+
     fn is_valid(&self) -> bool {
         if self.choose_a {
             self.a.is_valid()
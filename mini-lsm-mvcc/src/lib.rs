@@ -1,6 +1,9 @@
+pub mod backup;
 pub mod block;
+mod checkpoint;
 pub mod compact;
 pub mod debug;
+pub mod env;
 pub mod iterators;
 pub mod key;
 pub mod lsm_iterator;
@@ -8,6 +11,9 @@ pub mod lsm_storage;
 pub mod manifest;
 pub mod mem_table;
 pub mod mvcc;
+pub mod repair;
+pub mod replication;
+mod secondary;
 pub mod table;
 pub mod wal;
 
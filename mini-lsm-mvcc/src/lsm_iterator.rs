@@ -100,6 +100,10 @@ impl StorageIterator for LsmIterator {
         self.inner.value()
     }
 
+    fn value_bytes(&self) -> Bytes {
+        self.inner.value_bytes()
+    }
+
     fn next(&mut self) -> Result<()> {
         self.next_inner()?;
         self.move_to_key()?;
@@ -149,6 +153,13 @@ impl<I: StorageIterator> StorageIterator for FusedIterator<I> {
         self.iter.value()
     }
 
+    fn value_bytes(&self) -> Bytes {
+        if !self.is_valid() {
+            panic!("invalid access to the underlying iterator");
+        }
+        self.iter.value_bytes()
+    }
+
     fn next(&mut self) -> Result<()> {
         // only move when the iterator is valid and not errored
         if self.has_errored {
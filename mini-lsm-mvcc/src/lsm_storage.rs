@@ -1,32 +1,33 @@
-use std::collections::{BTreeSet, HashMap};
-use std::fs::File;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::ops::Bound;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::AtomicUsize;
 use std::sync::Arc;
 
-use anyhow::{Context, Result};
+use anyhow::{bail, ensure, Context, Result};
 use bytes::Bytes;
 use parking_lot::{Mutex, MutexGuard, RwLock};
 
-use crate::block::Block;
 use crate::compact::{
-    CompactionController, CompactionOptions, LeveledCompactionController, LeveledCompactionOptions,
-    SimpleLeveledCompactionController, SimpleLeveledCompactionOptions, TieredCompactionController,
+    CompactionController, CompactionOptions, LeveledCompactionOptions,
+    SimpleLeveledCompactionOptions,
 };
+use crate::env::{FileLock, FileSystem, PosixFileSystem};
 use crate::iterators::concat_iterator::SstConcatIterator;
 use crate::iterators::merge_iterator::MergeIterator;
 use crate::iterators::two_merge_iterator::TwoMergeIterator;
 use crate::iterators::StorageIterator;
 use crate::key::{self, KeySlice};
 use crate::lsm_iterator::{FusedIterator, LsmIterator};
-use crate::manifest::{Manifest, ManifestRecord};
+use crate::manifest::{Manifest, ManifestRecord, ManifestSnapshot, VersionEdit};
 use crate::mem_table::{map_bound, map_key_bound_plus_ts, MemTable};
 use crate::mvcc::txn::{Transaction, TxnIterator};
 use crate::mvcc::LsmMvccInner;
-use crate::table::{FileObject, SsTable, SsTableBuilder, SsTableIterator};
+use crate::replication::ReplicaUpdate;
+use crate::table::{FileObject, SsTable, SsTableIterator};
+use crate::wal::{WalBatchIterator, WalRecoveryMode};
 
-pub type BlockCache = moka::sync::Cache<(usize, usize), Arc<Block>>;
+pub use crate::block::BlockCache;
 
 /// Represents the state of the storage engine.
 #[derive(Clone)]
@@ -49,8 +50,22 @@ pub enum WriteBatchRecord<T: AsRef<[u8]>> {
     Del(T),
 }
 
+#[derive(Debug, Clone, Default)]
+pub struct WriteOptions {
+    /// Sync the WAL before the write returns, so that the write survives a machine crash.
+    pub sync: bool,
+}
+
+/// A write batch waiting in the commit queue. The first writer that gets the write lock commits all
+/// queued batches as a group and sends back the commit ts of each one.
+pub(crate) struct PendingWrite {
+    data: Vec<(Bytes, Bytes)>,
+    sync: bool,
+    result: crossbeam_channel::Sender<Result<u64>>,
+}
+
 impl LsmStorageState {
-    fn create(options: &LsmStorageOptions) -> Self {
+    pub(crate) fn create(options: &LsmStorageOptions) -> Self {
         let levels = match &options.compaction_options {
             CompactionOptions::Leveled(LeveledCompactionOptions { max_levels, .. })
             | CompactionOptions::Simple(SimpleLeveledCompactionOptions { max_levels, .. }) => (1
@@ -68,6 +83,67 @@ impl LsmStorageState {
             sstables: Default::default(),
         }
     }
+
+    /// Rearrange L0 and the levels for the compaction options, in case the storage is opened with options
+    /// different from those that built the structure. The precedence of the SSTs is kept: SSTs that do not fit
+    /// the new levels are moved to the end of L0 in the order of their levels.
+    fn migrate_levels(&mut self, options: &LsmStorageOptions) {
+        let expected_levels = Self::create(options).levels;
+        if let CompactionOptions::Tiered(_) = options.compaction_options {
+            // every L0 SST and every non-empty level becomes a tier, identified by its first SST
+            let l0_tiers = self.l0_sstables.drain(..).map(|id| (id, vec![id]));
+            let tiers = self
+                .levels
+                .drain(..)
+                .filter(|(_, ssts)| !ssts.is_empty())
+                .map(|(_, ssts)| (ssts[0], ssts));
+            self.levels = l0_tiers.chain(tiers).collect();
+            return;
+        }
+        let levels_fit = self
+            .levels
+            .iter()
+            .enumerate()
+            .all(|(index, (level, ssts))| {
+                *level == index + 1 && (index < expected_levels.len() || ssts.is_empty())
+            });
+        if levels_fit {
+            self.levels.truncate(expected_levels.len());
+            let num_levels = self.levels.len();
+            self.levels
+                .extend(expected_levels.into_iter().skip(num_levels));
+        } else {
+            for (_, ssts) in std::mem::replace(&mut self.levels, expected_levels) {
+                self.l0_sstables.extend(ssts);
+            }
+        }
+    }
+
+    /// The record that replaces the manifest history when the manifest is rotated.
+    fn manifest_snapshot(&self, next_sst_id: usize) -> ManifestSnapshot {
+        ManifestSnapshot {
+            l0_sstables: self.l0_sstables.clone(),
+            levels: self.levels.clone(),
+            memtables: self
+                .imm_memtables
+                .iter()
+                .rev()
+                .chain(std::iter::once(&self.memtable))
+                .map(|memtable| memtable.id())
+                .collect(),
+            next_sst_id,
+        }
+    }
+}
+
+/// What to do on open with the SSTs, WALs and manifest files not referenced by the manifest, which are left
+/// by a crash in the middle of a flush, a compaction or a manifest rotation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OrphanFileMode {
+    #[default]
+    Delete,
+    /// Move the files into the `quarantine` directory
+    Quarantine,
 }
 
 #[derive(Debug, Clone)]
@@ -78,9 +154,31 @@ pub struct LsmStorageOptions {
     pub target_sst_size: usize,
     // Maximum number of memtables in memory, flush to L0 when exceeding this limit
     pub num_memtable_limit: usize,
+    // Cut a compaction output SST when it overlaps with more than this many bytes of the level
+    // below the output level, 0 to disable
+    pub max_grandparent_overlap_size: usize,
     pub compaction_options: CompactionOptions,
     pub enable_wal: bool,
+    // How to handle incomplete or corrupted WAL records on recovery
+    pub wal_recovery_mode: WalRecoveryMode,
+    // Total size in bytes of the WALs kept after their memtables are flushed, so that `updates_since` can
+    // serve older writes; 0 to delete them right after the flush
+    pub wal_retention_size: usize,
+    // Rewrite the manifest into a new file starting with a snapshot of the state when it grows beyond
+    // this many bytes
+    pub manifest_max_size: usize,
+    // What to do with unreferenced files found on open
+    pub orphan_file_mode: OrphanFileMode,
     pub serializable: bool,
+    // The file system of the SSTs, WALs and manifest
+    pub fs: Arc<dyn FileSystem>,
+    // Read the SSTs through memory mappings instead of a read call and a new buffer per block.
+    // A value read from an SST then keeps the mapping of the whole file alive.
+    pub mmap_reads: bool,
+    // Total size in bytes of the blocks in the block cache
+    pub block_cache_size: usize,
+    // A block cache shared with other storages, used instead of a new cache of `block_cache_size` bytes
+    pub block_cache: Option<Arc<BlockCache>>,
 }
 
 impl LsmStorageOptions {
@@ -88,10 +186,19 @@ impl LsmStorageOptions {
         Self {
             block_size: 4096,
             target_sst_size: 2 << 20,
+            max_grandparent_overlap_size: 20 << 20,
             compaction_options: CompactionOptions::NoCompaction,
             enable_wal: false,
+            wal_recovery_mode: WalRecoveryMode::default(),
+            wal_retention_size: 0,
+            manifest_max_size: 1 << 20,
+            orphan_file_mode: OrphanFileMode::default(),
             num_memtable_limit: 50,
             serializable: false,
+            fs: Arc::new(PosixFileSystem),
+            mmap_reads: false,
+            block_cache_size: 64 << 20, // 64MB
+            block_cache: None,
         }
     }
 
@@ -99,10 +206,19 @@ impl LsmStorageOptions {
         Self {
             block_size: 4096,
             target_sst_size: 2 << 20,
+            max_grandparent_overlap_size: 20 << 20,
             compaction_options: CompactionOptions::NoCompaction,
             enable_wal: false,
+            wal_recovery_mode: WalRecoveryMode::default(),
+            wal_retention_size: 0,
+            manifest_max_size: 1 << 20,
+            orphan_file_mode: OrphanFileMode::default(),
             num_memtable_limit: 2,
             serializable: false,
+            fs: Arc::new(PosixFileSystem),
+            mmap_reads: false,
+            block_cache_size: 64 << 20, // 64MB
+            block_cache: None,
         }
     }
 
@@ -110,10 +226,45 @@ impl LsmStorageOptions {
         Self {
             block_size: 4096,
             target_sst_size: 1 << 20, // 1MB
+            max_grandparent_overlap_size: 10 << 20,
             compaction_options,
             enable_wal: false,
+            wal_recovery_mode: WalRecoveryMode::default(),
+            wal_retention_size: 0,
+            manifest_max_size: 1 << 20,
+            orphan_file_mode: OrphanFileMode::default(),
             num_memtable_limit: 2,
             serializable: false,
+            fs: Arc::new(PosixFileSystem),
+            mmap_reads: false,
+            block_cache_size: 64 << 20, // 64MB
+            block_cache: None,
+        }
+    }
+
+    /// The shared block cache of the options, or a new cache of `block_cache_size` bytes.
+    pub(crate) fn create_block_cache(&self) -> Arc<BlockCache> {
+        self.block_cache
+            .clone()
+            .unwrap_or_else(|| Arc::new(BlockCache::new(self.block_cache_size as u64)))
+    }
+
+    /// Open an SST file for reads, through a memory mapping if `mmap_reads` is set.
+    pub(crate) fn open_sst_file(&self, path: &Path) -> Result<FileObject> {
+        if self.mmap_reads {
+            FileObject::open_mmap_with_fs(&*self.fs, path)
+        } else {
+            FileObject::open_with_fs(&*self.fs, path)
+        }
+    }
+
+    /// Write an SST file and open it for reads.
+    pub(crate) fn create_sst_file(&self, path: &Path, data: Vec<u8>) -> Result<FileObject> {
+        if self.mmap_reads {
+            self.fs.write(path, &data)?;
+            FileObject::open_mmap_with_fs(&*self.fs, path)
+        } else {
+            FileObject::create_with_fs(&*self.fs, path, data)
         }
     }
 }
@@ -154,10 +305,75 @@ pub enum CompactionFilter {
     Prefix(Bytes),
 }
 
+/// The background work that failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BackgroundErrorReason {
+    Flush,
+    Compaction,
+    /// The WAL sync requested by a write, after the write is applied.
+    WalSync,
+    /// Freezing the memtable filled by a write, after the write is applied.
+    MemtableFreeze,
+}
+
+/// The error returned by all writes after a background flush or compaction fails, or after the WAL sync or
+/// memtable freeze following an applied write fails. Reads keep working until the storage is resumed with
+/// [`LsmStorageInner::resume`].
+#[derive(Clone, Debug)]
+pub struct BackgroundError {
+    pub reason: BackgroundErrorReason,
+    pub message: String,
+}
+
+impl std::fmt::Display for BackgroundError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let operation = match self.reason {
+            BackgroundErrorReason::Flush => "flush",
+            BackgroundErrorReason::Compaction => "compaction",
+            BackgroundErrorReason::WalSync => "WAL sync",
+            BackgroundErrorReason::MemtableFreeze => "memtable freeze",
+        };
+        write!(
+            f,
+            "storage is read-only because background {} failed: {}",
+            operation, self.message
+        )
+    }
+}
+
+impl std::error::Error for BackgroundError {}
+
+/// How the storage directory is opened.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum OpenMode {
+    ReadWrite,
+    /// Never write to the directory, and run no background threads.
+    ReadOnly,
+    /// Read-only, and skip the SSTs that are missing or fail checksum verification instead of failing to open.
+    Safe,
+}
+
+/// An SST skipped when opening in safe mode, and the range of keys that may be missing from reads because of it.
+#[derive(Clone, Debug)]
+pub struct UnavailableSst {
+    pub sst_id: usize,
+    /// 0 for L0, otherwise the position of the level or tier from 1
+    pub level: usize,
+    pub lower: Bound<Bytes>,
+    pub upper: Bound<Bytes>,
+    pub error: String,
+}
+
+/// An SST that failed to open in safe mode: its level, id, key range if the meta is readable, and the error.
+type FailedSst = (usize, usize, Option<(Bytes, Bytes)>, anyhow::Error);
+
 /// The storage interface of the LSM tree.
 pub(crate) struct LsmStorageInner {
     pub(crate) state: Arc<RwLock<Arc<LsmStorageState>>>,
     pub(crate) state_lock: Mutex<()>,
+    /// Held by a running compaction, so that a compaction triggered by hand does not race the compaction
+    /// thread on the same SSTs.
+    pub(crate) compaction_lock: Mutex<()>,
     path: PathBuf,
     pub(crate) block_cache: Arc<BlockCache>,
     next_sst_id: AtomicUsize,
@@ -166,6 +382,21 @@ pub(crate) struct LsmStorageInner {
     pub(crate) manifest: Option<Manifest>,
     pub(crate) mvcc: Option<LsmMvccInner>,
     pub(crate) compaction_filters: Arc<Mutex<Vec<CompactionFilter>>>,
+    /// Set when a background flush or compaction fails, and cleared by `resume`.
+    background_error: RwLock<Option<BackgroundError>>,
+    /// Write batches waiting for group commit.
+    pub(crate) pending_writes: Mutex<Vec<PendingWrite>>,
+    /// The connected replicas, which receive the write batches and manifest records.
+    pub(crate) replicas: Mutex<Vec<crossbeam_channel::Sender<ReplicaUpdate>>>,
+    /// Held for read when deleting SSTs and WALs, and for write by checkpoints to pause file deletion.
+    pub(crate) file_deletion_lock: RwLock<()>,
+    /// Set when opened read-only, in safe mode or as a secondary, where all writes fail.
+    read_only: bool,
+    /// The SSTs skipped when opened in safe mode.
+    unavailable_ssts: Vec<UnavailableSst>,
+    /// The LOCK file of the directory, locked while the storage is open for write. It is only released when
+    /// the storage is dropped, as a closed storage can still be written to.
+    _lock_file: Option<Box<dyn FileLock>>,
 }
 
 /// A thin wrapper for `LsmStorageInner` and the user interface for MiniLSM.
@@ -185,11 +416,20 @@ impl Drop for MiniLsm {
     fn drop(&mut self) {
         self.compaction_notifier.send(()).ok();
         self.flush_notifier.send(()).ok();
+        // the directory is unlocked once the background threads drop their references to the storage
+        for thread in [&self.compaction_thread, &self.flush_thread] {
+            if let Some(thread) = thread.lock().take() {
+                thread.join().ok();
+            }
+        }
     }
 }
 
 impl MiniLsm {
     pub fn close(&self) -> Result<()> {
+        if self.inner.read_only {
+            return Ok(());
+        }
         self.inner.sync_dir()?;
         self.compaction_notifier.send(()).ok();
         self.flush_notifier.send(()).ok();
@@ -225,7 +465,7 @@ impl MiniLsm {
             let snapshot = self.inner.state.read();
             !snapshot.imm_memtables.is_empty()
         } {
-            self.inner.force_flush_next_imm_memtable()?;
+            self.inner.force_flush_imm_memtables()?;
         }
         self.inner.sync_dir()?;
 
@@ -235,11 +475,57 @@ impl MiniLsm {
     /// Start the storage engine by either loading an existing directory or creating a new one if the directory does
     /// not exist.
     pub fn open(path: impl AsRef<Path>, options: LsmStorageOptions) -> Result<Arc<Self>> {
-        let inner = Arc::new(LsmStorageInner::open(path, options)?);
-        let (tx1, rx) = crossbeam_channel::unbounded();
-        let compaction_thread = inner.spawn_compaction_thread(rx)?;
-        let (tx2, rx) = crossbeam_channel::unbounded();
-        let flush_thread = inner.spawn_flush_thread(rx)?;
+        Self::start(LsmStorageInner::open(path, options)?)
+    }
+
+    /// Open an existing directory without writing to it. No WAL or manifest is written, no background thread is
+    /// started, and all writes fail.
+    pub fn open_read_only(path: impl AsRef<Path>, options: LsmStorageOptions) -> Result<Arc<Self>> {
+        Self::start(LsmStorageInner::open_with_mode(
+            path,
+            options,
+            OpenMode::ReadOnly,
+        )?)
+    }
+
+    /// Open an existing directory read-only, skipping the SSTs that are missing or corrupted instead of failing.
+    /// The key ranges that may be missing from reads are reported by `unavailable_ssts`.
+    pub fn open_safe_mode(path: impl AsRef<Path>, options: LsmStorageOptions) -> Result<Arc<Self>> {
+        Self::start(LsmStorageInner::open_with_mode(
+            path,
+            options,
+            OpenMode::Safe,
+        )?)
+    }
+
+    /// Open the directory of a primary running in another process as a secondary, which is read-only and
+    /// follows the writes of the primary with `try_catch_up_with_primary`.
+    pub fn open_as_secondary(
+        path: impl AsRef<Path>,
+        options: LsmStorageOptions,
+    ) -> Result<Arc<Self>> {
+        let storage = Self::start(LsmStorageInner::open_secondary(path, options)?)?;
+        storage.try_catch_up_with_primary()?;
+        Ok(storage)
+    }
+
+    pub fn try_catch_up_with_primary(&self) -> Result<()> {
+        self.inner.try_catch_up_with_primary()
+    }
+
+    /// Start the background threads of the storage, unless it is read-only.
+    fn start(inner: LsmStorageInner) -> Result<Arc<Self>> {
+        let inner = Arc::new(inner);
+        let (tx1, rx1) = crossbeam_channel::unbounded();
+        let (tx2, rx2) = crossbeam_channel::unbounded();
+        let (compaction_thread, flush_thread) = if inner.read_only {
+            (None, None)
+        } else {
+            (
+                inner.spawn_compaction_thread(rx1)?,
+                inner.spawn_flush_thread(rx2)?,
+            )
+        };
         Ok(Arc::new(Self {
             inner,
             flush_notifier: tx2,
@@ -253,6 +539,26 @@ impl MiniLsm {
         self.inner.add_compaction_filter(compaction_filter)
     }
 
+    pub fn background_error(&self) -> Option<BackgroundError> {
+        self.inner.background_error()
+    }
+
+    /// The SSTs skipped when opened in safe mode.
+    pub fn unavailable_ssts(&self) -> &[UnavailableSst] {
+        &self.inner.unavailable_ssts
+    }
+
+    pub fn resume(&self) -> Result<()> {
+        self.inner.resume()
+    }
+
+    pub fn updates_since(&self, ts: u64) -> Result<WalBatchIterator> {
+        self.inner.updates_since(ts)
+    }
+
+    /// Get a key from the storage. A value read from an SST shares the buffer of its block, so holding
+    /// it keeps the whole block in memory, and with `mmap_reads` the whole file mapped. Copy the value
+    /// to keep it for long.
     pub fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
         self.inner.get(key)
     }
@@ -261,6 +567,14 @@ impl MiniLsm {
         self.inner.write_batch(batch)
     }
 
+    pub fn write_batch_with_options<T: AsRef<[u8]>>(
+        &self,
+        batch: &[WriteBatchRecord<T>],
+        options: &WriteOptions,
+    ) -> Result<()> {
+        self.inner.write_batch_with_options(batch, options)
+    }
+
     pub fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
         self.inner.put(key, value)
     }
@@ -296,6 +610,10 @@ impl MiniLsm {
     pub fn force_full_compaction(&self) -> Result<()> {
         self.inner.force_full_compaction()
     }
+
+    pub fn create_checkpoint(&self, dir: impl AsRef<Path>) -> Result<()> {
+        self.inner.create_checkpoint(dir)
+    }
 }
 
 impl LsmStorageInner {
@@ -312,100 +630,189 @@ impl LsmStorageInner {
         self.manifest.as_ref().unwrap()
     }
 
+    /// Add a record to the manifest and ship it to the replicas.
+    pub(crate) fn add_manifest_record(
+        &self,
+        state_lock_observer: &MutexGuard<()>,
+        record: ManifestRecord,
+    ) -> Result<()> {
+        self.manifest()
+            .add_record(state_lock_observer, record.clone())?;
+        self.replicate_manifest_record(&record);
+        self.maybe_rotate_manifest(state_lock_observer)
+    }
+
+    /// Replace the manifest history with a snapshot of the current state once it grows too large.
+    fn maybe_rotate_manifest(&self, state_lock_observer: &MutexGuard<()>) -> Result<()> {
+        if self.manifest().size() > self.options.manifest_max_size as u64 {
+            self.manifest()
+                .rotate(state_lock_observer, self.manifest_snapshot())?;
+        }
+        Ok(())
+    }
+
+    /// The record describing the current state, which replaces the manifest history.
+    pub(crate) fn manifest_snapshot(&self) -> ManifestSnapshot {
+        self.state
+            .read()
+            .manifest_snapshot(self.next_sst_id.load(std::sync::atomic::Ordering::SeqCst))
+    }
+
     /// Start the storage engine by either loading an existing directory or creating a new one if the directory does
     /// not exist.
     pub(crate) fn open(path: impl AsRef<Path>, options: LsmStorageOptions) -> Result<Self> {
+        Self::open_with_mode(path, options, OpenMode::ReadWrite)
+    }
+
+    pub(crate) fn open_with_mode(
+        path: impl AsRef<Path>,
+        options: LsmStorageOptions,
+        mode: OpenMode,
+    ) -> Result<Self> {
         let mut state = LsmStorageState::create(&options);
         let path = path.as_ref();
         let mut next_sst_id = 1;
-        let block_cache = Arc::new(BlockCache::new(1 << 20)); // 4GB block cache,
+        let block_cache = options.create_block_cache();
         let manifest;
+        let read_only = mode != OpenMode::ReadWrite;
+        let mut unavailable_ssts = Vec::new();
 
-        let compaction_controller = match &options.compaction_options {
-            CompactionOptions::Leveled(options) => {
-                CompactionController::Leveled(LeveledCompactionController::new(options.clone()))
-            }
-            CompactionOptions::Tiered(options) => {
-                CompactionController::Tiered(TieredCompactionController::new(options.clone()))
-            }
-            CompactionOptions::Simple(options) => CompactionController::Simple(
-                SimpleLeveledCompactionController::new(options.clone()),
-            ),
-            CompactionOptions::NoCompaction => CompactionController::NoCompaction,
-        };
+        options.compaction_options.validate()?;
+        let compaction_controller = CompactionController::new(&options.compaction_options);
+        let fs = options.fs.clone();
 
-        if !path.exists() {
-            std::fs::create_dir_all(path).context("failed to create DB dir")?;
+        if read_only && !Manifest::exists(&*fs, path) {
+            bail!("no storage to open read-only at {}", path.display());
+        }
+        if !fs.exists(path) {
+            fs.create_dir_all(path).context("failed to create DB dir")?;
         }
-        let manifest_path = path.join("MANIFEST");
+        // read-only opens do not lock, so that they can read the directory of a running storage
+        let lock_file = if read_only {
+            None
+        } else {
+            Some(Self::lock_dir(&*fs, path)?)
+        };
         let mut last_commit_ts = 0;
-        if !manifest_path.exists() {
+        if !Manifest::exists(&*fs, path) {
             if options.enable_wal {
                 state.memtable = Arc::new(MemTable::create_with_wal(
                     state.memtable.id(),
+                    &*fs,
                     Self::path_of_wal_static(path, state.memtable.id()),
                 )?);
+                state.memtable.put_wal_start_ts(last_commit_ts)?;
             }
-            manifest = Manifest::create(&manifest_path).context("failed to create manifest")?;
-            manifest.add_record_when_init(ManifestRecord::NewMemtable(state.memtable.id()))?;
+            let m = Manifest::create(fs.clone(), path).context("failed to create manifest")?;
+            m.add_record_when_init(ManifestRecord::NewMemtable(state.memtable.id()))?;
+            manifest = Some(m);
         } else {
-            let (m, records) = Manifest::recover(&manifest_path)?;
-            let mut memtables = BTreeSet::new();
-            for record in records {
-                match record {
-                    ManifestRecord::Flush(sst_id) => {
-                        let res = memtables.remove(&sst_id);
-                        assert!(res, "memtable not exist?");
-                        if compaction_controller.flush_to_l0() {
-                            state.l0_sstables.insert(0, sst_id);
-                        } else {
-                            state.levels.insert(0, (sst_id, vec![sst_id]));
-                        }
-                        next_sst_id = next_sst_id.max(sst_id);
-                    }
-                    ManifestRecord::NewMemtable(x) => {
-                        next_sst_id = next_sst_id.max(x);
-                        memtables.insert(x);
-                    }
-                    ManifestRecord::Compaction(task, output) => {
-                        let (new_state, _) =
-                            compaction_controller.apply_compaction_result(&state, &task, &output);
-                        // TODO: apply remove again
-                        state = new_state;
-                        next_sst_id =
-                            next_sst_id.max(output.iter().max().copied().unwrap_or_default());
-                    }
-                }
+            let (m, records) = if read_only {
+                (None, Manifest::read_records(&*fs, path)?)
+            } else {
+                let (m, records) = Manifest::recover(fs.clone(), path)?;
+                (Some(m), records)
+            };
+            let (replayed_state, mut memtables, max_id) =
+                Self::replay_manifest(&options, &compaction_controller, records)?;
+            state = replayed_state;
+            next_sst_id = max_id;
+
+            let recovered_state = state.clone();
+            state.migrate_levels(&options);
+            let edit = VersionEdit::diff(&recovered_state, &state);
+            if let Some(m) = m.as_ref().filter(|_| !edit.is_empty()) {
+                println!("levels migrated for the compaction options");
+                m.add_record_when_init(ManifestRecord::Compaction(edit))?;
+            }
+
+            let wal_ids = if options.enable_wal {
+                memtables.iter().copied().collect()
+            } else {
+                Vec::new()
+            };
+            if mode != OpenMode::Safe {
+                Self::check_referenced_files(&*fs, path, &state, &wal_ids)?;
             }
 
             let mut sst_cnt = 0;
+            let mut failed_ssts = Vec::new();
             // recover SSTs
-            for table_id in state
-                .l0_sstables
-                .iter()
-                .chain(state.levels.iter().flat_map(|(_, files)| files))
-            {
+            for (level, table_id) in state.l0_sstables.iter().map(|id| (0, id)).chain(
+                state
+                    .levels
+                    .iter()
+                    .enumerate()
+                    .flat_map(|(idx, (_, files))| files.iter().map(move |id| (idx + 1, id))),
+            ) {
                 let table_id = *table_id;
-                let sst = SsTable::open(
-                    table_id,
-                    Some(block_cache.clone()),
-                    FileObject::open(&Self::path_of_sst_static(path, table_id))
-                        .context("failed to open SST")?,
-                )?;
+                let sst = options
+                    .open_sst_file(&Self::path_of_sst_static(path, table_id))
+                    .context("failed to open SST")
+                    .and_then(|file| SsTable::open(table_id, Some(block_cache.clone()), file));
+                let sst = match (sst, mode) {
+                    (Ok(sst), OpenMode::Safe) => {
+                        // verify all blocks, so that reads never hit a corrupted block
+                        match (0..sst.num_of_blocks())
+                            .try_for_each(|idx| sst.read_block(idx).map(|_| ()))
+                        {
+                            Ok(()) => sst,
+                            Err(e) => {
+                                let key_range = (
+                                    Bytes::copy_from_slice(sst.first_key().key_ref()),
+                                    Bytes::copy_from_slice(sst.last_key().key_ref()),
+                                );
+                                failed_ssts.push((level, table_id, Some(key_range), e));
+                                continue;
+                            }
+                        }
+                    }
+                    (Ok(sst), _) => sst,
+                    (Err(e), OpenMode::Safe) => {
+                        failed_ssts.push((level, table_id, None, e));
+                        continue;
+                    }
+                    (Err(e), _) => return Err(e),
+                };
                 last_commit_ts = last_commit_ts.max(sst.max_ts());
                 state.sstables.insert(table_id, Arc::new(sst));
                 sst_cnt += 1;
             }
             println!("{} SSTs opened", sst_cnt);
+            if !failed_ssts.is_empty() {
+                unavailable_ssts = Self::remove_unavailable_ssts(&mut state, failed_ssts);
+                for sst in &unavailable_ssts {
+                    eprintln!(
+                        "{}.sst is unavailable, keys in {:?}..{:?} may be missing: {}",
+                        sst.sst_id, sst.lower, sst.upper, sst.error
+                    );
+                }
+            }
 
             next_sst_id += 1;
 
             // recover memtables
             if options.enable_wal {
                 let mut wal_cnt = 0;
+                let mut point_in_time_reached = false;
                 for id in memtables.iter() {
-                    let memtable =
-                        MemTable::recover_from_wal(*id, Self::path_of_wal_static(path, *id))?;
+                    let wal_path = Self::path_of_wal_static(path, *id);
+                    if point_in_time_reached {
+                        if read_only {
+                            break;
+                        }
+                        // drop all writes after the first corrupted record
+                        fs.truncate(&wal_path, 0)?;
+                    }
+                    let (memtable, fully_recovered) = if read_only {
+                        MemTable::read_from_wal(*id, &*fs, wal_path, options.wal_recovery_mode)?
+                    } else {
+                        MemTable::recover_from_wal(*id, &*fs, wal_path, options.wal_recovery_mode)?
+                    };
+                    if !fully_recovered && options.wal_recovery_mode == WalRecoveryMode::PointInTime
+                    {
+                        point_in_time_reached = true;
+                    }
                     let max_ts = memtable
                         .map
                         .iter()
@@ -419,40 +826,311 @@ impl LsmStorageInner {
                     }
                 }
                 println!("{} WALs recovered", wal_cnt);
-                state.memtable = Arc::new(MemTable::create_with_wal(
-                    next_sst_id,
-                    Self::path_of_wal_static(path, next_sst_id),
-                )?);
-            } else {
-                state.memtable = Arc::new(MemTable::create(next_sst_id));
             }
-            m.add_record_when_init(ManifestRecord::NewMemtable(state.memtable.id()))?;
+            state.memtable = if options.enable_wal && !read_only {
+                // a crash after creating a WAL but before recording it in the manifest leaves an
+                // unreferenced WAL with the next id behind, which has no writes as the memtable is only
+                // used once it is recorded
+                let wal_path = Self::path_of_wal_static(path, next_sst_id);
+                if fs.exists(&wal_path) {
+                    fs.remove_file(&wal_path)?;
+                }
+                let memtable = MemTable::create_with_wal(next_sst_id, &*fs, wal_path)?;
+                memtable.put_wal_start_ts(last_commit_ts)?;
+                Arc::new(memtable)
+            } else {
+                Arc::new(MemTable::create(next_sst_id))
+            };
             next_sst_id += 1;
+            if let Some(m) = &m {
+                fs.sync_dir(path)?;
+                m.add_record_when_init(ManifestRecord::NewMemtable(state.memtable.id()))?;
+                // JSON records are rewritten in the binary encoding
+                if m.size() > options.manifest_max_size as u64 || m.has_json_records() {
+                    m.rotate_when_init(state.manifest_snapshot(next_sst_id))?;
+                }
+                memtables.insert(state.memtable.id());
+                Self::clean_up_orphan_files(path, &options, m, &state, &memtables)?;
+            }
             manifest = m;
         };
 
         let storage = Self {
             state: Arc::new(RwLock::new(Arc::new(state))),
             state_lock: Mutex::new(()),
+            compaction_lock: Mutex::new(()),
             path: path.to_path_buf(),
             block_cache,
             next_sst_id: AtomicUsize::new(next_sst_id),
             compaction_controller,
-            manifest: Some(manifest),
+            manifest,
             options: options.into(),
             mvcc: Some(LsmMvccInner::new(last_commit_ts)),
             compaction_filters: Arc::new(Mutex::new(Vec::new())),
+            background_error: RwLock::new(None),
+            pending_writes: Mutex::new(Vec::new()),
+            replicas: Mutex::new(Vec::new()),
+            file_deletion_lock: RwLock::new(()),
+            read_only,
+            unavailable_ssts,
+            _lock_file: lock_file,
         };
-        storage.sync_dir()?;
+        if !read_only {
+            storage.sync_dir()?;
+        }
 
         Ok(storage)
     }
 
+    /// Rebuild the levels from the manifest records. Returns the state without the SSTs and memtables opened,
+    /// the ids of the memtables not flushed, and the largest SST or memtable id.
+    pub(crate) fn replay_manifest(
+        options: &LsmStorageOptions,
+        compaction_controller: &CompactionController,
+        records: Vec<ManifestRecord>,
+    ) -> Result<(LsmStorageState, BTreeSet<usize>, usize)> {
+        let mut state = LsmStorageState::create(options);
+        let mut next_sst_id = 1;
+        let mut memtables = BTreeSet::new();
+        for record in records {
+            match record {
+                ManifestRecord::Flush(memtable_ids, edit) => {
+                    for memtable_id in memtable_ids {
+                        let res = memtables.remove(&memtable_id);
+                        assert!(res, "memtable not exist?");
+                    }
+                    edit.apply(&mut state)?;
+                    next_sst_id =
+                        next_sst_id.max(edit.added_ssts().into_iter().max().unwrap_or_default());
+                }
+                ManifestRecord::LegacyFlush(memtable_ids, output) => {
+                    for memtable_id in memtable_ids {
+                        let res = memtables.remove(&memtable_id);
+                        assert!(res, "memtable not exist?");
+                    }
+                    if compaction_controller.flush_to_l0() {
+                        state.l0_sstables.splice(0..0, output.iter().copied());
+                    } else if let Some(&tier_id) = output.first() {
+                        state.levels.insert(0, (tier_id, output.clone()));
+                    }
+                    next_sst_id = next_sst_id.max(output.iter().max().copied().unwrap_or_default());
+                }
+                ManifestRecord::NewMemtable(x) => {
+                    next_sst_id = next_sst_id.max(x);
+                    memtables.insert(x);
+                }
+                ManifestRecord::Compaction(edit) => {
+                    edit.apply(&mut state)?;
+                    next_sst_id =
+                        next_sst_id.max(edit.added_ssts().into_iter().max().unwrap_or_default());
+                }
+                ManifestRecord::LegacyCompaction(task, output) => {
+                    let (new_state, _) =
+                        compaction_controller.apply_compaction_result(&state, &task, &output);
+                    state = new_state;
+                    next_sst_id = next_sst_id.max(output.iter().max().copied().unwrap_or_default());
+                }
+                ManifestRecord::Snapshot(snapshot) => {
+                    state.l0_sstables = snapshot.l0_sstables;
+                    state.levels = snapshot.levels;
+                    memtables = snapshot.memtables.into_iter().collect();
+                    next_sst_id = next_sst_id.max(snapshot.next_sst_id);
+                }
+            }
+        }
+
+        Ok((state, memtables, next_sst_id))
+    }
+
+    /// Remove the SSTs that failed to open in safe mode from the levels, and return the key ranges that may be
+    /// missing because of them.
+    fn remove_unavailable_ssts(
+        state: &mut LsmStorageState,
+        failed_ssts: Vec<FailedSst>,
+    ) -> Vec<UnavailableSst> {
+        let mut unavailable_ssts = Vec::with_capacity(failed_ssts.len());
+        for (level, sst_id, key_range, error) in failed_ssts {
+            let (lower, upper) = match key_range {
+                Some((first_key, last_key)) => {
+                    (Bound::Included(first_key), Bound::Included(last_key))
+                }
+                // L0 SSTs may have any keys
+                None if level == 0 => (Bound::Unbounded, Bound::Unbounded),
+                None => {
+                    // the SSTs in a level are sorted, so the missing keys are between the available neighbors
+                    let ssts = &state.levels[level - 1].1;
+                    let idx = ssts.iter().position(|id| *id == sst_id).unwrap();
+                    let lower = ssts[..idx]
+                        .iter()
+                        .rev()
+                        .find_map(|id| state.sstables.get(id))
+                        .map_or(Bound::Unbounded, |sst| {
+                            Bound::Excluded(Bytes::copy_from_slice(sst.last_key().key_ref()))
+                        });
+                    let upper = ssts[idx + 1..]
+                        .iter()
+                        .find_map(|id| state.sstables.get(id))
+                        .map_or(Bound::Unbounded, |sst| {
+                            Bound::Excluded(Bytes::copy_from_slice(sst.first_key().key_ref()))
+                        });
+                    (lower, upper)
+                }
+            };
+            unavailable_ssts.push(UnavailableSst {
+                sst_id,
+                level,
+                lower,
+                upper,
+                error: format!("{:#}", error),
+            });
+        }
+        let is_available = |id: &usize| state.sstables.contains_key(id);
+        state.l0_sstables.retain(is_available);
+        for (_, ssts) in &mut state.levels {
+            ssts.retain(is_available);
+        }
+        unavailable_ssts
+    }
+
+    /// Open the directory of a primary as a secondary. The state is empty until `try_catch_up_with_primary`.
+    pub(crate) fn open_secondary(
+        path: impl AsRef<Path>,
+        options: LsmStorageOptions,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        if !Manifest::exists(&*options.fs, path) {
+            bail!("no storage to open as secondary at {}", path.display());
+        }
+        Ok(Self {
+            state: Arc::new(RwLock::new(Arc::new(LsmStorageState::create(&options)))),
+            state_lock: Mutex::new(()),
+            compaction_lock: Mutex::new(()),
+            path: path.to_path_buf(),
+            block_cache: options.create_block_cache(),
+            next_sst_id: AtomicUsize::new(1),
+            compaction_controller: CompactionController::new(&options.compaction_options),
+            manifest: None,
+            options: options.into(),
+            mvcc: Some(LsmMvccInner::new(0)),
+            compaction_filters: Arc::new(Mutex::new(Vec::new())),
+            background_error: RwLock::new(None),
+            pending_writes: Mutex::new(Vec::new()),
+            replicas: Mutex::new(Vec::new()),
+            file_deletion_lock: RwLock::new(()),
+            read_only: true,
+            unavailable_ssts: Vec::new(),
+            _lock_file: None,
+        })
+    }
+
+    /// Create the storage of a replica in an empty directory. The replica has no manifest or WAL, and its
+    /// state is filled by the primary.
+    pub(crate) fn open_replica(path: impl AsRef<Path>, options: LsmStorageOptions) -> Result<Self> {
+        let path = path.as_ref();
+        let fs = &*options.fs;
+        fs.create_dir_all(path).context("failed to create DB dir")?;
+        if !fs.list(path)?.is_empty() {
+            bail!("replica dir {} is not empty", path.display());
+        }
+        let lock_file = Self::lock_dir(fs, path)?;
+        Ok(Self {
+            state: Arc::new(RwLock::new(Arc::new(LsmStorageState::create(&options)))),
+            state_lock: Mutex::new(()),
+            compaction_lock: Mutex::new(()),
+            path: path.to_path_buf(),
+            block_cache: options.create_block_cache(),
+            next_sst_id: AtomicUsize::new(1),
+            compaction_controller: CompactionController::new(&options.compaction_options),
+            manifest: None,
+            options: options.into(),
+            mvcc: Some(LsmMvccInner::new(0)),
+            compaction_filters: Arc::new(Mutex::new(Vec::new())),
+            background_error: RwLock::new(None),
+            pending_writes: Mutex::new(Vec::new()),
+            replicas: Mutex::new(Vec::new()),
+            file_deletion_lock: RwLock::new(()),
+            read_only: false,
+            unavailable_ssts: Vec::new(),
+            _lock_file: Some(lock_file),
+        })
+    }
+
     pub fn add_compaction_filter(&self, compaction_filter: CompactionFilter) {
         let mut compaction_filters = self.compaction_filters.lock();
         compaction_filters.push(compaction_filter);
     }
 
+    /// Returns the error of the failed background work, if the storage is read-only.
+    pub fn background_error(&self) -> Option<BackgroundError> {
+        self.background_error.read().clone()
+    }
+
+    /// Puts the storage into the read-only state. Only the first error is kept, as later errors are
+    /// usually caused by it.
+    pub(crate) fn set_background_error(&self, reason: BackgroundErrorReason, error: anyhow::Error) {
+        eprintln!("background {:?} failed: {:#}", reason, error);
+        let mut background_error = self.background_error.write();
+        if background_error.is_none() {
+            *background_error = Some(BackgroundError {
+                reason,
+                message: format!("{:#}", error),
+            });
+        }
+    }
+
+    pub(crate) fn check_read_only(&self) -> Result<()> {
+        if self.read_only {
+            bail!("storage is opened read-only");
+        }
+        Ok(())
+    }
+
+    fn check_background_error(&self) -> Result<()> {
+        if let Some(error) = self.background_error() {
+            return Err(error.into());
+        }
+        Ok(())
+    }
+
+    /// Retries the failed background work and makes the storage writable again if it succeeds. Call this
+    /// after the underlying problem (e.g., a full disk) is fixed.
+    pub fn resume(&self) -> Result<()> {
+        let Some(error) = self.background_error() else {
+            return Ok(());
+        };
+        // The background threads skip their work until the error is cleared, and a retried compaction takes
+        // `compaction_lock` like any other, so it does not race a compaction triggered by hand.
+        let result = match error.reason {
+            BackgroundErrorReason::Flush => {
+                if self.state.read().imm_memtables.is_empty() {
+                    Ok(())
+                } else {
+                    self.force_flush_imm_memtables()
+                }
+            }
+            BackgroundErrorReason::Compaction => self.trigger_compaction(),
+            BackgroundErrorReason::WalSync => self.sync(),
+            BackgroundErrorReason::MemtableFreeze => {
+                let size = self.state.read().memtable.approximate_size();
+                self.try_freeze(size)
+            }
+        };
+        let mut background_error = self.background_error.write();
+        match result {
+            Ok(()) => {
+                *background_error = None;
+                Ok(())
+            }
+            Err(e) => {
+                *background_error = Some(BackgroundError {
+                    reason: error.reason,
+                    message: format!("{:#}", e),
+                });
+                Err(e)
+            }
+        }
+    }
+
     pub fn sync(&self) -> Result<()> {
         self.state.read().memtable.sync_wal()
     }
@@ -537,52 +1215,150 @@ impl LsmStorageInner {
         )?;
 
         if iter.is_valid() && iter.key() == key && !iter.value().is_empty() {
-            return Ok(Some(Bytes::copy_from_slice(iter.value())));
+            return Ok(Some(iter.value_bytes()));
         }
         Ok(None)
     }
 
-    pub fn write_batch_inner<T: AsRef<[u8]>>(&self, batch: &[WriteBatchRecord<T>]) -> Result<u64> {
-        let _lck = self.mvcc().write_lock.lock();
-        let ts = self.mvcc().latest_commit_ts() + 1;
+    pub fn write_batch_inner<T: AsRef<[u8]>>(
+        &self,
+        batch: &[WriteBatchRecord<T>],
+        options: &WriteOptions,
+    ) -> Result<u64> {
+        self.check_read_only()?;
+        self.check_background_error()?;
+        let mut data = Vec::with_capacity(batch.len());
         for record in batch {
             match record {
                 WriteBatchRecord::Del(key) => {
                     let key = key.as_ref();
                     assert!(!key.is_empty(), "key cannot be empty");
-                    let size;
-                    {
-                        let guard = self.state.read();
-                        guard.memtable.put(KeySlice::from_slice(key, ts), b"")?;
-                        size = guard.memtable.approximate_size();
-                    }
-                    self.try_freeze(size)?;
+                    data.push((Bytes::copy_from_slice(key), Bytes::new()));
                 }
                 WriteBatchRecord::Put(key, value) => {
                     let key = key.as_ref();
                     let value = value.as_ref();
                     assert!(!key.is_empty(), "key cannot be empty");
                     assert!(!value.is_empty(), "value cannot be empty");
-                    let size;
-                    {
-                        let guard = self.state.read();
-                        guard.memtable.put(KeySlice::from_slice(key, ts), value)?;
-                        size = guard.memtable.approximate_size();
-                    }
-                    self.try_freeze(size)?;
+                    data.push((Bytes::copy_from_slice(key), Bytes::copy_from_slice(value)));
+                }
+            }
+        }
+        if data.is_empty() {
+            // nothing is written, so no commit ts is taken
+            return Ok(self.mvcc().latest_commit_ts());
+        }
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        self.pending_writes.lock().push(PendingWrite {
+            data,
+            sync: options.sync,
+            result: tx,
+        });
+        {
+            let _lck = self.mvcc().write_lock.lock();
+            // If the result is not ready, no leader has taken this batch yet, so this writer becomes the
+            // leader and commits all batches queued so far, including its own.
+            if rx.is_empty() {
+                let group = std::mem::take(&mut *self.pending_writes.lock());
+                let first_ts = self.mvcc().latest_commit_ts() + 1;
+                let results = self.commit_write_group(&group, first_ts);
+                for (write, result) in group.iter().zip(results) {
+                    write.result.send(result).ok();
                 }
             }
         }
-        self.mvcc().update_commit_ts(ts);
-        Ok(ts)
+        rx.recv()?
+    }
+
+    /// Write a group of batches with one WAL append and at most one WAL sync. Each batch gets its own
+    /// commit ts starting from `first_ts`, and the result of each batch is returned in order.
+    ///
+    /// A failed WAL sync fails the batches that asked for a sync, and a failed memtable freeze fails all
+    /// batches. As the batches are already applied and visible by then, the failure also puts the storage into
+    /// the read-only state.
+    fn commit_write_group(&self, group: &[PendingWrite], first_ts: u64) -> Vec<Result<u64>> {
+        let (memtable, size) = match self.apply_write_group(group, first_ts) {
+            Ok(applied) => applied,
+            Err(e) => {
+                return group
+                    .iter()
+                    .map(|_| Err(anyhow::anyhow!("{:#}", e)))
+                    .collect()
+            }
+        };
+        let mut sync_error = None;
+        if group.iter().any(|write| write.sync) {
+            if let Err(e) = memtable.sync_wal() {
+                sync_error = Some(format!("failed to sync the WAL: {:#}", e));
+                self.set_background_error(BackgroundErrorReason::WalSync, e);
+            }
+        }
+        let mut freeze_error = None;
+        if let Err(e) = self.try_freeze(size) {
+            freeze_error = Some(format!("failed to freeze the memtable: {:#}", e));
+            self.set_background_error(BackgroundErrorReason::MemtableFreeze, e);
+        }
+        group
+            .iter()
+            .zip(first_ts..)
+            .map(|(write, ts)| match (&sync_error, &freeze_error) {
+                (Some(e), _) if write.sync => Err(anyhow::anyhow!("{}", e)),
+                (_, Some(e)) => Err(anyhow::anyhow!("{}", e)),
+                _ => Ok(ts),
+            })
+            .collect()
+    }
+
+    /// Put a group of batches into the memtable and publish their commit ts. Returns the memtable and its size
+    /// after the write.
+    fn apply_write_group(
+        &self,
+        group: &[PendingWrite],
+        first_ts: u64,
+    ) -> Result<(Arc<MemTable>, usize)> {
+        let batches = group
+            .iter()
+            .zip(first_ts..)
+            .map(|(write, ts)| {
+                write
+                    .data
+                    .iter()
+                    .map(|(key, value)| (KeySlice::from_slice(key, ts), &value[..]))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let batches = batches.iter().map(|x| x.as_slice()).collect::<Vec<_>>();
+        // All batches of the group go into the same memtable so that each is written as one WAL record.
+        let memtable;
+        let size;
+        {
+            let guard = self.state.read();
+            guard.memtable.put_batches(&batches)?;
+            self.replicate_batches(guard.memtable.id(), &batches);
+            // publish the commit ts before the memtable can be frozen, so that the start ts of the next WAL
+            // covers these batches
+            self.mvcc()
+                .update_commit_ts(first_ts + group.len() as u64 - 1);
+            memtable = guard.memtable.clone();
+            size = guard.memtable.approximate_size();
+        }
+        Ok((memtable, size))
     }
 
     pub fn write_batch<T: AsRef<[u8]>>(
         self: &Arc<Self>,
         batch: &[WriteBatchRecord<T>],
+    ) -> Result<()> {
+        self.write_batch_with_options(batch, &WriteOptions::default())
+    }
+
+    pub fn write_batch_with_options<T: AsRef<[u8]>>(
+        self: &Arc<Self>,
+        batch: &[WriteBatchRecord<T>],
+        options: &WriteOptions,
     ) -> Result<()> {
         if !self.options.serializable {
-            self.write_batch_inner(batch)?;
+            self.write_batch_inner(batch, options)?;
         } else {
             let txn = self.mvcc().new_txn(self.clone(), self.options.serializable);
             for record in batch {
@@ -595,7 +1371,7 @@ impl LsmStorageInner {
                     }
                 }
             }
-            txn.commit()?;
+            txn.commit_with_options(options)?;
         }
         Ok(())
     }
@@ -603,7 +1379,10 @@ impl LsmStorageInner {
     /// Put a key-value pair into the storage by writing into the current memtable.
     pub fn put(self: &Arc<Self>, key: &[u8], value: &[u8]) -> Result<()> {
         if !self.options.serializable {
-            self.write_batch_inner(&[WriteBatchRecord::Put(key, value)])?;
+            self.write_batch_inner(
+                &[WriteBatchRecord::Put(key, value)],
+                &WriteOptions::default(),
+            )?;
         } else {
             let txn = self.mvcc().new_txn(self.clone(), self.options.serializable);
             txn.put(key, value);
@@ -615,7 +1394,7 @@ impl LsmStorageInner {
     /// Remove a key from the storage by writing an empty value.
     pub fn delete(self: &Arc<Self>, key: &[u8]) -> Result<()> {
         if !self.options.serializable {
-            self.write_batch_inner(&[WriteBatchRecord::Del(key)])?;
+            self.write_batch_inner(&[WriteBatchRecord::Del(key)], &WriteOptions::default())?;
         } else {
             let txn = self.mvcc().new_txn(self.clone(), self.options.serializable);
             txn.delete(key);
@@ -637,6 +1416,10 @@ impl LsmStorageInner {
         Ok(())
     }
 
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
     pub(crate) fn path_of_sst_static(path: impl AsRef<Path>, id: usize) -> PathBuf {
         path.as_ref().join(format!("{:05}.sst", id))
     }
@@ -653,13 +1436,212 @@ impl LsmStorageInner {
         Self::path_of_wal_static(&self.path, id)
     }
 
-    pub(super) fn sync_dir(&self) -> Result<()> {
-        File::open(&self.path)?.sync_all()?;
+    pub(crate) fn path_of_quarantine_static(path: impl AsRef<Path>) -> PathBuf {
+        path.as_ref().join("quarantine")
+    }
+
+    pub(crate) fn path_of_lock_static(path: impl AsRef<Path>) -> PathBuf {
+        path.as_ref().join("LOCK")
+    }
+
+    /// Take the lock on the LOCK file of the directory, so that it is never opened for write by two storages
+    /// at the same time. The lock is released when the returned lock is dropped, including when the process
+    /// exits.
+    pub(crate) fn lock_dir(fs: &dyn FileSystem, path: &Path) -> Result<Box<dyn FileLock>> {
+        match fs
+            .try_lock(&Self::path_of_lock_static(path))
+            .context("failed to lock LOCK file")?
+        {
+            Some(lock) => Ok(lock),
+            None => bail!(
+                "{} is already opened by another storage or process",
+                path.display()
+            ),
+        }
+    }
+
+    /// Fail if any SST in the recovered state or any WAL to recover is missing.
+    fn check_referenced_files(
+        fs: &dyn FileSystem,
+        path: &Path,
+        state: &LsmStorageState,
+        wal_ids: &[usize],
+    ) -> Result<()> {
+        let missing_files = state
+            .l0_sstables
+            .iter()
+            .chain(state.levels.iter().flat_map(|(_, files)| files))
+            .map(|id| Self::path_of_sst_static(path, *id))
+            .chain(wal_ids.iter().map(|id| Self::path_of_wal_static(path, *id)))
+            .filter(|path| !fs.exists(path))
+            .collect::<Vec<_>>();
+        if !missing_files.is_empty() {
+            bail!(
+                "files referenced by the manifest are missing: {:?}",
+                missing_files
+            );
+        }
+        Ok(())
+    }
+
+    /// Delete or quarantine the SSTs, WALs and manifest files in the directory that are not referenced by the
+    /// recovered state. Other files and directories are left alone.
+    fn clean_up_orphan_files(
+        path: &Path,
+        options: &LsmStorageOptions,
+        manifest: &Manifest,
+        state: &LsmStorageState,
+        memtables: &BTreeSet<usize>,
+    ) -> Result<()> {
+        let ssts = state
+            .l0_sstables
+            .iter()
+            .chain(state.levels.iter().flat_map(|(_, files)| files))
+            .collect::<HashSet<_>>();
+        let fs = &*options.fs;
+        let manifest_path = manifest.path();
+        let mut orphan_files = Vec::new();
+        for name in fs.list(path)? {
+            let parse_id = |suffix| name.strip_suffix(suffix)?.parse::<usize>().ok();
+            let is_orphan = if let Some(id) = parse_id(".sst") {
+                !ssts.contains(&id)
+            } else if let Some(id) = parse_id(".wal") {
+                !memtables.contains(&id)
+            } else {
+                (name.starts_with("MANIFEST") && path.join(&name) != manifest_path)
+                    || name == "CURRENT.tmp"
+            };
+            if is_orphan {
+                orphan_files.push(path.join(&name));
+            }
+        }
+        if orphan_files.is_empty() {
+            return Ok(());
+        }
+        match options.orphan_file_mode {
+            OrphanFileMode::Delete => {
+                for file in &orphan_files {
+                    fs.remove_file(file)?;
+                }
+                println!("{} orphan files deleted", orphan_files.len());
+            }
+            OrphanFileMode::Quarantine => {
+                let quarantine = Self::path_of_quarantine_static(path);
+                fs.create_dir_all(&quarantine)?;
+                for file in &orphan_files {
+                    fs.rename(file, &quarantine.join(file.file_name().unwrap()))?;
+                }
+                fs.sync_dir(&quarantine)?;
+                println!("{} orphan files quarantined", orphan_files.len());
+            }
+        }
+        fs.sync_dir(path)?;
+        Ok(())
+    }
+
+    /// The directory of the WALs retained after their memtables are flushed.
+    pub(crate) fn path_of_wal_archive(&self) -> PathBuf {
+        self.path.join("archive")
+    }
+
+    /// Keep the WAL of a flushed memtable in the archive if the retention policy allows, or delete it.
+    fn retire_wal(&self, id: usize) -> Result<()> {
+        let _file_deletion_lock = self.file_deletion_lock.read();
+        let fs = &*self.options.fs;
+        if self.options.wal_retention_size == 0 {
+            fs.remove_file(&self.path_of_wal(id))?;
+            return Ok(());
+        }
+        let archive = self.path_of_wal_archive();
+        fs.create_dir_all(&archive)?;
+        fs.rename(
+            &self.path_of_wal(id),
+            &Self::path_of_wal_static(&archive, id),
+        )?;
         Ok(())
     }
 
+    /// The ids of the archived WALs, from earliest to latest.
+    fn archived_wal_ids(&self) -> Result<Vec<usize>> {
+        let archive = self.path_of_wal_archive();
+        if !self.options.fs.exists(&archive) {
+            return Ok(Vec::new());
+        }
+        let mut ids = Vec::new();
+        for name in self.options.fs.list(&archive)? {
+            if let Some(id) = name.strip_suffix(".wal").and_then(|id| id.parse().ok()) {
+                ids.push(id);
+            }
+        }
+        ids.sort();
+        Ok(ids)
+    }
+
+    /// Delete the earliest archived WALs until the archive fits in `wal_retention_size`.
+    fn prune_archived_wals(&self) -> Result<()> {
+        let _file_deletion_lock = self.file_deletion_lock.read();
+        let archive = self.path_of_wal_archive();
+        let mut wals = Vec::new();
+        let mut total_size = 0;
+        for id in self.archived_wal_ids()? {
+            let path = Self::path_of_wal_static(&archive, id);
+            let size = self.options.fs.file_size(&path)? as usize;
+            total_size += size;
+            wals.push((path, size));
+        }
+        for (path, size) in wals {
+            if total_size <= self.options.wal_retention_size {
+                break;
+            }
+            self.options.fs.remove_file(&path)?;
+            total_size -= size;
+        }
+        Ok(())
+    }
+
+    /// Returns the batches committed after `ts` in commit order, read from the archived WALs and the WALs of
+    /// the memtables. Fails if some of them are already discarded by the WAL retention policy.
+    pub fn updates_since(&self, ts: u64) -> Result<WalBatchIterator> {
+        if !self.options.enable_wal {
+            bail!("updates_since requires WAL to be enabled");
+        }
+        let end_ts = self.mvcc().latest_commit_ts();
+        // make sure all batches up to `end_ts` are in the file
+        self.sync()?;
+        let mut files = Vec::new();
+        {
+            // flushes move and delete WALs with the state lock held
+            let _state_lock = self.state_lock.lock();
+            let archive = self.path_of_wal_archive();
+            for id in self.archived_wal_ids()? {
+                files.push(
+                    self.options
+                        .fs
+                        .open(&Self::path_of_wal_static(&archive, id))?,
+                );
+            }
+            let snapshot = self.state.read().clone();
+            for memtable in snapshot
+                .imm_memtables
+                .iter()
+                .rev()
+                .chain(std::iter::once(&snapshot.memtable))
+            {
+                files.push(self.options.fs.open(&self.path_of_wal(memtable.id()))?);
+            }
+        }
+        WalBatchIterator::create(files, ts, end_ts)
+    }
+
+    pub(super) fn sync_dir(&self) -> Result<()> {
+        self.options.fs.sync_dir(&self.path)
+    }
+
     fn freeze_memtable_with_memtable(&self, memtable: Arc<MemTable>) -> Result<()> {
         let mut guard = self.state.write();
+        // No batch is being written with the state lock held, so all batches committed after the latest commit
+        // ts go to the new memtable.
+        memtable.put_wal_start_ts(self.mvcc().latest_commit_ts())?;
         // Swap the current memtable with a new one.
         let mut snapshot = guard.as_ref().clone();
         let old_memtable = std::mem::replace(&mut snapshot.memtable, memtable);
@@ -676,79 +1658,120 @@ impl LsmStorageInner {
 
     /// Force freeze the current memtable to an immutable memtable
     pub fn force_freeze_memtable(&self, state_lock_observer: &MutexGuard<'_, ()>) -> Result<()> {
+        self.check_read_only()?;
         let memtable_id = self.next_sst_id();
         let memtable = if self.options.enable_wal {
             Arc::new(MemTable::create_with_wal(
                 memtable_id,
+                &*self.options.fs,
                 self.path_of_wal(memtable_id),
             )?)
         } else {
             Arc::new(MemTable::create(memtable_id))
         };
 
-        self.freeze_memtable_with_memtable(memtable)?;
-
-        self.manifest().add_record(
-            state_lock_observer,
-            ManifestRecord::NewMemtable(memtable_id),
-        )?;
+        // The WAL and the manifest record must be durable before writes go to the new memtable. Otherwise a
+        // crash could leave acknowledged writes in a WAL that recovery treats as an orphan.
         self.sync_dir()?;
+        let record = ManifestRecord::NewMemtable(memtable_id);
+        self.manifest()
+            .add_record(state_lock_observer, record.clone())?;
+        self.freeze_memtable_with_memtable(memtable)?;
+        // replicas switch to the new memtable on the record, so it is not sent before batches written to the
+        // old memtable, and the manifest snapshot of a rotation must include the new memtable
+        self.replicate_manifest_record(&record);
+        self.maybe_rotate_manifest(state_lock_observer)?;
 
         Ok(())
     }
 
     /// Force flush the earliest-created immutable memtable to disk
     pub fn force_flush_next_imm_memtable(&self) -> Result<()> {
+        self.flush_earliest_imm_memtables(1)
+    }
+
+    /// Force flush all immutable memtables to disk in one flush, merging them into as few L0 SSTs as
+    /// `target_sst_size` allows
+    pub fn force_flush_imm_memtables(&self) -> Result<()> {
+        self.flush_earliest_imm_memtables(usize::MAX)
+    }
+
+    /// Merge up to `max_memtables` earliest-created immutable memtables and write them to disk, cutting
+    /// the output into SSTs of `target_sst_size`.
+    fn flush_earliest_imm_memtables(&self, max_memtables: usize) -> Result<()> {
+        self.check_read_only()?;
         let state_lock = self.state_lock.lock();
 
-        let flush_memtable;
+        let flush_memtables;
 
         {
             let guard = self.state.read();
-            flush_memtable = guard
-                .imm_memtables
-                .last()
-                .expect("no imm memtables!")
-                .clone();
+            let num_memtables = guard.imm_memtables.len().min(max_memtables);
+            if num_memtables == 0 {
+                // flushed by another thread
+                return Ok(());
+            }
+            // from latest to earliest
+            flush_memtables =
+                guard.imm_memtables[guard.imm_memtables.len() - num_memtables..].to_vec();
         }
 
-        let mut builder = SsTableBuilder::new(self.options.block_size);
-        flush_memtable.flush(&mut builder)?;
-        let sst_id = flush_memtable.id();
-        let sst = Arc::new(builder.build(
-            sst_id,
-            Some(self.block_cache.clone()),
-            self.path_of_sst(sst_id),
-        )?);
+        let mut iters = Vec::with_capacity(flush_memtables.len());
+        for memtable in &flush_memtables {
+            iters.push(Box::new(memtable.scan(Bound::Unbounded, Bound::Unbounded)));
+        }
+        let ssts = self.compact_generate_sst_from_iter(MergeIterator::create(iters), false, &[])?;
+        let memtable_ids = flush_memtables.iter().map(|x| x.id()).collect::<Vec<_>>();
+        let output = ssts.iter().map(|x| x.sst_id()).collect::<Vec<_>>();
 
-        // Add the flushed L0 table to the list.
+        // Add the flushed L0 tables to the list.
+        let edit;
         {
             let mut guard = self.state.write();
             let mut snapshot = guard.as_ref().clone();
-            // Remove the memtable from the immutable memtables.
-            let mem = snapshot.imm_memtables.pop().unwrap();
-            assert_eq!(mem.id(), sst_id);
-            // Add L0 table
+            // Remove the memtables from the immutable memtables, earliest first.
+            for memtable_id in memtable_ids.iter().rev() {
+                let mem = snapshot.imm_memtables.pop();
+                ensure!(
+                    mem.as_ref().map(|x| x.id()) == Some(*memtable_id),
+                    "memtable {} is not the earliest immutable memtable",
+                    memtable_id
+                );
+            }
+            // Add L0 tables
             if self.compaction_controller.flush_to_l0() {
                 // In leveled compaction or no compaction, simply flush to L0
-                snapshot.l0_sstables.insert(0, sst_id);
-            } else {
+                snapshot.l0_sstables.splice(0..0, output.iter().copied());
+            } else if let Some(&tier_id) = output.first() {
                 // In tiered compaction, create a new tier
-                snapshot.levels.insert(0, (sst_id, vec![sst_id]));
+                snapshot.levels.insert(0, (tier_id, output.clone()));
+            }
+            for sst in ssts {
+                println!(
+                    "flushed {}.sst with size={}",
+                    sst.sst_id(),
+                    sst.table_size()
+                );
+                snapshot.sstables.insert(sst.sst_id(), sst);
             }
-            println!("flushed {}.sst with size={}", sst_id, sst.table_size());
-            snapshot.sstables.insert(sst_id, sst);
+            edit = VersionEdit::diff(&guard, &snapshot);
             // Update the snapshot.
             *guard = Arc::new(snapshot);
         }
 
+        self.sync_dir()?;
+        self.add_manifest_record(
+            &state_lock,
+            ManifestRecord::Flush(memtable_ids.clone(), edit),
+        )?;
+
         if self.options.enable_wal {
-            std::fs::remove_file(self.path_of_wal(sst_id))?;
+            for memtable_id in memtable_ids {
+                self.retire_wal(memtable_id)?;
+            }
+            self.prune_archived_wals()?;
         }
 
-        self.manifest()
-            .add_record(&state_lock, ManifestRecord::Flush(sst_id))?;
-
         self.sync_dir()?;
 
         Ok(())
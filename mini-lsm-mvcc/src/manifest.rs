@@ -1,64 +1,250 @@
-use std::fs::{File, OpenOptions};
-use std::io::{Read, Write};
-use std::path::Path;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
-use anyhow::{bail, Context, Result};
+use anyhow::{bail, ensure, Context, Result};
 use bytes::{Buf, BufMut};
 use parking_lot::{Mutex, MutexGuard};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 
 use crate::compact::CompactionTask;
+use crate::env::{FileSystem, WritableFile};
+use crate::lsm_storage::LsmStorageState;
+
+/// The file that names the manifest file in use.
+const CURRENT_FILE: &str = "CURRENT";
+
+/// The manifest file of directories created before the manifest is rotated.
+const LEGACY_MANIFEST_FILE: &str = "MANIFEST";
+
+/// The version of the binary record encoding, which is the first byte of each record. Records written as JSON
+/// by older versions start with `{`.
+const RECORD_VERSION: u8 = 1;
+
+const TAG_LEGACY_FLUSH: u8 = 1;
+const TAG_NEW_MEMTABLE: u8 = 2;
+const TAG_COMPACTION: u8 = 3;
+const TAG_SNAPSHOT: u8 = 4;
+const TAG_FLUSH: u8 = 5;
 
 pub struct Manifest {
-    file: Arc<Mutex<File>>,
+    fs: Arc<dyn FileSystem>,
+    dir: PathBuf,
+    file: Arc<Mutex<ManifestFile>>,
 }
 
-#[derive(Serialize, Deserialize)]
+struct ManifestFile {
+    file: Box<dyn WritableFile>,
+    path: PathBuf,
+    id: usize,
+    size: u64,
+    /// Whether the file has records written as JSON
+    has_json_records: bool,
+}
+
+#[derive(Clone)]
 pub enum ManifestRecord {
-    Flush(usize),
+    /// Memtables flushed together (latest first) and the SSTs they are flushed to
+    Flush(Vec<usize>, VersionEdit),
+    NewMemtable(usize),
+    /// The SSTs moved by a compaction, or by migrating the levels to other compaction options
+    Compaction(VersionEdit),
+    /// The full state when the manifest is rotated, always the first record of the manifest file
+    Snapshot(ManifestSnapshot),
+    /// A flush read from an older manifest, with the output SSTs added to L0 or as a new tier depending on
+    /// the compaction controller. It is never written.
+    LegacyFlush(Vec<usize>, Vec<usize>),
+    /// A compaction read from a JSON manifest, which is replayed with the compaction controller. It is
+    /// never written.
+    LegacyCompaction(CompactionTask, Vec<usize>),
+}
+
+/// The JSON encoding of manifest records used before the binary encoding.
+#[derive(Deserialize)]
+enum JsonManifestRecord {
+    #[serde(deserialize_with = "deserialize_flush")]
+    Flush(Vec<usize>, Vec<usize>),
     NewMemtable(usize),
     Compaction(CompactionTask, Vec<usize>),
+    Snapshot(ManifestSnapshot),
+}
+
+/// Decode a flush record, including the `Flush(usize)` records written before memtables were flushed together,
+/// where memtable N is flushed to SST N.
+fn deserialize_flush<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<(Vec<usize>, Vec<usize>), D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Flush {
+        Legacy(usize),
+        Batch(Vec<usize>, Vec<usize>),
+    }
+    Ok(match Flush::deserialize(deserializer)? {
+        Flush::Legacy(id) => (vec![id], vec![id]),
+        Flush::Batch(memtable_ids, sst_ids) => (memtable_ids, sst_ids),
+    })
+}
+
+impl From<JsonManifestRecord> for ManifestRecord {
+    fn from(record: JsonManifestRecord) -> Self {
+        match record {
+            JsonManifestRecord::Flush(memtable_ids, output) => {
+                Self::LegacyFlush(memtable_ids, output)
+            }
+            JsonManifestRecord::NewMemtable(id) => Self::NewMemtable(id),
+            JsonManifestRecord::Compaction(task, output) => Self::LegacyCompaction(task, output),
+            JsonManifestRecord::Snapshot(snapshot) => Self::Snapshot(snapshot),
+        }
+    }
+}
+
+/// The SSTs removed from and added to L0 (`None`) or a level (or tier) by its id.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LevelEdit {
+    pub level: Option<usize>,
+    pub removed: Vec<usize>,
+    /// The added SSTs with their positions in the level after the edit, in ascending order of positions
+    pub added: Vec<(usize, usize)>,
+}
+
+/// A change of L0 and the levels, which is applied as is on recovery.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VersionEdit {
+    pub levels: Vec<LevelEdit>,
+    /// The ids of all levels after the edit, if levels are added, removed or reordered (by tiered compaction)
+    pub level_ids: Option<Vec<usize>>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ManifestSnapshot {
+    pub l0_sstables: Vec<usize>,
+    pub levels: Vec<(usize, Vec<usize>)>,
+    /// The memtables not flushed yet, from earliest to latest
+    pub memtables: Vec<usize>,
+    pub next_sst_id: usize,
 }
 
 impl Manifest {
-    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+    fn path_of_manifest(dir: &Path, id: usize) -> PathBuf {
+        dir.join(format!("MANIFEST-{:06}", id))
+    }
+
+    /// Whether the directory has a manifest to recover from.
+    pub fn exists(fs: &dyn FileSystem, dir: impl AsRef<Path>) -> bool {
+        let dir = dir.as_ref();
+        fs.exists(&dir.join(CURRENT_FILE)) || fs.exists(&dir.join(LEGACY_MANIFEST_FILE))
+    }
+
+    fn create_file(fs: &dyn FileSystem, path: &Path) -> Result<Box<dyn WritableFile>> {
+        fs.create_new(path).context("failed to create manifest")
+    }
+
+    /// Point the CURRENT file to the manifest file. The switch is atomic as the new content is renamed over
+    /// the old file.
+    fn set_current(fs: &dyn FileSystem, dir: &Path, id: usize) -> Result<()> {
+        let tmp_path = dir.join(format!("{}.tmp", CURRENT_FILE));
+        fs.write(&tmp_path, format!("MANIFEST-{:06}\n", id).as_bytes())?;
+        fs.rename(&tmp_path, &dir.join(CURRENT_FILE))?;
+        fs.sync_dir(dir)?;
+        Ok(())
+    }
+
+    pub fn create(fs: Arc<dyn FileSystem>, dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref();
+        let path = Self::path_of_manifest(dir, 1);
+        let mut file = Self::create_file(&*fs, &path)?;
+        file.sync()?;
+        Self::set_current(&*fs, dir, 1)?;
         Ok(Self {
-            file: Arc::new(Mutex::new(
-                OpenOptions::new()
-                    .read(true)
-                    .create_new(true)
-                    .write(true)
-                    .open(path)
-                    .context("failed to create manifest")?,
-            )),
+            fs,
+            dir: dir.to_path_buf(),
+            file: Arc::new(Mutex::new(ManifestFile {
+                file,
+                path,
+                id: 1,
+                size: 0,
+                has_json_records: false,
+            })),
         })
     }
 
-    pub fn recover(path: impl AsRef<Path>) -> Result<(Self, Vec<ManifestRecord>)> {
-        let mut file = OpenOptions::new()
-            .read(true)
-            .append(true)
-            .open(path)
-            .context("failed to recover manifest")?;
-        let mut buf = Vec::new();
-        file.read_to_end(&mut buf)?;
-        let mut buf_ptr = buf.as_slice();
+    /// The path and id of the manifest file pointed to by the CURRENT file.
+    fn current_manifest(fs: &dyn FileSystem, dir: &Path) -> Result<(PathBuf, usize)> {
+        let current_path = dir.join(CURRENT_FILE);
+        if !fs.exists(&current_path) {
+            return Ok((dir.join(LEGACY_MANIFEST_FILE), 0));
+        }
+        let current = String::from_utf8_lossy(&fs.read(&current_path)?).into_owned();
+        let name = current.trim();
+        let Some(id) = name
+            .strip_prefix("MANIFEST-")
+            .and_then(|id| id.parse().ok())
+        else {
+            bail!("invalid CURRENT file: {:?}", current);
+        };
+        Ok((dir.join(name), id))
+    }
+
+    /// Decode the records of a manifest file, and return whether some of them are in JSON.
+    fn decode_records(mut buf_ptr: &[u8]) -> Result<(Vec<ManifestRecord>, bool)> {
         let mut records = Vec::new();
+        let mut has_json_records = false;
         while buf_ptr.has_remaining() {
+            ensure!(
+                buf_ptr.remaining() >= 8,
+                "incomplete manifest record header"
+            );
             let len = buf_ptr.get_u64();
+            ensure!(
+                buf_ptr.remaining() as u64 >= len.saturating_add(4),
+                "incomplete manifest record"
+            );
             let slice = &buf_ptr[..len as usize];
-            let json = serde_json::from_slice::<ManifestRecord>(slice)?;
             buf_ptr.advance(len as usize);
             let checksum = buf_ptr.get_u32();
             if checksum != crc32fast::hash(slice) {
                 bail!("checksum mismatched!");
             }
-            records.push(json);
+            if slice.first() == Some(&b'{') {
+                has_json_records = true;
+                records.push(serde_json::from_slice::<JsonManifestRecord>(slice)?.into());
+            } else {
+                records.push(ManifestRecord::decode(slice)?);
+            }
         }
+        Ok((records, has_json_records))
+    }
+
+    /// Read the records of the manifest without opening it for writing.
+    pub fn read_records(fs: &dyn FileSystem, dir: impl AsRef<Path>) -> Result<Vec<ManifestRecord>> {
+        let (path, _) = Self::current_manifest(fs, dir.as_ref())?;
+        let buf = fs.read(&path).context("failed to read manifest")?;
+        Ok(Self::decode_records(&buf)?.0)
+    }
+
+    pub fn recover(
+        fs: Arc<dyn FileSystem>,
+        dir: impl AsRef<Path>,
+    ) -> Result<(Self, Vec<ManifestRecord>)> {
+        let dir = dir.as_ref();
+        let (path, id) = Self::current_manifest(&*fs, dir)?;
+        let buf = fs.read(&path).context("failed to recover manifest")?;
+        let file = fs
+            .open_append(&path)
+            .context("failed to recover manifest")?;
+        let (records, has_json_records) = Self::decode_records(&buf)?;
         Ok((
             Self {
-                file: Arc::new(Mutex::new(file)),
+                fs,
+                dir: dir.to_path_buf(),
+                file: Arc::new(Mutex::new(ManifestFile {
+                    file,
+                    path,
+                    id,
+                    size: buf.len() as u64,
+                    has_json_records,
+                })),
             },
             records,
         ))
@@ -74,12 +260,366 @@ impl Manifest {
 
     pub fn add_record_when_init(&self, record: ManifestRecord) -> Result<()> {
         let mut file = self.file.lock();
-        let mut buf = serde_json::to_vec(&record)?;
+        Self::write_record(&mut file, &record)
+    }
+
+    fn write_record(file: &mut ManifestFile, record: &ManifestRecord) -> Result<()> {
+        let mut buf = Vec::new();
+        record.encode(&mut buf)?;
         let hash = crc32fast::hash(&buf);
-        file.write_all(&(buf.len() as u64).to_be_bytes())?;
+        file.file.append(&(buf.len() as u64).to_be_bytes())?;
         buf.put_u32(hash);
-        file.write_all(&buf)?;
-        file.sync_all()?;
+        file.file.append(&buf)?;
+        file.file.sync()?;
+        file.size += buf.len() as u64 + 8;
         Ok(())
     }
+
+    /// The path of the manifest file in use.
+    pub fn path(&self) -> PathBuf {
+        self.file.lock().path.clone()
+    }
+
+    /// The size in bytes of the manifest file in use.
+    pub fn size(&self) -> u64 {
+        self.file.lock().size
+    }
+
+    /// Whether the manifest file in use has records written as JSON, which are rewritten by a rotation.
+    pub fn has_json_records(&self) -> bool {
+        self.file.lock().has_json_records
+    }
+
+    /// Replace the manifest file with a new one that starts with the snapshot, so that recovery does not
+    /// replay the records before it.
+    pub fn rotate(
+        &self,
+        _state_lock_observer: &MutexGuard<()>,
+        snapshot: ManifestSnapshot,
+    ) -> Result<()> {
+        self.rotate_when_init(snapshot)
+    }
+
+    pub fn rotate_when_init(&self, snapshot: ManifestSnapshot) -> Result<()> {
+        let mut file = self.file.lock();
+        let id = file.id + 1;
+        let path = Self::path_of_manifest(&self.dir, id);
+        if self.fs.exists(&path) {
+            // left by a rotation that crashed before switching CURRENT
+            self.fs.remove_file(&path)?;
+        }
+        let mut new_file = ManifestFile {
+            file: Self::create_file(&*self.fs, &path)?,
+            path,
+            id,
+            size: 0,
+            has_json_records: false,
+        };
+        Self::write_record(&mut new_file, &ManifestRecord::Snapshot(snapshot))?;
+        Self::set_current(&*self.fs, &self.dir, id)?;
+        let old_file = std::mem::replace(&mut *file, new_file);
+        self.fs.remove_file(&old_file.path)?;
+        Ok(())
+    }
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        buf.put_u8(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.put_u8(value as u8);
+}
+
+fn get_varint(buf: &mut &[u8]) -> Result<usize> {
+    let mut value = 0;
+    for shift in (0..usize::BITS).step_by(7) {
+        ensure!(buf.has_remaining(), "manifest record is truncated");
+        let byte = buf.get_u8();
+        value |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    bail!("invalid varint in manifest record")
+}
+
+fn put_ids(buf: &mut Vec<u8>, ids: &[usize]) {
+    put_varint(buf, ids.len());
+    for id in ids {
+        put_varint(buf, *id);
+    }
+}
+
+fn get_ids(buf: &mut &[u8]) -> Result<Vec<usize>> {
+    let len = get_varint(buf)?;
+    ensure!(len <= buf.len(), "manifest record is truncated");
+    (0..len).map(|_| get_varint(buf)).collect()
+}
+
+fn put_levels(buf: &mut Vec<u8>, levels: &[(usize, Vec<usize>)]) {
+    put_varint(buf, levels.len());
+    for (level, ssts) in levels {
+        put_varint(buf, *level);
+        put_ids(buf, ssts);
+    }
+}
+
+fn get_levels(buf: &mut &[u8]) -> Result<Vec<(usize, Vec<usize>)>> {
+    let len = get_varint(buf)?;
+    ensure!(len <= buf.len(), "manifest record is truncated");
+    (0..len)
+        .map(|_| Ok((get_varint(buf)?, get_ids(buf)?)))
+        .collect()
+}
+
+fn put_edit(buf: &mut Vec<u8>, edit: &VersionEdit) {
+    put_varint(buf, edit.levels.len());
+    for level_edit in &edit.levels {
+        // level ids are stored plus one, so that 0 is L0
+        put_varint(buf, level_edit.level.map_or(0, |level| level + 1));
+        put_ids(buf, &level_edit.removed);
+        put_varint(buf, level_edit.added.len());
+        for (position, id) in &level_edit.added {
+            put_varint(buf, *position);
+            put_varint(buf, *id);
+        }
+    }
+    match &edit.level_ids {
+        Some(level_ids) => {
+            buf.put_u8(1);
+            put_ids(buf, level_ids);
+        }
+        None => buf.put_u8(0),
+    }
+}
+
+fn get_edit(buf: &mut &[u8]) -> Result<VersionEdit> {
+    let len = get_varint(buf)?;
+    ensure!(len <= buf.len(), "manifest record is truncated");
+    let mut levels = Vec::with_capacity(len);
+    for _ in 0..len {
+        let level = get_varint(buf)?.checked_sub(1);
+        let removed = get_ids(buf)?;
+        let added_len = get_varint(buf)?;
+        ensure!(added_len <= buf.len(), "manifest record is truncated");
+        let added = (0..added_len)
+            .map(|_| Ok((get_varint(buf)?, get_varint(buf)?)))
+            .collect::<Result<_>>()?;
+        levels.push(LevelEdit {
+            level,
+            removed,
+            added,
+        });
+    }
+    ensure!(buf.has_remaining(), "manifest record is truncated");
+    let level_ids = match buf.get_u8() {
+        0 => None,
+        _ => Some(get_ids(buf)?),
+    };
+    Ok(VersionEdit { levels, level_ids })
+}
+
+impl ManifestRecord {
+    /// Encodes the record as `| version (u8) | tag (u8) | fields |`, where the fields are varints and lists of
+    /// varints prefixed with their lengths.
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) -> Result<()> {
+        buf.put_u8(RECORD_VERSION);
+        match self {
+            ManifestRecord::Flush(memtable_ids, edit) => {
+                buf.put_u8(TAG_FLUSH);
+                put_ids(buf, memtable_ids);
+                put_edit(buf, edit);
+            }
+            ManifestRecord::NewMemtable(id) => {
+                buf.put_u8(TAG_NEW_MEMTABLE);
+                put_varint(buf, *id);
+            }
+            ManifestRecord::Compaction(edit) => {
+                buf.put_u8(TAG_COMPACTION);
+                put_edit(buf, edit);
+            }
+            ManifestRecord::Snapshot(snapshot) => {
+                buf.put_u8(TAG_SNAPSHOT);
+                put_ids(buf, &snapshot.l0_sstables);
+                put_levels(buf, &snapshot.levels);
+                put_ids(buf, &snapshot.memtables);
+                put_varint(buf, snapshot.next_sst_id);
+            }
+            ManifestRecord::LegacyFlush(..) | ManifestRecord::LegacyCompaction(..) => {
+                bail!("legacy records cannot be written")
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn decode(mut buf: &[u8]) -> Result<Self> {
+        let buf = &mut buf;
+        ensure!(buf.remaining() >= 2, "manifest record is truncated");
+        let version = buf.get_u8();
+        ensure!(
+            version == RECORD_VERSION,
+            "unsupported manifest record version {}",
+            version
+        );
+        let record = match buf.get_u8() {
+            TAG_LEGACY_FLUSH => ManifestRecord::LegacyFlush(get_ids(buf)?, get_ids(buf)?),
+            TAG_FLUSH => ManifestRecord::Flush(get_ids(buf)?, get_edit(buf)?),
+            TAG_NEW_MEMTABLE => ManifestRecord::NewMemtable(get_varint(buf)?),
+            TAG_COMPACTION => ManifestRecord::Compaction(get_edit(buf)?),
+            TAG_SNAPSHOT => ManifestRecord::Snapshot(ManifestSnapshot {
+                l0_sstables: get_ids(buf)?,
+                levels: get_levels(buf)?,
+                memtables: get_ids(buf)?,
+                next_sst_id: get_varint(buf)?,
+            }),
+            tag => bail!("unknown manifest record type {}", tag),
+        };
+        ensure!(
+            !buf.has_remaining(),
+            "unexpected data after manifest record"
+        );
+        Ok(record)
+    }
+}
+
+fn diff_level(level: Option<usize>, old: &[usize], new: &[usize]) -> Option<LevelEdit> {
+    if old == new {
+        return None;
+    }
+    let old_ssts = old.iter().collect::<HashSet<_>>();
+    let new_ssts = new.iter().collect::<HashSet<_>>();
+    Some(LevelEdit {
+        level,
+        removed: old
+            .iter()
+            .filter(|id| !new_ssts.contains(id))
+            .copied()
+            .collect(),
+        added: new
+            .iter()
+            .enumerate()
+            .filter(|(_, id)| !old_ssts.contains(id))
+            .map(|(position, id)| (position, *id))
+            .collect(),
+    })
+}
+
+impl VersionEdit {
+    /// The edit that changes the SSTs of `old` into those of `new`. The SSTs kept in a level must keep their
+    /// order.
+    pub fn diff(old: &LsmStorageState, new: &LsmStorageState) -> Self {
+        let mut levels = Vec::new();
+        levels.extend(diff_level(None, &old.l0_sstables, &new.l0_sstables));
+        for (level, ssts) in &new.levels {
+            let old_ssts = old
+                .levels
+                .iter()
+                .find(|(old_level, _)| old_level == level)
+                .map_or(&[][..], |(_, ssts)| ssts);
+            levels.extend(diff_level(Some(*level), old_ssts, ssts));
+        }
+        for (level, ssts) in &old.levels {
+            if !new.levels.iter().any(|(new_level, _)| new_level == level) {
+                levels.extend(diff_level(Some(*level), ssts, &[]));
+            }
+        }
+        let level_ids = |state: &LsmStorageState| {
+            state
+                .levels
+                .iter()
+                .map(|(level, _)| *level)
+                .collect::<Vec<_>>()
+        };
+        let new_level_ids = level_ids(new);
+        Self {
+            levels,
+            level_ids: (level_ids(old) != new_level_ids).then_some(new_level_ids),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.levels.is_empty() && self.level_ids.is_none()
+    }
+
+    pub fn apply(&self, state: &mut LsmStorageState) -> Result<()> {
+        for level_edit in &self.levels {
+            let ssts = match level_edit.level {
+                None => &mut state.l0_sstables,
+                Some(level) => {
+                    let index = match state.levels.iter().position(|(id, _)| *id == level) {
+                        Some(index) => index,
+                        None => {
+                            state.levels.push((level, Vec::new()));
+                            state.levels.len() - 1
+                        }
+                    };
+                    &mut state.levels[index].1
+                }
+            };
+            let removed = level_edit.removed.iter().collect::<HashSet<_>>();
+            let len = ssts.len();
+            ssts.retain(|id| !removed.contains(id));
+            ensure!(
+                ssts.len() + removed.len() == len,
+                "removed SSTs not found in level {:?}",
+                level_edit.level
+            );
+            for (position, id) in &level_edit.added {
+                ensure!(*position <= ssts.len(), "invalid position of {}.sst", id);
+                ssts.insert(*position, *id);
+            }
+        }
+        if let Some(level_ids) = &self.level_ids {
+            let mut levels = Vec::with_capacity(level_ids.len());
+            for level in level_ids {
+                let index = state.levels.iter().position(|(id, _)| id == level);
+                levels.push(match index {
+                    Some(index) => state.levels.swap_remove(index),
+                    None => (*level, Vec::new()),
+                });
+            }
+            ensure!(
+                state.levels.iter().all(|(_, ssts)| ssts.is_empty()),
+                "levels removed by the edit are not empty"
+            );
+            state.levels = levels;
+        }
+        Ok(())
+    }
+
+    fn ssts(&self, added: bool) -> Vec<usize> {
+        self.levels
+            .iter()
+            .flat_map(|level_edit| {
+                if added {
+                    level_edit
+                        .added
+                        .iter()
+                        .map(|(_, id)| *id)
+                        .collect::<Vec<_>>()
+                } else {
+                    level_edit.removed.clone()
+                }
+            })
+            .collect()
+    }
+
+    /// The SSTs added by the edit, excluding those moved from another level.
+    pub fn added_ssts(&self) -> Vec<usize> {
+        let removed = self.ssts(false).into_iter().collect::<HashSet<_>>();
+        self.ssts(true)
+            .into_iter()
+            .filter(|id| !removed.contains(id))
+            .collect()
+    }
+
+    /// The SSTs removed by the edit, excluding those moved to another level.
+    pub fn removed_ssts(&self) -> Vec<usize> {
+        let added = self.ssts(true).into_iter().collect::<HashSet<_>>();
+        self.ssts(false)
+            .into_iter()
+            .filter(|id| !added.contains(id))
+            .collect()
+    }
 }
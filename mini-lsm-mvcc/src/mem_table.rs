@@ -9,10 +9,10 @@ use crossbeam_skiplist::map::Entry;
 use crossbeam_skiplist::SkipMap;
 use ouroboros::self_referencing;
 
+use crate::env::FileSystem;
 use crate::iterators::StorageIterator;
 use crate::key::{KeyBytes, KeySlice, TS_DEFAULT};
-use crate::table::SsTableBuilder;
-use crate::wal::Wal;
+use crate::wal::{Wal, WalRecoveryMode};
 
 /// A basic mem-table based on crossbeam-skiplist.
 ///
@@ -70,24 +70,54 @@ impl MemTable {
     }
 
     /// Create a new mem-table with WAL
-    pub fn create_with_wal(id: usize, path: impl AsRef<Path>) -> Result<Self> {
+    pub fn create_with_wal(id: usize, fs: &dyn FileSystem, path: impl AsRef<Path>) -> Result<Self> {
         Ok(Self {
             id,
             map: Arc::new(SkipMap::new()),
-            wal: Some(Wal::create(path.as_ref())?),
+            wal: Some(Wal::create(fs, path.as_ref())?),
             approximate_size: Arc::new(AtomicUsize::new(0)),
         })
     }
 
-    /// Create a memtable from WAL
-    pub fn recover_from_wal(id: usize, path: impl AsRef<Path>) -> Result<Self> {
+    /// Create a memtable from WAL, and return whether all records in the WAL are recovered
+    pub fn recover_from_wal(
+        id: usize,
+        fs: &dyn FileSystem,
+        path: impl AsRef<Path>,
+        mode: WalRecoveryMode,
+    ) -> Result<(Self, bool)> {
         let map = Arc::new(SkipMap::new());
-        Ok(Self {
-            id,
-            wal: Some(Wal::recover(path.as_ref(), &map)?),
-            map,
-            approximate_size: Arc::new(AtomicUsize::new(0)),
-        })
+        let (wal, fully_recovered) = Wal::recover(fs, path.as_ref(), &map, mode)?;
+        Ok((
+            Self {
+                id,
+                wal: Some(wal),
+                map,
+                approximate_size: Arc::new(AtomicUsize::new(0)),
+            },
+            fully_recovered,
+        ))
+    }
+
+    /// Create a memtable from WAL without opening the WAL for writing, and return whether all records in the
+    /// WAL are read. The memtable has no WAL, so it is only for reading.
+    pub fn read_from_wal(
+        id: usize,
+        fs: &dyn FileSystem,
+        path: impl AsRef<Path>,
+        mode: WalRecoveryMode,
+    ) -> Result<(Self, bool)> {
+        let map = Arc::new(SkipMap::new());
+        let fully_recovered = Wal::read(fs, path.as_ref(), &map, mode)?;
+        Ok((
+            Self {
+                id,
+                wal: None,
+                map,
+                approximate_size: Arc::new(AtomicUsize::new(0)),
+            },
+            fully_recovered,
+        ))
     }
 
     /// Get a value by key. Should not be used in week 3.
@@ -123,15 +153,42 @@ impl MemTable {
     /// In week 1, day 1, simply put the key-value pair into the skipmap.
     /// In week 2, day 6, also flush the data to WAL.
     pub fn put(&self, key: KeySlice, value: &[u8]) -> Result<()> {
-        let estimated_size = key.raw_len() + value.len();
-        self.map.insert(
-            key.to_key_vec().into_key_bytes(),
-            Bytes::copy_from_slice(value),
-        );
+        self.put_batch(&[(key, value)])
+    }
+
+    /// Put the key-value pairs of one commit into the mem-table. They are written to the WAL as a single
+    /// record, so that a crash never recovers part of the batch.
+    pub fn put_batch(&self, data: &[(KeySlice, &[u8])]) -> Result<()> {
+        self.put_batches(&[data])
+    }
+
+    /// Put the batches of a commit group into the mem-table with a single WAL append.
+    pub fn put_batches(&self, batches: &[&[(KeySlice, &[u8])]]) -> Result<()> {
+        if let Some(ref wal) = self.wal {
+            wal.put_batches(batches)?;
+        }
+        let mut estimated_size = 0;
+        for (key, value) in batches.iter().flat_map(|data| data.iter()) {
+            estimated_size += key.raw_len() + value.len();
+            self.map.insert(
+                key.to_key_vec().into_key_bytes(),
+                Bytes::copy_from_slice(value),
+            );
+        }
         self.approximate_size
             .fetch_add(estimated_size, std::sync::atomic::Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// The length of the WAL with all batches put so far, if the memtable has a WAL.
+    pub fn wal_len(&self) -> Result<Option<u64>> {
+        self.wal.as_ref().map(|wal| wal.flushed_len()).transpose()
+    }
+
+    /// Record in the WAL that all batches committed after `ts` are in this memtable or a later one.
+    pub fn put_wal_start_ts(&self, ts: u64) -> Result<()> {
         if let Some(ref wal) = self.wal {
-            wal.put(key, value)?;
+            wal.put_start_ts(ts)?;
         }
         Ok(())
     }
@@ -156,14 +213,6 @@ impl MemTable {
         iter
     }
 
-    /// Flush the mem-table to SSTable. Implement in week 1 day 6.
-    pub fn flush(&self, builder: &mut SsTableBuilder) -> Result<()> {
-        for entry in self.map.iter() {
-            builder.add(entry.key().as_key_slice(), &entry.value()[..]);
-        }
-        Ok(())
-    }
-
     pub fn id(&self) -> usize {
         self.id
     }
@@ -218,6 +267,10 @@ impl StorageIterator for MemTableIterator {
         &self.borrow_item().1[..]
     }
 
+    fn value_bytes(&self) -> Bytes {
+        self.borrow_item().1.clone()
+    }
+
     fn key(&self) -> KeySlice {
         self.borrow_item().0.as_key_slice()
     }
@@ -16,7 +16,7 @@ use parking_lot::Mutex;
 use crate::{
     iterators::{two_merge_iterator::TwoMergeIterator, StorageIterator},
     lsm_iterator::{FusedIterator, LsmIterator},
-    lsm_storage::{LsmStorageInner, WriteBatchRecord},
+    lsm_storage::{LsmStorageInner, WriteBatchRecord, WriteOptions},
     mem_table::map_bound,
     mvcc::CommittedTxnData,
 };
@@ -99,6 +99,10 @@ impl Transaction {
     }
 
     pub fn commit(&self) -> Result<()> {
+        self.commit_with_options(&WriteOptions::default())
+    }
+
+    pub fn commit_with_options(&self, options: &WriteOptions) -> Result<()> {
         self.committed
             .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
             .expect("cannot operate on committed txn!");
@@ -136,8 +140,9 @@ impl Transaction {
                 }
             })
             .collect::<Vec<_>>();
-        let ts = self.inner.write_batch_inner(&batch)?;
-        if serializability_check {
+        let ts = self.inner.write_batch_inner(&batch, options)?;
+        // a txn without writes takes no commit ts, and cannot conflict with later txns
+        if serializability_check && !batch.is_empty() {
             let mut committed_txns = self.inner.mvcc().committed_txns.lock();
             let mut key_hashes = self.key_hashes.as_ref().unwrap().lock();
             let (write_set, _) = &mut *key_hashes;
@@ -202,6 +207,10 @@ impl StorageIterator for TxnLocalIterator {
         &self.borrow_item().1[..]
     }
 
+    fn value_bytes(&self) -> Bytes {
+        self.borrow_item().1.clone()
+    }
+
     fn key(&self) -> &[u8] {
         &self.borrow_item().0[..]
     }
@@ -258,6 +267,10 @@ impl StorageIterator for TxnIterator {
         self.iter.value()
     }
 
+    fn value_bytes(&self) -> Bytes {
+        self.iter.value_bytes()
+    }
+
     fn key(&self) -> Self::KeyType<'_> {
         self.iter.key()
     }
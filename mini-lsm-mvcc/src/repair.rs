@@ -0,0 +1,173 @@
+//! Rebuilds the manifest of a storage directory from its SSTs and WALs, for when the manifest is corrupted or lost.
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use crate::compact::CompactionOptions;
+use crate::env::FileSystem;
+use crate::lsm_storage::{LsmStorageInner, LsmStorageOptions, LsmStorageState};
+use crate::manifest::{Manifest, ManifestRecord, ManifestSnapshot};
+use crate::mem_table::MemTable;
+use crate::table::{FileObject, SsTable};
+use crate::wal::WalRecoveryMode;
+
+/// What `repair_db` found in the directory.
+#[derive(Debug, Default)]
+pub struct RepairReport {
+    /// The SSTs placed into the rebuilt levels
+    pub sst_ids: Vec<usize>,
+    /// The SSTs that failed verification, moved to the quarantine directory
+    pub corrupted_sst_ids: Vec<usize>,
+    /// The WALs kept as memtables, each truncated at its first corrupted record
+    pub wal_ids: Vec<usize>,
+    /// The WALs that could not be read, moved to the quarantine directory
+    pub corrupted_wal_ids: Vec<usize>,
+}
+
+/// The ids of the files in the directory with the suffix, in ascending order.
+fn file_ids(fs: &dyn FileSystem, path: &Path, suffix: &str) -> Result<Vec<usize>> {
+    let mut ids = Vec::new();
+    for name in fs.list(path)? {
+        if let Some(id) = name.strip_suffix(suffix).and_then(|id| id.parse().ok()) {
+            ids.push(id);
+        }
+    }
+    ids.sort();
+    Ok(ids)
+}
+
+fn quarantine(fs: &dyn FileSystem, path: &Path, file: &Path) -> Result<()> {
+    let quarantine = LsmStorageInner::path_of_quarantine_static(path);
+    fs.create_dir_all(&quarantine)?;
+    fs.rename(file, &quarantine.join(file.file_name().unwrap()))?;
+    Ok(())
+}
+
+/// Open the SST and read all its blocks to verify their checksums.
+pub(crate) fn verify_sst(fs: &dyn FileSystem, id: usize, path: &Path) -> Result<SsTable> {
+    let sst = SsTable::open(id, None, FileObject::open_with_fs(fs, path)?)?;
+    for block_idx in 0..sst.num_of_blocks() {
+        sst.read_block(block_idx)?;
+    }
+    Ok(sst)
+}
+
+/// Group the SSTs into sorted runs of SSTs with disjoint key ranges. An SST is placed in the run above all runs
+/// with an overlapping SST, so that newer versions of a key are always above older versions. Returns the runs
+/// from the bottom, each sorted by key.
+fn build_sorted_runs(mut ssts: Vec<SsTable>) -> Vec<Vec<usize>> {
+    ssts.sort_by_key(|sst| (sst.max_ts(), sst.sst_id()));
+    let mut runs: Vec<Vec<SsTable>> = Vec::new();
+    for sst in ssts {
+        let overlaps = |other: &SsTable| {
+            sst.first_key().key_ref() <= other.last_key().key_ref()
+                && other.first_key().key_ref() <= sst.last_key().key_ref()
+        };
+        let run_idx = runs
+            .iter()
+            .rposition(|run| run.iter().any(overlaps))
+            .map_or(0, |idx| idx + 1);
+        if run_idx == runs.len() {
+            runs.push(Vec::new());
+        }
+        runs[run_idx].push(sst);
+    }
+    runs.into_iter()
+        .map(|mut run| {
+            run.sort_by(|x, y| x.first_key().cmp(y.first_key()));
+            run.iter().map(|sst| sst.sst_id()).collect()
+        })
+        .collect()
+}
+
+/// Place the sorted runs (from the bottom) into L0 and the levels of the compaction options. Each run becomes a
+/// tier in tiered compaction. Otherwise the runs fill the levels from the bottom, and the runs that do not fit
+/// go to L0.
+fn place_runs(
+    runs: Vec<Vec<usize>>,
+    options: &LsmStorageOptions,
+) -> (Vec<usize>, Vec<(usize, Vec<usize>)>) {
+    if let CompactionOptions::Tiered(_) = options.compaction_options {
+        let tiers = runs.into_iter().rev().map(|run| (run[0], run)).collect();
+        return (Vec::new(), tiers);
+    }
+    let mut levels = LsmStorageState::create(options).levels;
+    let mut l0_sstables = Vec::new();
+    for (idx, run) in runs.into_iter().enumerate().rev() {
+        match levels.len().checked_sub(idx + 1) {
+            Some(level_idx) => levels[level_idx].1 = run,
+            None => l0_sstables.extend(run),
+        }
+    }
+    (l0_sstables, levels)
+}
+
+/// Rebuild the manifest from the SSTs and WALs in the directory, which can then be opened with the options.
+///
+/// Every SST is verified and placed into the levels by its key range and max ts, and every WAL is recovered up
+/// to its first corrupted record and kept as a memtable. Corrupted files and the old manifest are moved to the
+/// quarantine directory.
+pub fn repair_db(path: impl AsRef<Path>, options: &LsmStorageOptions) -> Result<RepairReport> {
+    let path = path.as_ref();
+    let fs = &*options.fs;
+    // the directory must not be written to by a storage while it is repaired
+    let _lock_file = LsmStorageInner::lock_dir(fs, path)?;
+    let mut report = RepairReport::default();
+    let mut ssts = Vec::new();
+    let mut max_id = 0;
+
+    for id in file_ids(fs, path, ".sst")? {
+        max_id = max_id.max(id);
+        let sst_path = LsmStorageInner::path_of_sst_static(path, id);
+        match verify_sst(fs, id, &sst_path) {
+            Ok(sst) => {
+                report.sst_ids.push(id);
+                ssts.push(sst);
+            }
+            Err(e) => {
+                println!("{}.sst is corrupted: {:#}", id, e);
+                quarantine(fs, path, &sst_path)?;
+                report.corrupted_sst_ids.push(id);
+            }
+        }
+    }
+
+    for id in file_ids(fs, path, ".wal")? {
+        max_id = max_id.max(id);
+        let wal_path = LsmStorageInner::path_of_wal_static(path, id);
+        match MemTable::recover_from_wal(id, fs, &wal_path, WalRecoveryMode::PointInTime) {
+            Ok(_) => report.wal_ids.push(id),
+            Err(e) => {
+                println!("{}.wal is corrupted: {:#}", id, e);
+                quarantine(fs, path, &wal_path)?;
+                report.corrupted_wal_ids.push(id);
+            }
+        }
+    }
+
+    // keep the old manifest for inspection
+    for name in fs.list(path)? {
+        if name.starts_with("MANIFEST") || name.starts_with("CURRENT") {
+            quarantine(fs, path, &path.join(name))?;
+        }
+    }
+
+    let (l0_sstables, levels) = place_runs(build_sorted_runs(ssts), options);
+    let manifest =
+        Manifest::create(options.fs.clone(), path).context("failed to create manifest")?;
+    manifest.add_record_when_init(ManifestRecord::Snapshot(ManifestSnapshot {
+        l0_sstables,
+        levels,
+        memtables: report.wal_ids.clone(),
+        next_sst_id: max_id + 1,
+    }))?;
+    fs.sync_dir(path)?;
+    println!(
+        "repaired with {} SSTs and {} WALs, {} corrupted files quarantined",
+        report.sst_ids.len(),
+        report.wal_ids.len(),
+        report.corrupted_sst_ids.len() + report.corrupted_wal_ids.len()
+    );
+    Ok(report)
+}
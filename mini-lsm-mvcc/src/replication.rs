@@ -0,0 +1,634 @@
+//! Primary/replica replication over TCP.
+//!
+//! When a replica connects, the primary sends a snapshot of its state: the SST files, the structure of the
+//! levels and the content of the memtables. After that, it streams every write batch (in the WAL record
+//! format) and every manifest record, together with the SST files added by the record. The replica applies
+//! them in order, and serves snapshot reads at the latest replicated commit ts. A replica that falls behind by
+//! more than `REPLICA_QUEUE_CAPACITY` messages is disconnected.
+//!
+//! Each message is a frame with the JSON-encoded `ReplicationMessage`, followed by the frames of the raw
+//! data it refers to. A frame is `| len (u32) | data | checksum of data (u32) |`.
+
+use std::collections::BTreeMap;
+use std::io::{BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use bytes::{Buf, BufMut, Bytes};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::key::{KeyBytes, KeySlice};
+use crate::lsm_storage::{LsmStorageInner, LsmStorageOptions, LsmStorageState, MiniLsm};
+use crate::manifest::ManifestRecord;
+use crate::mem_table::MemTable;
+use crate::mvcc::txn::TxnIterator;
+use crate::table::SsTable;
+use crate::wal::Wal;
+
+#[derive(Serialize, Deserialize)]
+enum ReplicationMessage {
+    /// The state of the primary when the replica connects, followed by a frame for each SST (L0 SSTs
+    /// first, then the levels in order) and a frame of WAL batch records for each memtable.
+    Snapshot {
+        l0_sstables: Vec<usize>,
+        levels: Vec<(usize, Vec<usize>)>,
+        /// From earliest to latest, the last one is the current memtable.
+        memtables: Vec<usize>,
+        commit_ts: u64,
+    },
+    /// Followed by a frame of WAL batch records written to the memtable.
+    Batches { memtable_id: usize },
+    /// Followed by a frame of the encoded manifest record and a frame for each SST added by the record.
+    Manifest,
+}
+
+fn put_frame(buf: &mut Vec<u8>, data: &[u8]) {
+    buf.put_u32(data.len() as u32);
+    buf.put_slice(data);
+    buf.put_u32(crc32fast::hash(data));
+}
+
+fn put_message(buf: &mut Vec<u8>, message: &ReplicationMessage) -> Result<()> {
+    put_frame(buf, &serde_json::to_vec(message)?);
+    Ok(())
+}
+
+fn read_frame(reader: &mut impl Read) -> Result<Vec<u8>> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let mut data = vec![0; (&len[..]).get_u32() as usize];
+    reader.read_exact(&mut data)?;
+    let mut checksum = [0; 4];
+    reader.read_exact(&mut checksum)?;
+    if crc32fast::hash(&data) != (&checksum[..]).get_u32() {
+        bail!("replication frame checksum mismatched");
+    }
+    Ok(data)
+}
+
+fn read_message(reader: &mut impl Read) -> Result<ReplicationMessage> {
+    Ok(serde_json::from_slice(&read_frame(reader)?)?)
+}
+
+/// The SSTs added by a manifest record, which are shipped to the replicas together with the record.
+fn sst_ids_added_by(record: &ManifestRecord) -> Vec<usize> {
+    match record {
+        ManifestRecord::LegacyFlush(_, output) | ManifestRecord::LegacyCompaction(_, output) => {
+            output.clone()
+        }
+        ManifestRecord::Flush(_, edit) | ManifestRecord::Compaction(edit) => edit.added_ssts(),
+        ManifestRecord::NewMemtable(_) | ManifestRecord::Snapshot(_) => Vec::new(),
+    }
+}
+
+fn decode_batches(mut data: &[u8]) -> Result<Vec<Vec<(KeyBytes, Bytes)>>> {
+    let mut batches = Vec::new();
+    while !data.is_empty() {
+        let (record_len, batch) = Wal::decode_batch(data)?;
+        batches.push(batch);
+        data = &data[record_len..];
+    }
+    Ok(batches)
+}
+
+/// The number of messages queued for a replica before it is disconnected for falling behind.
+pub(crate) const REPLICA_QUEUE_CAPACITY: usize = 4096;
+
+fn write_sst_frame(writer: &mut impl Write, buf: &mut Vec<u8>, sst: &SsTable) -> Result<()> {
+    buf.clear();
+    put_frame(buf, &sst.file.read(0, sst.file.size())?);
+    writer.write_all(buf)?;
+    Ok(())
+}
+
+/// A message queued for a replica, followed by the frames of the SSTs it refers to. The SSTs are read when
+/// the message is sent to the replica, outside the locks of the primary, and are held open until then even if
+/// they are compacted in the meantime.
+#[derive(Clone)]
+pub(crate) struct ReplicaUpdate {
+    message: Bytes,
+    ssts: Vec<Arc<SsTable>>,
+}
+
+impl ReplicaUpdate {
+    pub(crate) fn write_to(&self, writer: &mut impl Write) -> Result<()> {
+        writer.write_all(&self.message)?;
+        let mut buf = Vec::new();
+        for sst in &self.ssts {
+            write_sst_frame(writer, &mut buf, sst)?;
+        }
+        Ok(())
+    }
+}
+
+/// The state of the primary when a replica is added. It holds the SSTs and memtables of the state, so that
+/// they can be sent after the locks are released, even if they are compacted or flushed in the meantime.
+pub(crate) struct ReplicaSnapshot {
+    state: Arc<LsmStorageState>,
+    commit_ts: u64,
+}
+
+impl ReplicaSnapshot {
+    /// Write the snapshot message, reading one SST at a time.
+    fn write_to(&self, writer: &mut impl Write) -> Result<()> {
+        let mut memtables = self.state.imm_memtables.clone();
+        memtables.reverse();
+        memtables.push(self.state.memtable.clone());
+
+        let mut buf = Vec::new();
+        put_message(
+            &mut buf,
+            &ReplicationMessage::Snapshot {
+                l0_sstables: self.state.l0_sstables.clone(),
+                levels: self.state.levels.clone(),
+                memtables: memtables.iter().map(|memtable| memtable.id()).collect(),
+                commit_ts: self.commit_ts,
+            },
+        )?;
+        writer.write_all(&buf)?;
+        for id in self
+            .state
+            .l0_sstables
+            .iter()
+            .chain(self.state.levels.iter().flat_map(|(_, files)| files))
+        {
+            write_sst_frame(writer, &mut buf, &self.state.sstables[id])?;
+        }
+        for memtable in &memtables {
+            // group the entries by commit ts to rebuild the batches, leaving out the batches committed after the
+            // snapshot, which are sent to the replica as updates
+            let mut batches = BTreeMap::<u64, Vec<(KeyBytes, Bytes)>>::new();
+            for entry in memtable.map.iter() {
+                if entry.key().ts() <= self.commit_ts {
+                    batches
+                        .entry(entry.key().ts())
+                        .or_default()
+                        .push((entry.key().clone(), entry.value().clone()));
+                }
+            }
+            let mut data = Vec::new();
+            for batch in batches.values() {
+                let batch = batch
+                    .iter()
+                    .map(|(key, value)| (key.as_key_slice(), &value[..]))
+                    .collect::<Vec<_>>();
+                Wal::encode_batch(&batch, &mut data)?;
+            }
+            buf.clear();
+            put_frame(&mut buf, &data);
+            writer.write_all(&buf)?;
+        }
+        Ok(())
+    }
+}
+
+impl LsmStorageInner {
+    fn has_replicas(&self) -> bool {
+        !self.replicas.lock().is_empty()
+    }
+
+    /// Send a message to all replicas. A replica is disconnected if the message cannot be built, as it
+    /// would miss the update.
+    fn send_to_replicas(&self, message: impl FnOnce() -> Result<ReplicaUpdate>) {
+        let mut replicas = self.replicas.lock();
+        if replicas.is_empty() {
+            return;
+        }
+        match message() {
+            Ok(message) => {
+                replicas.retain(|replica| match replica.try_send(message.clone()) {
+                    Ok(()) => true,
+                    Err(crossbeam_channel::TrySendError::Full(_)) => {
+                        eprintln!("disconnecting a replica that falls behind");
+                        false
+                    }
+                    Err(crossbeam_channel::TrySendError::Disconnected(_)) => false,
+                });
+            }
+            Err(e) => {
+                eprintln!("disconnecting replicas: {:#}", e);
+                replicas.clear();
+            }
+        }
+    }
+
+    /// Ship the batches of a commit group written to the memtable. The caller must hold the state read lock
+    /// so that the batches are sent before the memtable is flushed.
+    pub(crate) fn replicate_batches(&self, memtable_id: usize, batches: &[&[(KeySlice, &[u8])]]) {
+        self.send_to_replicas(|| {
+            let mut data = Vec::new();
+            for batch in batches {
+                Wal::encode_batch(batch, &mut data)?;
+            }
+            let mut buf = Vec::new();
+            put_message(&mut buf, &ReplicationMessage::Batches { memtable_id })?;
+            put_frame(&mut buf, &data);
+            Ok(ReplicaUpdate {
+                message: buf.into(),
+                ssts: Vec::new(),
+            })
+        });
+    }
+
+    /// Ship a manifest record and the SSTs it adds, which must be in the current state. The caller must hold
+    /// the state lock. The SSTs are read by the senders of the replicas after the locks are released.
+    pub(crate) fn replicate_manifest_record(&self, record: &ManifestRecord) {
+        if !self.has_replicas() {
+            return;
+        }
+        self.send_to_replicas(|| {
+            let mut buf = Vec::new();
+            put_message(&mut buf, &ReplicationMessage::Manifest)?;
+            let mut encoded_record = Vec::new();
+            record.encode(&mut encoded_record)?;
+            put_frame(&mut buf, &encoded_record);
+            let state = self.state.read();
+            let ssts = sst_ids_added_by(record)
+                .into_iter()
+                .map(|id| {
+                    state
+                        .sstables
+                        .get(&id)
+                        .cloned()
+                        .with_context(|| format!("SST {} is not in the state", id))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            Ok(ReplicaUpdate {
+                message: buf.into(),
+                ssts,
+            })
+        });
+    }
+
+    /// Register a new replica, which receives all updates after the returned snapshot. Writes, flushes and
+    /// compactions are only blocked while the snapshot is taken, and its files are read when it is sent.
+    pub(crate) fn add_replica(
+        &self,
+    ) -> Result<(ReplicaSnapshot, crossbeam_channel::Receiver<ReplicaUpdate>)> {
+        let _write_lock = self.mvcc().write_lock.lock();
+        let _state_lock = self.state_lock.lock();
+        let (tx, rx) = crossbeam_channel::bounded(REPLICA_QUEUE_CAPACITY);
+        self.replicas.lock().push(tx);
+        Ok((
+            ReplicaSnapshot {
+                state: self.state.read().clone(),
+                commit_ts: self.mvcc().latest_commit_ts(),
+            },
+            rx,
+        ))
+    }
+
+    /// Make the memtable with the given id the current memtable of the replica.
+    fn replica_switch_memtable(&self, memtable_id: usize) {
+        let mut guard = self.state.write();
+        if guard.memtable.id() == memtable_id {
+            return;
+        }
+        let mut snapshot = guard.as_ref().clone();
+        let old_memtable = std::mem::replace(
+            &mut snapshot.memtable,
+            Arc::new(MemTable::create(memtable_id)),
+        );
+        snapshot.imm_memtables.insert(0, old_memtable);
+        *guard = Arc::new(snapshot);
+    }
+
+    fn replica_apply_batches(&self, memtable_id: usize, data: &[u8]) -> Result<()> {
+        // A batch may arrive before the manifest record of the memtable it is written to, as the primary
+        // switches to the new memtable before adding the record.
+        self.replica_switch_memtable(memtable_id);
+        let memtable = self.state.read().memtable.clone();
+        for batch in decode_batches(data)? {
+            let Some(commit_ts) = batch.first().map(|(key, _)| key.ts()) else {
+                continue;
+            };
+            memtable.put_batch(
+                &batch
+                    .iter()
+                    .map(|(key, value)| (key.as_key_slice(), &value[..]))
+                    .collect::<Vec<_>>(),
+            )?;
+            self.mvcc().update_commit_ts(commit_ts);
+        }
+        Ok(())
+    }
+
+    fn replica_open_sst(&self, id: usize, data: Vec<u8>) -> Result<Arc<SsTable>> {
+        Ok(Arc::new(SsTable::open(
+            id,
+            Some(self.block_cache.clone()),
+            self.options.create_sst_file(&self.path_of_sst(id), data)?,
+        )?))
+    }
+
+    fn replica_apply_snapshot(
+        &self,
+        l0_sstables: Vec<usize>,
+        levels: Vec<(usize, Vec<usize>)>,
+        memtables: Vec<usize>,
+        commit_ts: u64,
+        reader: &mut impl Read,
+    ) -> Result<()> {
+        let mut snapshot = self.state.read().as_ref().clone();
+        for id in l0_sstables
+            .iter()
+            .chain(levels.iter().flat_map(|(_, files)| files))
+        {
+            let sst = self.replica_open_sst(*id, read_frame(reader)?)?;
+            snapshot.sstables.insert(*id, sst);
+        }
+        snapshot.l0_sstables = l0_sstables;
+        snapshot.levels = levels;
+        let mut memtables = memtables
+            .into_iter()
+            .map(|id| {
+                let memtable = MemTable::create(id);
+                for batch in decode_batches(&read_frame(reader)?)? {
+                    memtable.put_batch(
+                        &batch
+                            .iter()
+                            .map(|(key, value)| (key.as_key_slice(), &value[..]))
+                            .collect::<Vec<_>>(),
+                    )?;
+                }
+                Ok(Arc::new(memtable))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let Some(memtable) = memtables.pop() else {
+            bail!("snapshot without memtable");
+        };
+        memtables.reverse();
+        snapshot.memtable = memtable;
+        snapshot.imm_memtables = memtables;
+        *self.state.write() = Arc::new(snapshot);
+        self.mvcc().update_commit_ts(commit_ts);
+        Ok(())
+    }
+
+    fn replica_apply_manifest_record(
+        &self,
+        record: ManifestRecord,
+        reader: &mut impl Read,
+    ) -> Result<()> {
+        let mut new_ssts = Vec::new();
+        for id in sst_ids_added_by(&record) {
+            new_ssts.push(self.replica_open_sst(id, read_frame(reader)?)?);
+        }
+        let _state_lock = self.state_lock.lock();
+        match record {
+            ManifestRecord::NewMemtable(memtable_id) => {
+                self.replica_switch_memtable(memtable_id);
+            }
+            ManifestRecord::Flush(memtable_ids, edit) => {
+                let mut guard = self.state.write();
+                let mut snapshot = guard.as_ref().clone();
+                for memtable_id in memtable_ids.iter().rev() {
+                    let memtable = snapshot.imm_memtables.pop();
+                    if memtable.as_ref().map(|x| x.id()) != Some(*memtable_id) {
+                        bail!("memtable {} is not flushed in order", memtable_id);
+                    }
+                }
+                edit.apply(&mut snapshot)?;
+                for sst in new_ssts {
+                    snapshot.sstables.insert(sst.sst_id(), sst);
+                }
+                *guard = Arc::new(snapshot);
+            }
+            ManifestRecord::Snapshot(_)
+            | ManifestRecord::LegacyFlush(..)
+            | ManifestRecord::LegacyCompaction(..) => {
+                bail!("unexpected manifest record")
+            }
+            ManifestRecord::Compaction(edit) => {
+                let files_to_remove = {
+                    let mut guard = self.state.write();
+                    let mut snapshot = guard.as_ref().clone();
+                    for sst in new_ssts {
+                        snapshot.sstables.insert(sst.sst_id(), sst);
+                    }
+                    edit.apply(&mut snapshot)?;
+                    let files_to_remove = edit.removed_ssts();
+                    for id in &files_to_remove {
+                        snapshot.sstables.remove(id);
+                    }
+                    *guard = Arc::new(snapshot);
+                    files_to_remove
+                };
+                for id in files_to_remove {
+                    self.options.fs.remove_file(&self.path_of_sst(id))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Serves the replicas of a `MiniLsm`. Stops accepting replicas and disconnects them when dropped, and
+/// no update is sent after the drop returns.
+pub struct ReplicationPrimary {
+    local_addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    accept_thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl ReplicationPrimary {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    fn serve_replica(
+        inner: &LsmStorageInner,
+        stop: &AtomicBool,
+        mut stream: TcpStream,
+    ) -> Result<()> {
+        stream.set_nonblocking(false)?;
+        let (snapshot, rx) = inner.add_replica()?;
+        snapshot.write_to(&mut stream)?;
+        drop(snapshot);
+        while !stop.load(Ordering::SeqCst) {
+            match rx.recv_timeout(Duration::from_millis(50)) {
+                Ok(update) => update.write_to(&mut stream)?,
+                Err(crossbeam_channel::RecvTimeoutError::Timeout) => {}
+                Err(crossbeam_channel::RecvTimeoutError::Disconnected) => {
+                    bail!("replica is disconnected by the primary")
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ReplicationPrimary {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(accept_thread) = self.accept_thread.take() {
+            accept_thread.join().ok();
+        }
+    }
+}
+
+impl MiniLsm {
+    /// Listen for replicas on the address. Each replica gets a snapshot of the storage and then all
+    /// updates after it.
+    pub fn start_replication(&self, addr: impl ToSocketAddrs) -> Result<ReplicationPrimary> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let local_addr = listener.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let inner = self.inner.clone();
+        let accept_stop = stop.clone();
+        let accept_thread = std::thread::spawn(move || {
+            let mut replica_threads = Vec::new();
+            while !accept_stop.load(Ordering::SeqCst) {
+                match listener.accept() {
+                    Ok((stream, addr)) => {
+                        let inner = inner.clone();
+                        let stop = accept_stop.clone();
+                        replica_threads.push(std::thread::spawn(move || {
+                            if let Err(e) = ReplicationPrimary::serve_replica(&inner, &stop, stream)
+                            {
+                                eprintln!("replication to {} stopped: {:#}", addr, e);
+                            }
+                        }));
+                    }
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                        std::thread::sleep(Duration::from_millis(50));
+                    }
+                    Err(e) => eprintln!("failed to accept replica: {}", e),
+                }
+            }
+            for replica_thread in replica_threads {
+                replica_thread.join().ok();
+            }
+        });
+        Ok(ReplicationPrimary {
+            local_addr,
+            stop,
+            accept_thread: Some(accept_thread),
+        })
+    }
+}
+
+/// A read-only replica of a `MiniLsm` on another process.
+///
+/// The replica keeps the shipped SSTs in its own directory but does not persist its memtables or the
+/// manifest, so it starts over with a new snapshot after a restart.
+pub struct MiniLsmReplica {
+    inner: Arc<LsmStorageInner>,
+    stream: TcpStream,
+    /// Why the replication stopped.
+    error: Arc<Mutex<Option<String>>>,
+    apply_thread: Mutex<Option<std::thread::JoinHandle<()>>>,
+}
+
+impl MiniLsmReplica {
+    /// Connect to the primary and copy its current state into `path`, which must be empty, then keep
+    /// applying the updates of the primary in the background.
+    pub fn connect(
+        addr: impl ToSocketAddrs,
+        path: impl AsRef<Path>,
+        options: LsmStorageOptions,
+    ) -> Result<Arc<Self>> {
+        let inner = Arc::new(LsmStorageInner::open_replica(path, options)?);
+        let stream = TcpStream::connect(addr).context("failed to connect to the primary")?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let ReplicationMessage::Snapshot {
+            l0_sstables,
+            levels,
+            memtables,
+            commit_ts,
+        } = read_message(&mut reader)?
+        else {
+            bail!("expect a snapshot from the primary");
+        };
+        inner.replica_apply_snapshot(l0_sstables, levels, memtables, commit_ts, &mut reader)?;
+
+        let error = Arc::new(Mutex::new(None));
+        let apply_thread = {
+            let inner = inner.clone();
+            let error = error.clone();
+            std::thread::spawn(move || {
+                if let Err(e) = Self::apply_updates(&inner, &mut reader) {
+                    *error.lock() = Some(format!("{:#}", e));
+                }
+            })
+        };
+        Ok(Arc::new(Self {
+            inner,
+            stream,
+            error,
+            apply_thread: Mutex::new(Some(apply_thread)),
+        }))
+    }
+
+    fn apply_updates(inner: &LsmStorageInner, reader: &mut impl Read) -> Result<()> {
+        loop {
+            match read_message(reader)? {
+                ReplicationMessage::Batches { memtable_id } => {
+                    inner.replica_apply_batches(memtable_id, &read_frame(reader)?)?
+                }
+                ReplicationMessage::Manifest => {
+                    let record = ManifestRecord::decode(&read_frame(reader)?)?;
+                    inner.replica_apply_manifest_record(record, reader)?
+                }
+                ReplicationMessage::Snapshot { .. } => bail!("unexpected snapshot"),
+            }
+        }
+    }
+
+    /// The commit ts of the latest write batch applied to the replica.
+    pub fn replicated_ts(&self) -> u64 {
+        self.inner.mvcc().latest_commit_ts()
+    }
+
+    /// Returns the reason if the replica no longer receives updates from the primary.
+    pub fn replication_error(&self) -> Option<String> {
+        self.error.lock().clone()
+    }
+
+    /// Wait until the replica applies all writes up to `ts`.
+    pub fn wait_for_ts(&self, ts: u64, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        while self.replicated_ts() < ts {
+            if let Some(error) = self.replication_error() {
+                bail!("replication stopped: {}", error);
+            }
+            if Instant::now() > deadline {
+                bail!("timeout waiting for ts {}", ts);
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        self.inner.get(key)
+    }
+
+    pub fn scan(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> Result<TxnIterator> {
+        self.inner.scan(lower, upper)
+    }
+
+    /// Disconnect from the primary.
+    pub fn close(&self) -> Result<()> {
+        self.stream.shutdown(Shutdown::Both).ok();
+        if let Some(apply_thread) = self.apply_thread.lock().take() {
+            apply_thread
+                .join()
+                .map_err(|e| anyhow::anyhow!("{:?}", e))?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for MiniLsmReplica {
+    fn drop(&mut self) {
+        self.stream.shutdown(Shutdown::Both).ok();
+    }
+}
@@ -0,0 +1,110 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+
+use crate::lsm_storage::{LsmStorageInner, LsmStorageState};
+use crate::manifest::Manifest;
+use crate::mem_table::MemTable;
+use crate::table::SsTable;
+use crate::wal::WalRecoveryMode;
+
+/// The primary may delete a file or append to the manifest while the secondary reads them, in which case the
+/// secondary retries with the newer manifest.
+const CATCH_UP_ATTEMPTS: usize = 10;
+
+impl LsmStorageInner {
+    /// Refresh the state from the manifest and WALs written by the primary, so that new reads see all writes the
+    /// primary made durable. Reads in progress keep their snapshot, as the SSTs already opened stay readable
+    /// after the primary deletes them.
+    pub fn try_catch_up_with_primary(&self) -> Result<()> {
+        let _state_lock = self.state_lock.lock();
+        let mut attempt = 1;
+        let (state, max_ts) = loop {
+            match self.load_primary_state() {
+                Ok(result) => break result,
+                Err(e) if attempt < CATCH_UP_ATTEMPTS => {
+                    println!("failed to catch up with primary, retrying: {:#}", e);
+                    attempt += 1;
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        *self.state.write() = Arc::new(state);
+        let mvcc = self.mvcc();
+        mvcc.update_commit_ts(mvcc.latest_commit_ts().max(max_ts));
+        Ok(())
+    }
+
+    /// Build the state of the primary from its manifest and WALs, reusing the SSTs and flushed memtables of the
+    /// current state. Returns the state and the max ts of the SSTs and memtables loaded.
+    fn load_primary_state(&self) -> Result<(LsmStorageState, u64)> {
+        let fs = &*self.options.fs;
+        let records = Manifest::read_records(fs, self.path())?;
+        let (mut state, memtables, _) =
+            Self::replay_manifest(&self.options, &self.compaction_controller, records)?;
+        let old_state = self.state.read().clone();
+        let mut max_ts = 0;
+
+        for id in state
+            .l0_sstables
+            .iter()
+            .chain(state.levels.iter().flat_map(|(_, ssts)| ssts))
+        {
+            let sst = match old_state.sstables.get(id) {
+                Some(sst) => sst.clone(),
+                None => {
+                    let sst = SsTable::open(
+                        *id,
+                        Some(self.block_cache.clone()),
+                        self.options.open_sst_file(&self.path_of_sst(*id))?,
+                    )?;
+                    max_ts = max_ts.max(sst.max_ts());
+                    Arc::new(sst)
+                }
+            };
+            state.sstables.insert(*id, sst);
+        }
+
+        if !self.options.enable_wal {
+            return Ok((state, max_ts));
+        }
+        // from earliest to latest, where the latest one is still being written by the primary
+        let memtable_ids = memtables.into_iter().collect::<Vec<_>>();
+        for (idx, id) in memtable_ids.iter().enumerate() {
+            let is_active = idx + 1 == memtable_ids.len();
+            let reused = old_state
+                .imm_memtables
+                .iter()
+                .find(|memtable| memtable.id() == *id)
+                .filter(|_| !is_active);
+            let memtable = match reused {
+                Some(memtable) => memtable.clone(),
+                None => {
+                    let mut path = self.path_of_wal(*id);
+                    if !fs.exists(&path) {
+                        // flushed and archived by the primary after the manifest is read
+                        path = Self::path_of_wal_static(self.path_of_wal_archive(), *id);
+                    }
+                    // the last record may be incomplete as the primary is writing it
+                    let (memtable, _) = MemTable::read_from_wal(
+                        *id,
+                        fs,
+                        path,
+                        WalRecoveryMode::TolerateCorruptedTailRecords,
+                    )?;
+                    let memtable_max_ts = memtable.map.iter().map(|x| x.key().ts()).max();
+                    max_ts = max_ts.max(memtable_max_ts.unwrap_or_default());
+                    Arc::new(memtable)
+                }
+            };
+            if is_active {
+                state.memtable = memtable;
+            } else {
+                state.imm_memtables.insert(0, memtable);
+            }
+        }
+        Ok((state, max_ts))
+    }
+}
@@ -2,16 +2,17 @@ pub(crate) mod bloom;
 mod builder;
 mod iterator;
 
-use std::fs::File;
 use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 
-use anyhow::{anyhow, bail, Result};
+use anyhow::{bail, ensure, Result};
 pub use builder::SsTableBuilder;
-use bytes::{Buf, BufMut};
+use bytes::{Buf, BufMut, Bytes};
 pub use iterator::SsTableIterator;
 
 use crate::block::Block;
+use crate::env::{FileSystem, PosixFileSystem, RandomAccessFile};
 use crate::key::{KeyBytes, KeySlice};
 use crate::lsm_storage::BlockCache;
 
@@ -68,8 +69,13 @@ impl BlockMeta {
     /// Decode block meta from a buffer.
     pub fn decode_block_meta(mut buf: &[u8]) -> Result<(Vec<BlockMeta>, u64)> {
         let mut block_meta = Vec::new();
+        ensure!(buf.len() >= 16, "block meta too short");
+        // verify the checksum before decoding, so that a corrupted meta never panics
+        let checksum = crc32fast::hash(&buf[4..buf.len() - 4]);
+        if (&buf[buf.len() - 4..]).get_u32() != checksum {
+            bail!("meta checksum mismatched");
+        }
         let num = buf.get_u32() as usize;
-        let checksum = crc32fast::hash(&buf[..buf.remaining() - 4]);
         for _ in 0..num {
             let offset = buf.get_u32() as usize;
             let first_key_len = buf.get_u16() as usize;
@@ -85,26 +91,22 @@ impl BlockMeta {
             });
         }
         let max_ts = buf.get_u64();
-        if buf.get_u32() != checksum {
-            bail!("meta checksum mismatched");
-        }
 
         Ok((block_meta, max_ts))
     }
 }
 
 /// A file object.
-pub struct FileObject(Option<File>, u64);
+pub struct FileObject(Option<Arc<dyn RandomAccessFile>>, u64);
 
 impl FileObject {
     pub fn read(&self, offset: u64, len: u64) -> Result<Vec<u8>> {
-        use std::os::unix::fs::FileExt;
-        let mut data = vec![0; len as usize];
-        self.0
-            .as_ref()
-            .unwrap()
-            .read_exact_at(&mut data[..], offset)?;
-        Ok(data)
+        self.0.as_ref().unwrap().read_at(offset, len as usize)
+    }
+
+    /// Like `read`, but returns a slice of the mapped file instead of a copy if the file is memory-mapped.
+    pub fn read_bytes(&self, offset: u64, len: u64) -> Result<Bytes> {
+        self.0.as_ref().unwrap().read_bytes_at(offset, len as usize)
     }
 
     pub fn size(&self) -> u64 {
@@ -113,21 +115,61 @@ impl FileObject {
 
     /// Create a new file object (day 2) and write the file to the disk (day 4).
     pub fn create(path: &Path, data: Vec<u8>) -> Result<Self> {
-        std::fs::write(path, &data)?;
-        File::open(path)?.sync_all()?;
-        Ok(FileObject(
-            Some(File::options().read(true).write(false).open(path)?),
-            data.len() as u64,
-        ))
+        Self::create_with_fs(&PosixFileSystem, path, data)
     }
 
     pub fn open(path: &Path) -> Result<Self> {
-        let file = File::options().read(true).write(false).open(path)?;
-        let size = file.metadata()?.len();
+        Self::open_with_fs(&PosixFileSystem, path)
+    }
+
+    /// Write the file to the file system and open it.
+    pub fn create_with_fs(fs: &dyn FileSystem, path: &Path, data: Vec<u8>) -> Result<Self> {
+        fs.write(path, &data)?;
+        Ok(FileObject(Some(fs.open(path)?), data.len() as u64))
+    }
+
+    pub fn open_with_fs(fs: &dyn FileSystem, path: &Path) -> Result<Self> {
+        let file = fs.open(path)?;
+        let size = file.size()?;
+        Ok(FileObject(Some(file), size))
+    }
+
+    /// Open the file through a memory mapping, if the file system supports it.
+    pub fn open_mmap_with_fs(fs: &dyn FileSystem, path: &Path) -> Result<Self> {
+        let file = fs.open_mmap(path)?;
+        let size = file.size()?;
         Ok(FileObject(Some(file), size))
     }
 }
 
+/// The next id of an opened SST in the block cache. The ids are unique in the process, so that the SSTs of
+/// different storages sharing a block cache, or of the same storage opened again, never share blocks.
+static NEXT_CACHE_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// The block cache of an SST, with the id of the SST in it. The cached blocks of the SST are dropped together
+/// with the SST, as they are never read again.
+pub(crate) struct SstBlockCache {
+    cache: Arc<BlockCache>,
+    cache_id: usize,
+    num_blocks: usize,
+}
+
+impl SstBlockCache {
+    pub(crate) fn new(cache: Arc<BlockCache>, num_blocks: usize) -> Self {
+        Self {
+            cache,
+            cache_id: NEXT_CACHE_ID.fetch_add(1, Ordering::Relaxed),
+            num_blocks,
+        }
+    }
+}
+
+impl Drop for SstBlockCache {
+    fn drop(&mut self) {
+        self.cache.invalidate_sst(self.cache_id, self.num_blocks);
+    }
+}
+
 /// An SSTable.
 pub struct SsTable {
     /// The actual storage unit of SsTable, the format is as above.
@@ -137,7 +179,7 @@ pub struct SsTable {
     /// The offset that indicates the start point of meta blocks in `file`.
     pub(crate) block_meta_offset: usize,
     id: usize,
-    block_cache: Option<Arc<BlockCache>>,
+    block_cache: Option<SstBlockCache>,
     first_key: KeyBytes,
     last_key: KeyBytes,
     pub(crate) bloom: Option<Bloom>,
@@ -152,14 +194,25 @@ impl SsTable {
     /// Open SSTable from a file.
     pub fn open(id: usize, block_cache: Option<Arc<BlockCache>>, file: FileObject) -> Result<Self> {
         let len = file.size();
+        ensure!(len >= 8, "SST too short");
         let raw_bloom_offset = file.read(len - 4, 4)?;
         let bloom_offset = (&raw_bloom_offset[..]).get_u32() as u64;
+        ensure!(
+            (4..=len - 4).contains(&bloom_offset),
+            "invalid bloom filter offset"
+        );
         let raw_bloom = file.read(bloom_offset, len - 4 - bloom_offset)?;
         let bloom_filter = Bloom::decode(&raw_bloom)?;
         let raw_meta_offset = file.read(bloom_offset - 4, 4)?;
         let block_meta_offset = (&raw_meta_offset[..]).get_u32() as u64;
+        ensure!(
+            block_meta_offset <= bloom_offset - 4,
+            "invalid block meta offset"
+        );
         let raw_meta = file.read(block_meta_offset, bloom_offset - 4 - block_meta_offset)?;
         let (block_meta, max_ts) = BlockMeta::decode_block_meta(&raw_meta[..])?;
+        ensure!(!block_meta.is_empty(), "SST has no blocks");
+        let num_blocks = block_meta.len();
         Ok(Self {
             file,
             first_key: block_meta.first().unwrap().first_key.clone(),
@@ -167,7 +220,7 @@ impl SsTable {
             block_meta,
             block_meta_offset: block_meta_offset as usize,
             id,
-            block_cache,
+            block_cache: block_cache.map(|cache| SstBlockCache::new(cache, num_blocks)),
             bloom: Some(bloom_filter),
             max_ts,
         })
@@ -201,24 +254,25 @@ impl SsTable {
             .get(block_idx + 1)
             .map_or(self.block_meta_offset, |x| x.offset);
         let block_len = offset_end - offset - 4;
-        let block_data_with_chksum: Vec<u8> = self
+        let block_data_with_chksum = self
             .file
-            .read(offset as u64, (offset_end - offset) as u64)?;
-        let block_data = &block_data_with_chksum[..block_len];
+            .read_bytes(offset as u64, (offset_end - offset) as u64)?;
+        let block_data = block_data_with_chksum.slice(..block_len);
         let checksum = (&block_data_with_chksum[block_len..]).get_u32();
-        if checksum != crc32fast::hash(block_data) {
+        if checksum != crc32fast::hash(&block_data) {
             bail!("block checksum mismatched");
         }
-        Ok(Arc::new(Block::decode(block_data)))
+        Ok(Arc::new(Block::decode_bytes(block_data)))
     }
 
     /// Read a block from disk, with block cache.
     pub fn read_block_cached(&self, block_idx: usize) -> Result<Arc<Block>> {
         if let Some(ref block_cache) = self.block_cache {
-            let blk = block_cache
-                .try_get_with((self.id, block_idx), || self.read_block(block_idx))
-                .map_err(|e| anyhow!("{}", e))?;
-            Ok(blk)
+            block_cache
+                .cache
+                .try_get_with(block_cache.cache_id, block_idx, || {
+                    self.read_block(block_idx)
+                })
         } else {
             self.read_block(block_idx)
         }
@@ -1,6 +1,6 @@
 // Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
 
-use anyhow::{bail, Result};
+use anyhow::{bail, ensure, Result};
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 
 /// Implements a bloom filter
@@ -47,6 +47,7 @@ impl<T: AsMut<[u8]>> BitSliceMut for T {
 impl Bloom {
     /// Decode a bloom filter
     pub fn decode(buf: &[u8]) -> Result<Self> {
+        ensure!(buf.len() >= 5, "bloom filter too short");
         let checksum = (&buf[buf.len() - 4..buf.len()]).get_u32();
         if checksum != crc32fast::hash(&buf[..buf.len() - 4]) {
             bail!("checksum mismatched for bloom filters");
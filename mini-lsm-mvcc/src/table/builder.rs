@@ -5,10 +5,11 @@ use anyhow::Result;
 use bytes::BufMut;
 
 use super::bloom::Bloom;
-use super::{BlockMeta, FileObject, SsTable};
+use super::{BlockMeta, FileObject, SsTable, SstBlockCache};
 use crate::block::BlockBuilder;
+use crate::env::{FileSystem, PosixFileSystem};
 use crate::key::{KeySlice, KeyVec};
-use crate::lsm_storage::BlockCache;
+use crate::lsm_storage::{BlockCache, LsmStorageOptions};
 
 /// Builds an SSTable from key-value pairs.
 pub struct SsTableBuilder {
@@ -82,10 +83,46 @@ impl SsTableBuilder {
 
     /// Builds the SSTable and writes it to the given path. Use the `FileObject` structure to manipulate the disk objects.
     pub fn build(
-        mut self,
+        self,
         id: usize,
         block_cache: Option<Arc<BlockCache>>,
         path: impl AsRef<Path>,
+    ) -> Result<SsTable> {
+        self.build_with_fs(id, block_cache, &PosixFileSystem, path)
+    }
+
+    /// Builds the SSTable and writes it to the given path of the file system.
+    pub fn build_with_fs(
+        self,
+        id: usize,
+        block_cache: Option<Arc<BlockCache>>,
+        fs: &dyn FileSystem,
+        path: impl AsRef<Path>,
+    ) -> Result<SsTable> {
+        self.build_with(id, block_cache, |data| {
+            FileObject::create_with_fs(fs, path.as_ref(), data)
+        })
+    }
+
+    /// Builds the SSTable and writes it to the given path with the file system of the options, reading it
+    /// through a memory mapping if `mmap_reads` is set.
+    pub fn build_with_options(
+        self,
+        id: usize,
+        block_cache: Option<Arc<BlockCache>>,
+        options: &LsmStorageOptions,
+        path: impl AsRef<Path>,
+    ) -> Result<SsTable> {
+        self.build_with(id, block_cache, |data| {
+            options.create_sst_file(path.as_ref(), data)
+        })
+    }
+
+    fn build_with(
+        mut self,
+        id: usize,
+        block_cache: Option<Arc<BlockCache>>,
+        create_file: impl FnOnce(Vec<u8>) -> Result<FileObject>,
     ) -> Result<SsTable> {
         self.finish_block();
         let mut buf = self.data;
@@ -99,7 +136,8 @@ impl SsTableBuilder {
         let bloom_offset = buf.len();
         bloom.encode(&mut buf);
         buf.put_u32(bloom_offset as u32);
-        let file = FileObject::create(path.as_ref(), buf)?;
+        let file = create_file(buf)?;
+        let num_blocks = self.meta.len();
         Ok(SsTable {
             id,
             file,
@@ -107,7 +145,7 @@ impl SsTableBuilder {
             last_key: self.meta.last().unwrap().last_key.clone(),
             block_meta: self.meta,
             block_meta_offset: meta_offset,
-            block_cache,
+            block_cache: block_cache.map(|cache| SstBlockCache::new(cache, num_blocks)),
             bloom: Some(bloom),
             max_ts: self.max_ts,
         })
@@ -1,6 +1,7 @@
 use std::sync::Arc;
 
 use anyhow::Result;
+use bytes::Bytes;
 
 use super::SsTable;
 use crate::block::BlockIterator;
@@ -82,6 +83,10 @@ impl StorageIterator for SsTableIterator {
         self.blk_iter.value()
     }
 
+    fn value_bytes(&self) -> Bytes {
+        self.blk_iter.value_bytes()
+    }
+
     fn key(&self) -> KeySlice {
         self.blk_iter.key()
     }
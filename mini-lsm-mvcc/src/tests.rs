@@ -1,4 +1,30 @@
+mod background_error;
+mod backup;
+mod baseline_db;
+mod block_cache;
+mod checkpoint;
+mod compaction_options_migration;
+mod crash_consistency;
+mod dir_lock;
+mod flush_gc;
+mod flush_multiple_memtables;
+mod grandparent_overlap;
+mod group_commit;
 mod harness;
+mod intra_l0_compaction;
+mod manifest_encoding;
+mod manifest_rotation;
+mod mem_fs;
+mod mmap_reads;
+mod orphan_files;
+mod pinned_reads;
+mod read_only;
+mod repair;
+mod replication;
+mod secondary;
+mod updates_since;
+mod wal_batch;
+mod wal_recovery_mode;
 mod week1_day1;
 mod week1_day2;
 mod week1_day3;
@@ -0,0 +1,116 @@
+use std::time::Duration;
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    lsm_storage::{BackgroundError, BackgroundErrorReason, LsmStorageOptions, MiniLsm},
+};
+
+#[test]
+fn test_background_flush_error_and_resume() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+
+    // Block the paths of the next SSTs with directories so that the flush cannot create the files.
+    let next_id = storage.inner.state.read().memtable.id();
+    let blocked_paths = (next_id..next_id + 100)
+        .map(|id| storage.inner.path_of_sst(id))
+        .collect::<Vec<_>>();
+    for path in &blocked_paths {
+        std::fs::create_dir(path).unwrap();
+    }
+
+    for i in 0..=options.num_memtable_limit {
+        storage
+            .put(format!("key{}", i).as_bytes(), b"value")
+            .unwrap();
+        storage
+            .inner
+            .force_freeze_memtable(&storage.inner.state_lock.lock())
+            .unwrap();
+    }
+    while storage.background_error().is_none() {
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    assert_eq!(
+        storage.background_error().unwrap().reason,
+        BackgroundErrorReason::Flush
+    );
+
+    // writes fail with the typed error, and reads keep working
+    let err = storage.put(b"key", b"value").unwrap_err();
+    assert!(err.downcast_ref::<BackgroundError>().is_some());
+    assert!(storage.delete(b"key0").is_err());
+    assert_eq!(storage.get(b"key0").unwrap(), Some(Bytes::from("value")));
+
+    // resume fails as long as the problem persists
+    assert!(storage.resume().is_err());
+    assert!(storage.background_error().is_some());
+
+    for path in &blocked_paths {
+        std::fs::remove_dir(path).unwrap();
+    }
+    storage.resume().unwrap();
+    assert!(storage.background_error().is_none());
+    assert!(storage.inner.state.read().imm_memtables.is_empty());
+    storage.put(b"key", b"value").unwrap();
+    for i in 0..=options.num_memtable_limit {
+        assert_eq!(
+            storage.get(format!("key{}", i).as_bytes()).unwrap(),
+            Some(Bytes::from("value"))
+        );
+    }
+}
+
+#[test]
+fn test_memtable_freeze_error_after_write() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    options.target_sst_size = 1024;
+    let storage = MiniLsm::open(&dir, options).unwrap();
+
+    // Block the paths of the next WALs with directories so that the memtable cannot be frozen.
+    let next_id = storage.inner.state.read().memtable.id() + 1;
+    let blocked_paths = (next_id..next_id + 100)
+        .map(|id| storage.inner.path_of_wal(id))
+        .collect::<Vec<_>>();
+    for path in &blocked_paths {
+        std::fs::create_dir(path).unwrap();
+    }
+
+    // the write that fills the memtable fails, though it is applied and visible before the freeze fails
+    let value = [b'v'; 1024];
+    assert!(storage.put(b"key", &value).is_err());
+    assert_eq!(
+        storage.background_error().unwrap().reason,
+        BackgroundErrorReason::MemtableFreeze
+    );
+    assert_eq!(
+        storage.get(b"key").unwrap(),
+        Some(Bytes::copy_from_slice(&value))
+    );
+    let err = storage.put(b"key2", b"value").unwrap_err();
+    assert!(err.downcast_ref::<BackgroundError>().is_some());
+
+    for path in &blocked_paths {
+        std::fs::remove_dir(path).unwrap();
+    }
+    storage.resume().unwrap();
+    assert_eq!(storage.inner.state.read().imm_memtables.len(), 1);
+    storage.put(b"key2", b"value").unwrap();
+}
+
+#[test]
+fn test_empty_batch_takes_no_commit_ts() {
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(&dir, LsmStorageOptions::default_for_week1_test()).unwrap();
+    storage.put(b"key", b"value").unwrap();
+    let ts = storage.inner.mvcc().latest_commit_ts();
+    storage.write_batch::<&[u8]>(&[]).unwrap();
+    storage.new_txn().unwrap().commit().unwrap();
+    assert_eq!(storage.inner.mvcc().latest_commit_ts(), ts);
+}
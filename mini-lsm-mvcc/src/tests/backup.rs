@@ -0,0 +1,177 @@
+use std::path::Path;
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    backup::BackupEngine,
+    compact::CompactionOptions,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+};
+
+fn options() -> LsmStorageOptions {
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    options
+}
+
+fn num_files(dir: &Path) -> usize {
+    std::fs::read_dir(dir).unwrap().count()
+}
+
+/// Creates two backups, where the second one has an SST compacted away after the first one.
+fn create_backups(dir: &Path, backup_engine: &BackupEngine) {
+    let storage = MiniLsm::open(dir.join("db"), options()).unwrap();
+    storage.put(b"a", b"1").unwrap();
+    storage.force_flush().unwrap();
+    storage.put(b"b", b"1").unwrap();
+    storage.force_flush().unwrap();
+    storage.put(b"c", b"1").unwrap();
+    let backup = backup_engine.create_backup(&storage).unwrap();
+    assert_eq!(backup.backup_id, 1);
+    assert_eq!(num_files(&dir.join("backup/shared")), 2);
+
+    storage.put(b"a", b"2").unwrap();
+    storage.force_flush().unwrap();
+    storage.put(b"d", b"2").unwrap();
+    let backup = backup_engine.create_backup(&storage).unwrap();
+    assert_eq!(backup.backup_id, 2);
+    // only the new SST is copied
+    assert_eq!(num_files(&dir.join("backup/shared")), 3);
+    assert_eq!(
+        backup
+            .files
+            .iter()
+            .filter(|file| file.name.ends_with(".sst"))
+            .count(),
+        3
+    );
+
+    storage.force_full_compaction().unwrap();
+    storage.delete(b"b").unwrap();
+    let backup = backup_engine.create_backup(&storage).unwrap();
+    assert_eq!(backup.backup_id, 3);
+    assert_eq!(num_files(&dir.join("backup/shared")), 4);
+}
+
+#[test]
+fn test_backup_and_restore() {
+    let dir = tempdir().unwrap();
+    let backup_engine = BackupEngine::open(dir.path().join("backup"), &options()).unwrap();
+    create_backups(dir.path(), &backup_engine);
+    let backups = backup_engine.backups().unwrap();
+    assert_eq!(
+        backups
+            .iter()
+            .map(|backup| backup.backup_id)
+            .collect::<Vec<_>>(),
+        vec![1, 2, 3]
+    );
+
+    let expected = [
+        (1, [Some("1"), Some("1"), Some("1"), None]),
+        (2, [Some("2"), Some("1"), Some("1"), Some("2")]),
+        (3, [Some("2"), None, Some("1"), Some("2")]),
+    ];
+    for (backup_id, values) in expected {
+        backup_engine.verify_backup(backup_id).unwrap();
+        let restore_dir = dir.path().join(format!("restore{}", backup_id));
+        backup_engine
+            .restore_backup(backup_id, &restore_dir)
+            .unwrap();
+        let storage = MiniLsm::open(&restore_dir, options()).unwrap();
+        for (key, value) in [b"a", b"b", b"c", b"d"].into_iter().zip(values) {
+            assert_eq!(storage.get(key).unwrap(), value.map(Bytes::from));
+        }
+        // the restored storage is independent of the backup
+        storage.put(b"e", b"3").unwrap();
+        storage.force_flush().unwrap();
+        storage.force_full_compaction().unwrap();
+    }
+    for backup_id in 1..=3 {
+        backup_engine.verify_backup(backup_id).unwrap();
+    }
+    assert!(backup_engine
+        .restore_backup(1, dir.path().join("restore1"))
+        .is_err());
+}
+
+#[test]
+fn test_backup_verify_corruption() {
+    let dir = tempdir().unwrap();
+    let backup_engine = BackupEngine::open(dir.path().join("backup"), &options()).unwrap();
+    create_backups(dir.path(), &backup_engine);
+    // corrupt the SST only in the second and third backups
+    let backup = backup_engine.backup(2).unwrap();
+    let file = backup
+        .files
+        .iter()
+        .filter(|file| file.name.ends_with(".sst"))
+        .max_by_key(|file| file.path.clone())
+        .unwrap();
+    let path = dir.path().join("backup").join(&file.path);
+    let mut data = std::fs::read(&path).unwrap();
+    data[0] ^= 0xff;
+    std::fs::write(&path, data).unwrap();
+
+    backup_engine.verify_backup(1).unwrap();
+    assert!(backup_engine.verify_backup(2).is_err());
+    assert!(backup_engine
+        .restore_backup(2, dir.path().join("restore"))
+        .is_err());
+}
+
+#[test]
+fn test_backup_finds_shared_ssts_without_reading() {
+    let dir = tempdir().unwrap();
+    let backup_engine = BackupEngine::open(dir.path().join("backup"), &options()).unwrap();
+    let storage = MiniLsm::open(dir.path().join("db"), options()).unwrap();
+    storage.put(b"a", b"1").unwrap();
+    storage.force_flush().unwrap();
+    let backup = backup_engine.create_backup(&storage).unwrap();
+    let file = backup
+        .files
+        .iter()
+        .find(|file| file.name.ends_with(".sst"))
+        .unwrap();
+    let path = dir.path().join("backup").join(&file.path);
+    let mut data = std::fs::read(&path).unwrap();
+    data[0] ^= 0xff;
+    std::fs::write(&path, data).unwrap();
+
+    // the SST is found by id and size, so the next backup neither copies nor checksums it again
+    storage.put(b"b", b"2").unwrap();
+    let backup = backup_engine.create_backup(&storage).unwrap();
+    assert!(backup.files.contains(file));
+    assert_eq!(num_files(&dir.path().join("backup/shared")), 1);
+    assert!(backup_engine.verify_backup(backup.backup_id).is_err());
+}
+
+#[test]
+fn test_backup_purge() {
+    let dir = tempdir().unwrap();
+    let backup_engine = BackupEngine::open(dir.path().join("backup"), &options()).unwrap();
+    create_backups(dir.path(), &backup_engine);
+    backup_engine.purge_old_backups(2).unwrap();
+    assert_eq!(backup_engine.backups().unwrap().len(), 2);
+    // all SSTs are still referenced by backup 2
+    assert_eq!(num_files(&dir.path().join("backup/shared")), 4);
+    assert_eq!(num_files(&dir.path().join("backup/private")), 2);
+
+    backup_engine.delete_backup(2).unwrap();
+    assert!(backup_engine.delete_backup(2).is_err());
+    // only the compacted SST of backup 3 is left
+    assert_eq!(num_files(&dir.path().join("backup/shared")), 1);
+    assert_eq!(num_files(&dir.path().join("backup/private")), 1);
+    backup_engine.verify_backup(3).unwrap();
+    backup_engine
+        .restore_backup(3, dir.path().join("restore"))
+        .unwrap();
+    let storage = MiniLsm::open(dir.path().join("restore"), options()).unwrap();
+    assert_eq!(storage.get(b"a").unwrap(), Some(Bytes::from("2")));
+    assert_eq!(storage.get(b"b").unwrap(), None);
+
+    // a new backup gets the id after the latest one
+    let storage = MiniLsm::open(dir.path().join("db"), options()).unwrap();
+    assert_eq!(backup_engine.create_backup(&storage).unwrap().backup_id, 4);
+}
@@ -0,0 +1,74 @@
+use std::path::Path;
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::{CompactionOptions, SimpleLeveledCompactionOptions},
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+};
+
+/// Copy a storage written by the version before memtables were flushed together into `dir`. The CLI wrote it
+/// with simple leveled compaction and the WAL enabled, running `fill 1000 1049`, `flush`, `fill 1040 1059`,
+/// `flush`, `fill 1050 1069`, `del 1001` and `close`, so the flushes are compacted into SST 6 at the bottom
+/// level and the last memtable is only in WAL 2.
+pub(crate) fn copy_baseline_db(dir: &Path) {
+    let files: [(&str, &[u8]); 3] = [
+        ("MANIFEST", include_bytes!("fixtures/baseline/MANIFEST")),
+        ("00002.wal", include_bytes!("fixtures/baseline/00002.wal")),
+        ("00006.sst", include_bytes!("fixtures/baseline/00006.sst")),
+    ];
+    for (name, data) in files {
+        std::fs::write(dir.join(name), data).unwrap();
+    }
+}
+
+/// The options the CLI opened the baseline storage with.
+pub(crate) fn baseline_db_options() -> LsmStorageOptions {
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::Simple(
+        SimpleLeveledCompactionOptions {
+            size_ratio_percent: 200,
+            level0_file_num_compaction_trigger: 2,
+            max_levels: 4,
+            level0_file_num_intra_compaction_trigger: None,
+        },
+    ));
+    options.enable_wal = true;
+    options
+}
+
+#[test]
+fn test_open_baseline_db() {
+    let dir = tempdir().unwrap();
+    copy_baseline_db(dir.path());
+    let storage = MiniLsm::open(&dir, baseline_db_options()).unwrap();
+    assert_eq!(storage.inner.state.read().levels[3].1, vec![6]);
+    assert_eq!(
+        storage.get(b"1000").unwrap(),
+        Some(Bytes::from("value1000@0"))
+    );
+    assert_eq!(
+        storage.get(b"1045").unwrap(),
+        Some(Bytes::from("value1045@2"))
+    );
+    // the writes in the WAL, which has a record for each key
+    assert_eq!(storage.get(b"1001").unwrap(), None);
+    assert_eq!(
+        storage.get(b"1055").unwrap(),
+        Some(Bytes::from("value1055@4"))
+    );
+    storage.close().unwrap();
+    drop(storage);
+
+    // the WAL is rewritten with batch records
+    let storage = MiniLsm::open(&dir, baseline_db_options()).unwrap();
+    assert_eq!(
+        storage.get(b"1049").unwrap(),
+        Some(Bytes::from("value1049@2"))
+    );
+    assert_eq!(storage.get(b"1001").unwrap(), None);
+    assert_eq!(
+        storage.get(b"1069").unwrap(),
+        Some(Bytes::from("value1069@4"))
+    );
+}
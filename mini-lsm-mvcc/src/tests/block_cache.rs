@@ -0,0 +1,79 @@
+use std::sync::Arc;
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    block::BlockCache,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+};
+
+fn key_of(i: usize) -> Vec<u8> {
+    format!("key{:05}", i).into_bytes()
+}
+
+#[test]
+fn test_block_cache_byte_budget() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week1_test();
+    options.block_size = 1024;
+    options.block_cache_size = 16 << 10;
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    for i in 0..2000 {
+        storage.put(&key_of(i), &[b'v'; 100]).unwrap();
+    }
+    storage.force_flush().unwrap();
+    for i in 0..2000 {
+        assert_eq!(
+            storage.get(&key_of(i)).unwrap(),
+            Some(Bytes::from(vec![b'v'; 100]))
+        );
+    }
+    let cache = &storage.inner.block_cache;
+    assert_eq!(cache.capacity(), 16 << 10);
+    // the SST is much larger than the cache, which keeps only some of its blocks
+    assert!(cache.size() > 0);
+    assert!(cache.size() <= cache.capacity());
+}
+
+#[test]
+fn test_shared_block_cache() {
+    let cache = Arc::new(BlockCache::new(1 << 20));
+    let mut options = LsmStorageOptions::default_for_week1_test();
+    options.block_cache = Some(cache.clone());
+    let dir1 = tempdir().unwrap();
+    let dir2 = tempdir().unwrap();
+    let storage1 = MiniLsm::open(&dir1, options.clone()).unwrap();
+    let storage2 = MiniLsm::open(&dir2, options).unwrap();
+    assert!(Arc::ptr_eq(&storage1.inner.block_cache, &cache));
+    assert!(Arc::ptr_eq(&storage2.inner.block_cache, &cache));
+
+    // the SSTs of both storages have the same ids, but their blocks are cached separately
+    for i in 0..100 {
+        storage1.put(&key_of(i), b"storage1").unwrap();
+        storage2.put(&key_of(i), b"storage2").unwrap();
+    }
+    storage1.force_flush().unwrap();
+    storage2.force_flush().unwrap();
+    for i in 0..100 {
+        assert_eq!(
+            storage1.get(&key_of(i)).unwrap(),
+            Some(Bytes::from("storage1"))
+        );
+        assert_eq!(
+            storage2.get(&key_of(i)).unwrap(),
+            Some(Bytes::from("storage2"))
+        );
+    }
+    let size = cache.size();
+    assert!(size > 0);
+
+    // the cache outlives the storages, and only keeps the blocks of the open one
+    storage1.close().unwrap();
+    drop(storage1);
+    assert_eq!(
+        storage2.get(&key_of(0)).unwrap(),
+        Some(Bytes::from("storage2"))
+    );
+    assert_eq!(cache.size(), size / 2);
+}
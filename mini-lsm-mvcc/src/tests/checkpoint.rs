@@ -0,0 +1,130 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    iterators::StorageIterator,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+};
+
+fn options(enable_wal: bool) -> LsmStorageOptions {
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = enable_wal;
+    options
+}
+
+fn key_of(i: usize) -> Vec<u8> {
+    format!("key{:05}", i).into_bytes()
+}
+
+fn check_checkpoint(enable_wal: bool) {
+    let dir = tempdir().unwrap();
+    let checkpoint_dir = dir.path().join("checkpoint");
+    let storage = MiniLsm::open(dir.path().join("db"), options(enable_wal)).unwrap();
+    storage.put(b"a", b"1").unwrap();
+    storage.force_flush().unwrap();
+    storage.put(b"b", b"1").unwrap();
+    storage.force_flush().unwrap();
+    storage.force_full_compaction().unwrap();
+    storage.put(b"a", b"2").unwrap();
+    storage.force_flush().unwrap();
+    storage.put(b"c", b"1").unwrap();
+    storage.create_checkpoint(&checkpoint_dir).unwrap();
+
+    // the storage keeps working after the checkpoint, and its changes are not seen by the checkpoint
+    storage.put(b"d", b"1").unwrap();
+    storage.force_flush().unwrap();
+    storage.force_full_compaction().unwrap();
+    storage.delete(b"a").unwrap();
+
+    let checkpoint = MiniLsm::open(&checkpoint_dir, options(enable_wal)).unwrap();
+    assert_eq!(checkpoint.get(b"a").unwrap(), Some(Bytes::from("2")));
+    assert_eq!(checkpoint.get(b"b").unwrap(), Some(Bytes::from("1")));
+    assert_eq!(checkpoint.get(b"c").unwrap(), Some(Bytes::from("1")));
+    assert_eq!(checkpoint.get(b"d").unwrap(), None);
+
+    // and the checkpoint is independent of the storage
+    checkpoint.put(b"e", b"1").unwrap();
+    checkpoint.force_flush().unwrap();
+    checkpoint.force_full_compaction().unwrap();
+    assert_eq!(storage.get(b"a").unwrap(), None);
+    assert_eq!(storage.get(b"d").unwrap(), Some(Bytes::from("1")));
+    assert_eq!(storage.get(b"e").unwrap(), None);
+    checkpoint.close().unwrap();
+    drop(checkpoint);
+    let checkpoint = MiniLsm::open(&checkpoint_dir, options(enable_wal)).unwrap();
+    assert_eq!(checkpoint.get(b"a").unwrap(), Some(Bytes::from("2")));
+    assert_eq!(checkpoint.get(b"e").unwrap(), Some(Bytes::from("1")));
+}
+
+#[test]
+fn test_checkpoint_with_wal() {
+    check_checkpoint(true);
+}
+
+#[test]
+fn test_checkpoint_without_wal() {
+    check_checkpoint(false);
+}
+
+#[test]
+fn test_checkpoint_dir_exists() {
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(dir.path().join("db"), options(true)).unwrap();
+    std::fs::create_dir(dir.path().join("checkpoint")).unwrap();
+    assert!(storage
+        .create_checkpoint(dir.path().join("checkpoint"))
+        .is_err());
+}
+
+#[test]
+fn test_checkpoint_during_writes() {
+    let dir = tempdir().unwrap();
+    let mut options = options(true);
+    options.target_sst_size = 1 << 10;
+    options.num_memtable_limit = 3;
+    let storage = MiniLsm::open(dir.path().join("db"), options.clone()).unwrap();
+    let stop = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let storage = storage.clone();
+        let stop = stop.clone();
+        std::thread::spawn(move || {
+            let mut i = 0;
+            while !stop.load(Ordering::SeqCst) {
+                storage.put(&key_of(i), b"value").unwrap();
+                i += 1;
+            }
+            i
+        })
+    };
+    for i in 0..3 {
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        storage
+            .create_checkpoint(dir.path().join(format!("checkpoint{}", i)))
+            .unwrap();
+    }
+    stop.store(true, Ordering::SeqCst);
+    let num_keys = writer.join().unwrap();
+
+    let mut last_num_keys = 0;
+    for i in 0..3 {
+        // each checkpoint has a prefix of the writes
+        let checkpoint =
+            MiniLsm::open(dir.path().join(format!("checkpoint{}", i)), options.clone()).unwrap();
+        let mut iter = checkpoint
+            .scan(std::ops::Bound::Unbounded, std::ops::Bound::Unbounded)
+            .unwrap();
+        let mut checkpoint_num_keys = 0;
+        while iter.is_valid() {
+            assert_eq!(iter.key(), key_of(checkpoint_num_keys));
+            checkpoint_num_keys += 1;
+            iter.next().unwrap();
+        }
+        assert!(checkpoint_num_keys >= last_num_keys);
+        assert!(checkpoint_num_keys <= num_keys);
+        last_num_keys = checkpoint_num_keys;
+    }
+}
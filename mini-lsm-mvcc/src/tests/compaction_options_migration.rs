@@ -0,0 +1,128 @@
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::{
+        CompactionOptions, LeveledCompactionOptions, SimpleLeveledCompactionOptions,
+        TieredCompactionOptions,
+    },
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+};
+
+fn leveled() -> CompactionOptions {
+    CompactionOptions::Leveled(LeveledCompactionOptions {
+        level_size_multiplier: 2,
+        level0_file_num_compaction_trigger: 2,
+        max_levels: 4,
+        base_level_size_mb: 1,
+        level0_file_num_intra_compaction_trigger: None,
+    })
+}
+
+fn simple(max_levels: usize) -> CompactionOptions {
+    CompactionOptions::Simple(SimpleLeveledCompactionOptions {
+        size_ratio_percent: 200,
+        level0_file_num_compaction_trigger: 2,
+        max_levels,
+        level0_file_num_intra_compaction_trigger: None,
+    })
+}
+
+fn tiered() -> CompactionOptions {
+    CompactionOptions::Tiered(TieredCompactionOptions {
+        num_tiers: 3,
+        max_size_amplification_percent: 200,
+        size_ratio: 1,
+        min_merge_width: 2,
+    })
+}
+
+/// Writes a round of updates: key `i` gets value `round` unless it is deleted in this round.
+fn write_round(storage: &MiniLsm, round: usize) {
+    for i in 0..20 {
+        if i % 5 == round % 5 {
+            storage.delete(format!("key{:02}", i).as_bytes()).unwrap();
+        } else {
+            storage
+                .put(
+                    format!("key{:02}", i).as_bytes(),
+                    format!("{}", round).as_bytes(),
+                )
+                .unwrap();
+        }
+    }
+    storage.force_flush().unwrap();
+    if !matches!(
+        storage.inner.options.compaction_options,
+        CompactionOptions::NoCompaction
+    ) {
+        storage.inner.trigger_compaction().unwrap();
+    }
+}
+
+fn check_round(storage: &MiniLsm, round: usize) {
+    for i in 0..20 {
+        let expected = (i % 5 != round % 5).then(|| Bytes::from(format!("{}", round)));
+        assert_eq!(
+            storage.get(format!("key{:02}", i).as_bytes()).unwrap(),
+            expected,
+            "key{:02} after round {}",
+            i,
+            round
+        );
+    }
+}
+
+#[test]
+fn test_reopen_with_other_compaction_options() {
+    let dir = tempdir().unwrap();
+    let mut round = 0;
+    for compaction_options in [
+        leveled(),
+        tiered(),
+        simple(3),
+        simple(1),
+        leveled(),
+        CompactionOptions::NoCompaction,
+        tiered(),
+        leveled(),
+    ] {
+        let options = LsmStorageOptions::default_for_week2_test(compaction_options.clone());
+        let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+        if round > 0 {
+            check_round(&storage, round);
+        }
+        // the level layout fits the compaction options
+        {
+            let state = storage.inner.state.read();
+            match &compaction_options {
+                CompactionOptions::Tiered(_) => {
+                    assert!(state.l0_sstables.is_empty());
+                    for (tier_id, ssts) in &state.levels {
+                        assert_eq!(ssts.first(), Some(tier_id));
+                    }
+                }
+                CompactionOptions::Leveled(LeveledCompactionOptions { max_levels, .. })
+                | CompactionOptions::Simple(SimpleLeveledCompactionOptions {
+                    max_levels, ..
+                }) => {
+                    let level_ids = state.levels.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+                    assert_eq!(level_ids, (1..=*max_levels).collect::<Vec<_>>());
+                }
+                CompactionOptions::NoCompaction => assert_eq!(state.levels.len(), 1),
+            }
+        }
+        for _ in 0..3 {
+            round += 1;
+            write_round(&storage, round);
+        }
+        check_round(&storage, round);
+        storage.close().unwrap();
+        drop(storage);
+
+        // reopening with the same options keeps the layout
+        let storage = MiniLsm::open(&dir, options).unwrap();
+        check_round(&storage, round);
+        storage.close().unwrap();
+    }
+}
@@ -0,0 +1,369 @@
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use parking_lot::Mutex;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::{
+    compact::{CompactionOptions, LeveledCompactionOptions},
+    env::fault_injection::FaultInjectionFileSystem,
+    env::FileSystem,
+    iterators::StorageIterator,
+    lsm_storage::{
+        BackgroundError, BackgroundErrorReason, LsmStorageInner, LsmStorageOptions, MiniLsm,
+        WriteBatchRecord, WriteOptions,
+    },
+};
+
+const DB_DIR: &str = "/mini-lsm-crash-test/db";
+
+fn options(fs: &FaultInjectionFileSystem) -> LsmStorageOptions {
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::Leveled(
+        LeveledCompactionOptions {
+            level_size_multiplier: 2,
+            level0_file_num_compaction_trigger: 2,
+            max_levels: 3,
+            base_level_size_mb: 1,
+            level0_file_num_intra_compaction_trigger: None,
+        },
+    ));
+    options.enable_wal = true;
+    options.block_size = 64;
+    options.target_sst_size = 1024;
+    // memtables are only flushed by the test, but the compaction thread still compacts in the background, so
+    // the crash points vary between runs
+    options.num_memtable_limit = 1000;
+    options.manifest_max_size = 1024;
+    options.fs = Arc::new(fs.clone());
+    options
+}
+
+/// A write of the workload: the key and the value, or `None` for a delete.
+type Write = (Vec<u8>, Option<Vec<u8>>);
+
+fn workload(seed: u64, num_writes: usize) -> Vec<Write> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    (0..num_writes)
+        .map(|i| {
+            let key = format!("key{:03}", rng.gen_range(0..100)).into_bytes();
+            let value = if rng.gen_bool(0.2) {
+                None
+            } else {
+                Some(format!("value{}", i).into_bytes())
+            };
+            (key, value)
+        })
+        .collect()
+}
+
+fn apply(model: &mut BTreeMap<Vec<u8>, Vec<u8>>, (key, value): &Write) {
+    match value {
+        Some(value) => model.insert(key.clone(), value.clone()),
+        None => model.remove(key),
+    };
+}
+
+fn dump(storage: &MiniLsm) -> BTreeMap<Vec<u8>, Vec<u8>> {
+    let mut iter = storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+    let mut data = BTreeMap::new();
+    while iter.is_valid() {
+        data.insert(iter.key().to_vec(), iter.value().to_vec());
+        iter.next().unwrap();
+    }
+    data
+}
+
+/// Reopen the storage after a crash, and check that its data is the result of some prefix of the attempted
+/// writes that includes all acknowledged synced writes.
+fn check_recovered(fs: &FaultInjectionFileSystem, writes: &[Write], num_synced: usize) {
+    let storage = MiniLsm::open(DB_DIR, options(fs)).unwrap();
+    let recovered = dump(&storage);
+    let mut model = BTreeMap::new();
+    for write in &writes[..num_synced] {
+        apply(&mut model, write);
+    }
+    let mut matched = model == recovered;
+    for write in &writes[num_synced..] {
+        if matched {
+            break;
+        }
+        apply(&mut model, write);
+        matched = model == recovered;
+    }
+    assert!(
+        matched,
+        "recovered state is not a prefix of the writes with all {} synced writes",
+        num_synced
+    );
+    storage.close().unwrap();
+}
+
+/// Run the workload until the first failure, flushing and compacting along the way. Returns the number of
+/// attempted writes (including a failed one, which may or may not be applied) and the number of writes up to
+/// the last acknowledged synced write.
+fn run_until_failure(storage: &MiniLsm, writes: &[Write]) -> (usize, usize) {
+    let mut num_synced = 0;
+    for (idx, (key, value)) in writes.iter().enumerate() {
+        let record = match value {
+            Some(value) => WriteBatchRecord::Put(key, value),
+            None => WriteBatchRecord::Del(key),
+        };
+        let sync = idx % 5 == 4;
+        if storage
+            .write_batch_with_options(&[record], &WriteOptions { sync })
+            .is_err()
+        {
+            return (idx + 1, num_synced);
+        }
+        if sync {
+            num_synced = idx + 1;
+        }
+        let result = match idx % 50 {
+            29 => storage.force_flush(),
+            49 => storage
+                .force_flush()
+                .and_then(|_| storage.inner.trigger_compaction()),
+            _ => Ok(()),
+        };
+        if result.is_err() {
+            return (idx + 1, num_synced);
+        }
+    }
+    (writes.len(), num_synced)
+}
+
+#[test]
+fn test_crash_during_writes_flushes_and_compactions() {
+    let writes = workload(1, 300);
+    let mut num_crashes = 0;
+    for crash_after in (0..700).step_by(7) {
+        let fs = FaultInjectionFileSystem::new();
+        let storage = MiniLsm::open(DB_DIR, options(&fs)).unwrap();
+        fs.crash_after(crash_after);
+        let (num_attempted, num_synced) = run_until_failure(&storage, &writes);
+        if fs.crashed() {
+            num_crashes += 1;
+        } else {
+            // the workload completed, so kill the process instead
+            fs.crash();
+        }
+        drop(storage);
+        let fs = fs.restart();
+        check_recovered(&fs, &writes[..num_attempted], num_synced);
+    }
+    assert!(num_crashes > 50);
+}
+
+#[test]
+fn test_repeated_crashes() {
+    let mut fs = FaultInjectionFileSystem::new();
+    let mut writes = Vec::new();
+    let mut model = BTreeMap::new();
+    for round in 0..20 {
+        let storage = MiniLsm::open(DB_DIR, options(&fs)).unwrap();
+        assert_eq!(dump(&storage), model, "round {}", round);
+        let round_writes = workload(round, 120);
+        fs.crash_after(50 + round as usize * 37);
+        let (num_attempted, num_synced) = run_until_failure(&storage, &round_writes);
+        fs.crash();
+        drop(storage);
+        fs = fs.restart();
+        writes.extend_from_slice(&round_writes[..num_attempted]);
+        let num_synced = writes.len() - num_attempted + num_synced;
+        check_recovered(&fs, &writes, num_synced);
+        // continue from the recovered state
+        let storage = MiniLsm::open(DB_DIR, options(&fs)).unwrap();
+        model = dump(&storage);
+        storage.close().unwrap();
+        drop(storage);
+        writes = model
+            .iter()
+            .map(|(key, value)| (key.clone(), Some(value.clone())))
+            .collect();
+    }
+}
+
+#[test]
+fn test_crash_with_concurrent_writers() {
+    for crash_after in (20..1000).step_by(2) {
+        let fs = FaultInjectionFileSystem::new();
+        let storage = MiniLsm::open(DB_DIR, options(&fs)).unwrap();
+        fs.crash_after(crash_after);
+        let acked = Mutex::new(Vec::new());
+        std::thread::scope(|scope| {
+            for thread in 0..4 {
+                let storage = &storage;
+                let acked = &acked;
+                scope.spawn(move || {
+                    for i in 0.. {
+                        let key = format!("key{}_{:05}", thread, i);
+                        let result = storage.write_batch_with_options(
+                            &[WriteBatchRecord::Put(key.as_bytes(), b"value")],
+                            &WriteOptions { sync: true },
+                        );
+                        if result.is_err() || storage.background_error().is_some() {
+                            return;
+                        }
+                        acked.lock().push(key);
+                    }
+                });
+            }
+            // switch memtables while the writers write to them
+            while !fs.crashed() {
+                let state_lock = storage.inner.state_lock.lock();
+                if !storage.inner.state.read().memtable.is_empty() {
+                    storage.inner.force_freeze_memtable(&state_lock).ok();
+                }
+            }
+        });
+        drop(storage);
+        let fs = fs.restart();
+        let storage = MiniLsm::open(DB_DIR, options(&fs)).unwrap();
+        for key in acked.lock().iter() {
+            assert_eq!(
+                storage.get(key.as_bytes()).unwrap(),
+                Some(Bytes::from("value")),
+                "acknowledged synced write of {} is lost after crashing after {} operations",
+                key,
+                crash_after
+            );
+        }
+    }
+}
+
+#[test]
+fn test_sync_failure() {
+    let fs = FaultInjectionFileSystem::new();
+    let storage = MiniLsm::open(DB_DIR, options(&fs)).unwrap();
+    let writes = workload(2, 200);
+    let (num_attempted, num_synced) = run_until_failure(&storage, &writes[..100]);
+    assert_eq!((num_attempted, num_synced), (100, 100));
+
+    fs.set_fail_sync(true);
+    // the write whose sync fails is not acknowledged, and puts the storage into the read-only state
+    let (key, _) = &writes[100];
+    let result = storage.inner.write_batch_inner(
+        &[WriteBatchRecord::Put(key.as_slice(), b"unsynced")],
+        &WriteOptions { sync: true },
+    );
+    assert!(result
+        .unwrap_err()
+        .downcast_ref::<BackgroundError>()
+        .is_none());
+    assert_eq!(
+        storage.background_error().unwrap().reason,
+        BackgroundErrorReason::WalSync
+    );
+    for (key, _) in &writes[101..120] {
+        let result = storage.inner.write_batch_inner(
+            &[WriteBatchRecord::Put(key.as_slice(), b"unsynced")],
+            &WriteOptions { sync: true },
+        );
+        assert!(result
+            .unwrap_err()
+            .downcast_ref::<BackgroundError>()
+            .is_some());
+    }
+    fs.set_fail_sync(false);
+    fs.crash();
+    drop(storage);
+    let fs = fs.restart();
+    // the write with the failed sync is lost in the crash, and the later writes are never applied
+    let storage = MiniLsm::open(DB_DIR, options(&fs)).unwrap();
+    let mut model = BTreeMap::new();
+    for write in &writes[..100] {
+        apply(&mut model, write);
+    }
+    assert_eq!(dump(&storage), model);
+}
+
+#[test]
+fn test_no_space() {
+    let fs = FaultInjectionFileSystem::new();
+    let mut options = options(&fs);
+    // no memtable is frozen during the test, so a failed write is never applied
+    options.target_sst_size = 1 << 20;
+    let storage = MiniLsm::open(DB_DIR, options.clone()).unwrap();
+    let mut model = BTreeMap::new();
+    for (idx, write) in workload(3, 200).iter().enumerate() {
+        fs.set_no_space((50..80).contains(&idx));
+        let (key, value) = write;
+        let record = match value {
+            Some(value) => WriteBatchRecord::Put(key, value),
+            None => WriteBatchRecord::Del(key),
+        };
+        let result = storage.write_batch_with_options(&[record], &WriteOptions { sync: true });
+        if (50..80).contains(&idx) {
+            let err = result.unwrap_err();
+            assert!(
+                format!("{:#}", err).contains("No space left on device"),
+                "{:#}",
+                err
+            );
+        } else {
+            result.unwrap();
+            apply(&mut model, write);
+        }
+    }
+    assert_eq!(dump(&storage), model);
+    fs.crash();
+    drop(storage);
+    let fs = fs.restart();
+    options.fs = Arc::new(fs.clone());
+    let storage = MiniLsm::open(DB_DIR, options).unwrap();
+    assert_eq!(dump(&storage), model);
+}
+
+#[test]
+fn test_corruption_detected() {
+    let fs = FaultInjectionFileSystem::new();
+    let storage = MiniLsm::open(DB_DIR, options(&fs)).unwrap();
+    for i in 0..100 {
+        storage
+            .put(format!("key{:03}", i).as_bytes(), b"value")
+            .unwrap();
+    }
+    storage.force_flush().unwrap();
+    let sst_id = storage.inner.state.read().l0_sstables[0];
+    for i in 100..110 {
+        storage
+            .write_batch_with_options(
+                &[WriteBatchRecord::Put(
+                    format!("key{:03}", i),
+                    "value".to_string(),
+                )],
+                &WriteOptions { sync: true },
+            )
+            .unwrap();
+    }
+    storage.close().unwrap();
+    drop(storage);
+
+    // a corrupted byte in the last WAL record drops only that record
+    let wal_path = fs
+        .list(Path::new(DB_DIR))
+        .unwrap()
+        .into_iter()
+        .filter(|name| name.ends_with(".wal"))
+        .max()
+        .unwrap();
+    let wal_path = Path::new(DB_DIR).join(wal_path);
+    let wal_size = fs.file_size(&wal_path).unwrap() as usize;
+    fs.corrupt(&wal_path, wal_size - 1).unwrap();
+    let storage = MiniLsm::open(DB_DIR, options(&fs)).unwrap();
+    assert_eq!(storage.get(b"key108").unwrap(), Some(Bytes::from("value")));
+    assert_eq!(storage.get(b"key109").unwrap(), None);
+    storage.close().unwrap();
+    drop(storage);
+
+    // a corrupted data block is reported instead of returning wrong data
+    fs.corrupt(LsmStorageInner::path_of_sst_static(DB_DIR, sst_id), 10)
+        .unwrap();
+    let storage = MiniLsm::open(DB_DIR, options(&fs)).unwrap();
+    assert!(storage.get(b"key000").is_err());
+    assert_eq!(storage.get(b"key105").unwrap(), Some(Bytes::from("value")));
+}
@@ -0,0 +1,78 @@
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+    repair::repair_db,
+};
+
+fn options() -> LsmStorageOptions {
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    options
+}
+
+#[test]
+fn test_double_open() {
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(&dir, options()).unwrap();
+    storage.put(b"key", b"value").unwrap();
+    assert!(dir.path().join("LOCK").exists());
+    let err = MiniLsm::open(&dir, options()).err().unwrap().to_string();
+    assert!(err.contains("already opened"), "{}", err);
+    // the failed open does not affect the storage
+    storage.put(b"key2", b"value2").unwrap();
+    assert_eq!(storage.get(b"key").unwrap(), Some(Bytes::from("value")));
+
+    // read-only storages and secondaries do not take the lock
+    storage.inner.sync().unwrap();
+    let read_only = MiniLsm::open_read_only(&dir, options()).unwrap();
+    assert_eq!(read_only.get(b"key").unwrap(), Some(Bytes::from("value")));
+    let secondary = MiniLsm::open_as_secondary(&dir, options()).unwrap();
+    assert_eq!(secondary.get(b"key2").unwrap(), Some(Bytes::from("value2")));
+}
+
+#[test]
+fn test_reopen_after_close_and_drop() {
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(&dir, options()).unwrap();
+    storage.put(b"key", b"value").unwrap();
+    storage.close().unwrap();
+    // the closed storage can still be written to, so it keeps the lock until it is dropped
+    assert!(MiniLsm::open(&dir, options()).is_err());
+    storage.put(b"key", b"value2").unwrap();
+    storage.inner.sync().unwrap();
+    drop(storage);
+
+    let storage = MiniLsm::open(&dir, options()).unwrap();
+    assert_eq!(storage.get(b"key").unwrap(), Some(Bytes::from("value2")));
+    // a transaction keeps the storage open after the handle is dropped
+    let txn = storage.new_txn().unwrap();
+    drop(storage);
+    assert!(MiniLsm::open(&dir, options()).is_err());
+    txn.put(b"key", b"value3");
+    txn.commit().unwrap();
+    drop(txn);
+
+    let storage = MiniLsm::open(&dir, options()).unwrap();
+    assert_eq!(storage.get(b"key").unwrap(), Some(Bytes::from("value3")));
+}
+
+#[test]
+fn test_repair_open_storage() {
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(&dir, options()).unwrap();
+    storage.put(b"key", b"value").unwrap();
+    let err = repair_db(&dir, &options()).unwrap_err().to_string();
+    assert!(err.contains("already opened"), "{}", err);
+    // the failed repair leaves the manifest in place
+    storage.put(b"key2", b"value2").unwrap();
+    storage.close().unwrap();
+    drop(storage);
+
+    let storage = MiniLsm::open(&dir, options()).unwrap();
+    assert_eq!(storage.get(b"key2").unwrap(), Some(Bytes::from("value2")));
+    drop(storage);
+    repair_db(&dir, &options()).unwrap();
+}
@@ -0,0 +1,84 @@
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    lsm_storage::{CompactionFilter, LsmStorageOptions, MiniLsm, WriteBatchRecord},
+    table::SsTableIterator,
+};
+
+use super::harness::{check_iter_result_by_key, construct_merge_iterator_over_storage};
+
+#[test]
+fn test_flush_gc_obsolete_versions() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"a", b"1").unwrap();
+    storage.put(b"a", b"2").unwrap();
+    storage.put(b"b", b"1").unwrap();
+    let snapshot = storage.new_txn().unwrap();
+    storage.put(b"a", b"3").unwrap();
+    storage.delete(b"b").unwrap();
+    storage.put(b"a", b"4").unwrap();
+    storage.force_flush().unwrap();
+
+    let mut iter = construct_merge_iterator_over_storage(&storage.inner.state.read());
+    check_iter_result_by_key(
+        &mut iter,
+        vec![
+            (Bytes::from("a"), Bytes::from("4")),
+            (Bytes::from("a"), Bytes::from("3")),
+            (Bytes::from("a"), Bytes::from("2")),
+            (Bytes::from("b"), Bytes::new()),
+            (Bytes::from("b"), Bytes::from("1")),
+        ],
+    );
+    assert_eq!(snapshot.get(b"a").unwrap(), Some(Bytes::from("2")));
+    assert_eq!(snapshot.get(b"b").unwrap(), Some(Bytes::from("1")));
+    drop(snapshot);
+
+    storage.put(b"a", b"5").unwrap();
+    storage.put(b"a", b"6").unwrap();
+    storage.delete(b"b").unwrap();
+    storage.force_flush().unwrap();
+
+    let state = storage.inner.state.read().clone();
+    let latest_sst = state.sstables[&state.l0_sstables[0]].clone();
+    let mut iter = SsTableIterator::create_and_seek_to_first(latest_sst).unwrap();
+    check_iter_result_by_key(
+        &mut iter,
+        vec![
+            (Bytes::from("a"), Bytes::from("6")),
+            (Bytes::from("b"), Bytes::new()),
+        ],
+    );
+    assert_eq!(storage.get(b"a").unwrap(), Some(Bytes::from("6")));
+    assert_eq!(storage.get(b"b").unwrap(), None);
+}
+
+#[test]
+fn test_flush_with_compaction_filter() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.add_compaction_filter(CompactionFilter::Prefix(Bytes::from("table2_")));
+    storage
+        .write_batch(&[
+            WriteBatchRecord::Put("table1_a", "1"),
+            WriteBatchRecord::Put("table1_b", "1"),
+            WriteBatchRecord::Put("table2_a", "1"),
+            WriteBatchRecord::Put("table2_b", "1"),
+        ])
+        .unwrap();
+    storage.force_flush().unwrap();
+
+    let mut iter = construct_merge_iterator_over_storage(&storage.inner.state.read());
+    check_iter_result_by_key(
+        &mut iter,
+        vec![
+            (Bytes::from("table1_a"), Bytes::from("1")),
+            (Bytes::from("table1_b"), Bytes::from("1")),
+        ],
+    );
+}
@@ -0,0 +1,104 @@
+use std::sync::Arc;
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    lsm_storage::{LsmStorageInner, LsmStorageOptions, MiniLsm},
+};
+
+use super::harness::check_lsm_iter_result_by_key;
+
+fn key_of(i: usize) -> Bytes {
+    Bytes::from(format!("key_{:05}", i))
+}
+
+fn value_of(i: usize) -> Bytes {
+    Bytes::from(format!("value_{:0100}", i))
+}
+
+#[test]
+fn test_flush_multiple_memtables_into_one_sst() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    for i in 0..4 {
+        storage.put(&key_of(i), &value_of(i)).unwrap();
+        storage.put(b"common", &value_of(i)).unwrap();
+        storage
+            .inner
+            .force_freeze_memtable(&storage.inner.state_lock.lock())
+            .unwrap();
+    }
+    let memtable_ids = storage
+        .inner
+        .state
+        .read()
+        .imm_memtables
+        .iter()
+        .map(|x| x.id())
+        .collect::<Vec<_>>();
+    assert_eq!(memtable_ids.len(), 4);
+    storage.inner.force_flush_imm_memtables().unwrap();
+    {
+        let snapshot = storage.inner.state.read();
+        assert!(snapshot.imm_memtables.is_empty());
+        assert_eq!(snapshot.l0_sstables.len(), 1);
+    }
+    for id in memtable_ids {
+        assert!(!storage.inner.path_of_wal(id).exists());
+    }
+    assert_eq!(storage.get(b"common").unwrap(), Some(value_of(3)));
+    storage.close().unwrap();
+    drop(storage);
+
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    assert_eq!(storage.inner.state.read().l0_sstables.len(), 1);
+    let mut expected = (0..4).map(|i| (key_of(i), value_of(i))).collect::<Vec<_>>();
+    expected.push((Bytes::from("common"), value_of(3)));
+    expected.sort();
+    check_lsm_iter_result_by_key(
+        &mut storage
+            .scan(std::ops::Bound::Unbounded, std::ops::Bound::Unbounded)
+            .unwrap(),
+        expected,
+    );
+}
+
+#[test]
+fn test_flush_output_cut_by_target_sst_size() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.block_size = 1024;
+    options.target_sst_size = 4096;
+    options.num_memtable_limit = 100;
+    let storage = Arc::new(LsmStorageInner::open(&dir, options).unwrap());
+    for i in 0..200 {
+        storage.put(&key_of(i), &value_of(i)).unwrap();
+    }
+    let num_memtables = storage.state.read().imm_memtables.len();
+    assert!(num_memtables > 1);
+    storage.force_flush_imm_memtables().unwrap();
+
+    let snapshot = storage.state.read().clone();
+    assert!(snapshot.imm_memtables.is_empty());
+    assert!(snapshot.l0_sstables.len() > 1);
+    for id in &snapshot.l0_sstables {
+        assert!(snapshot.sstables[id].table_size() < 2 * 4096);
+    }
+    // SSTs flushed together do not overlap and are added to L0 in key order
+    let mut last_key = None;
+    for id in &snapshot.l0_sstables {
+        let sst = &snapshot.sstables[id];
+        if let Some(last_key) = last_key {
+            assert!(sst.first_key().key_ref() > last_key);
+        }
+        last_key = Some(sst.last_key().key_ref());
+    }
+    drop(snapshot);
+    for i in 0..200 {
+        assert_eq!(storage.get(&key_of(i)).unwrap(), Some(value_of(i)));
+    }
+}
@@ -0,0 +1,85 @@
+use std::sync::Arc;
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::{CompactionOptions, SimpleLeveledCompactionOptions},
+    lsm_storage::{LsmStorageInner, LsmStorageOptions},
+};
+
+use super::harness::generate_sst_with_ts;
+
+fn key_of(i: usize) -> Bytes {
+    Bytes::from(format!("key_{:05}", i))
+}
+
+fn value_of(i: usize) -> Bytes {
+    Bytes::from(format!("value_{:0100}", i))
+}
+
+/// Compacts an L1 SST with keys 0..100 into L2, with 10 SSTs of 10 keys each in L3, and returns the first keys of the
+/// L2 SSTs.
+fn compact_with_grandparent_overlap(grandparent_ssts_allowed: f64) -> Vec<Bytes> {
+    let dir = tempdir().unwrap();
+    let mut l3_ssts = Vec::new();
+    for i in 0..10 {
+        let id = 200 + i;
+        l3_ssts.push(Arc::new(generate_sst_with_ts(
+            id,
+            LsmStorageInner::path_of_sst_static(&dir, id),
+            (i * 10..i * 10 + 10)
+                .map(|x| ((key_of(x), 1), value_of(x)))
+                .collect(),
+            None,
+        )));
+    }
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::Simple(
+        SimpleLeveledCompactionOptions {
+            size_ratio_percent: 200,
+            level0_file_num_compaction_trigger: 2,
+            max_levels: 3,
+            level0_file_num_intra_compaction_trigger: None,
+        },
+    ));
+    options.max_grandparent_overlap_size =
+        (l3_ssts[0].table_size() as f64 * grandparent_ssts_allowed) as usize;
+    let storage = LsmStorageInner::open(&dir, options).unwrap();
+    let l1_sst = Arc::new(generate_sst_with_ts(
+        100,
+        storage.path_of_sst(100),
+        (0..100).map(|x| ((key_of(x), 1), value_of(x))).collect(),
+        None,
+    ));
+    {
+        let mut guard = storage.state.write();
+        let mut snapshot = guard.as_ref().clone();
+        snapshot.levels[0].1 = vec![l1_sst.sst_id()];
+        snapshot.sstables.insert(l1_sst.sst_id(), l1_sst);
+        snapshot.levels[2].1 = l3_ssts.iter().map(|sst| sst.sst_id()).collect();
+        for sst in l3_ssts {
+            snapshot.sstables.insert(sst.sst_id(), sst);
+        }
+        *guard = Arc::new(snapshot);
+    }
+    storage.trigger_compaction().unwrap();
+
+    let snapshot = storage.state.read().clone();
+    assert!(snapshot.levels[0].1.is_empty());
+    snapshot.levels[1]
+        .1
+        .iter()
+        .map(|id| Bytes::copy_from_slice(snapshot.sstables[id].first_key().key_ref()))
+        .collect()
+}
+
+#[test]
+fn test_compaction_output_cut_by_grandparent_overlap() {
+    // cut when the output SST overlaps with more than 1.5 grandparent SSTs, which is at every other L3 boundary
+    assert_eq!(
+        compact_with_grandparent_overlap(1.5),
+        vec![key_of(0), key_of(20), key_of(40), key_of(60), key_of(80)]
+    );
+    // 0 disables cutting by grandparent overlap
+    assert_eq!(compact_with_grandparent_overlap(0.0), vec![key_of(0)]);
+}
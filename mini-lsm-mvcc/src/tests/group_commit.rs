@@ -0,0 +1,97 @@
+use std::time::Duration;
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    lsm_storage::{LsmStorageOptions, MiniLsm, WriteBatchRecord, WriteOptions},
+};
+
+#[test]
+fn test_group_commit_queued_writers() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    let sync = WriteOptions { sync: true };
+
+    // writers queue up while another leader holds the write lock, and the next leader commits all of them
+    let write_lock = storage.inner.mvcc().write_lock.lock();
+    let handles = (0..8)
+        .map(|i| {
+            let storage = storage.clone();
+            let sync = sync.clone();
+            std::thread::spawn(move || {
+                let key = format!("key{}", i);
+                storage
+                    .inner
+                    .write_batch_inner(&[WriteBatchRecord::Put(key.as_bytes(), b"value")], &sync)
+                    .unwrap()
+            })
+        })
+        .collect::<Vec<_>>();
+    while storage.inner.pending_writes.lock().len() < 8 {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    drop(write_lock);
+    let mut commit_ts = handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .collect::<Vec<_>>();
+    commit_ts.sort();
+    assert_eq!(commit_ts, (1..=8).collect::<Vec<_>>());
+    assert!(storage.inner.pending_writes.lock().is_empty());
+    for i in 0..8 {
+        assert_eq!(
+            storage.get(format!("key{}", i).as_bytes()).unwrap(),
+            Some(Bytes::from("value"))
+        );
+    }
+}
+
+#[test]
+fn test_concurrent_sync_writes_recovery() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    let handles = (0..8)
+        .map(|thread| {
+            let storage = storage.clone();
+            std::thread::spawn(move || {
+                for i in 0..100 {
+                    let key = format!("key_{}_{:03}", thread, i);
+                    storage
+                        .write_batch_with_options(
+                            &[
+                                WriteBatchRecord::Put(key.as_bytes(), b"value"),
+                                WriteBatchRecord::Put(b"last", key.as_bytes()),
+                            ],
+                            &WriteOptions { sync: i % 2 == 0 },
+                        )
+                        .unwrap();
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    let last = storage.get(b"last").unwrap().unwrap();
+    storage.close().unwrap();
+    drop(storage);
+
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    for thread in 0..8 {
+        for i in 0..100 {
+            assert_eq!(
+                storage
+                    .get(format!("key_{}_{:03}", thread, i).as_bytes())
+                    .unwrap(),
+                Some(Bytes::from("value"))
+            );
+        }
+    }
+    assert_eq!(storage.get(b"last").unwrap(), Some(last));
+}
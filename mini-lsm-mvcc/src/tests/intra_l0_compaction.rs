@@ -0,0 +1,246 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::{
+        CompactionController, CompactionOptions, CompactionTask, LeveledCompactionController,
+        LeveledCompactionOptions, SimpleLeveledCompactionController,
+        SimpleLeveledCompactionOptions, SimpleLeveledCompactionTask,
+    },
+    key::KeyBytes,
+    lsm_storage::{LsmStorageOptions, LsmStorageState, MiniLsm},
+    mem_table::MemTable,
+    table::SsTable,
+};
+
+fn simple_options_with_intra_l0(trigger: usize) -> SimpleLeveledCompactionOptions {
+    SimpleLeveledCompactionOptions {
+        size_ratio_percent: 200,
+        level0_file_num_compaction_trigger: 2,
+        max_levels: 1,
+        level0_file_num_intra_compaction_trigger: Some(trigger),
+    }
+}
+
+fn mock_sst(id: usize, file_size: u64) -> Arc<SsTable> {
+    Arc::new(SsTable::create_meta_only(
+        id,
+        file_size,
+        KeyBytes::for_testing_from_bytes_no_ts(Bytes::from("a")),
+        KeyBytes::for_testing_from_bytes_no_ts(Bytes::from("z")),
+    ))
+}
+
+/// A state with the given L0 SSTs and their sizes, and `num_l1_sstables` SSTs in L1.
+fn mock_state(l0_sstables: &[(usize, u64)], num_l1_sstables: usize) -> LsmStorageState {
+    let l1_sstables = (100..100 + num_l1_sstables).collect::<Vec<_>>();
+    let mut state = LsmStorageState {
+        memtable: Arc::new(MemTable::create(0)),
+        imm_memtables: Vec::new(),
+        l0_sstables: l0_sstables.iter().map(|(id, _)| *id).collect(),
+        levels: vec![(1, l1_sstables.clone())],
+        sstables: Default::default(),
+    };
+    for (id, file_size) in l0_sstables {
+        state.sstables.insert(*id, mock_sst(*id, *file_size));
+    }
+    for id in l1_sstables {
+        state.sstables.insert(id, mock_sst(id, 1));
+    }
+    state
+}
+
+#[test]
+fn test_intra_l0_compaction_task() {
+    let controller = CompactionController::Simple(SimpleLeveledCompactionController::new(
+        simple_options_with_intra_l0(4),
+    ));
+    // L0 is compacted into L1 when it can be
+    let task = controller
+        .generate_compaction_task(&mock_state(&[(4, 1), (3, 1), (2, 1), (1, 1)], 0))
+        .unwrap();
+    assert!(matches!(
+        task,
+        CompactionTask::Simple(SimpleLeveledCompactionTask {
+            upper_level: None,
+            ..
+        })
+    ));
+
+    // L1 is large enough that L0 is held back by the size ratio
+    assert!(controller
+        .generate_compaction_task(&mock_state(&[(3, 1), (2, 1), (1, 1)], 10))
+        .is_none());
+    let task = controller
+        .generate_compaction_task(&mock_state(&[(5, 1), (4, 1), (3, 1), (2, 1), (1, 1)], 10))
+        .unwrap();
+    let CompactionTask::IntraL0 { ref l0_sstables } = task else {
+        panic!("expect intra-L0 compaction task, got {:?}", task);
+    };
+    // at most as many SSTs as the trigger are merged, starting from the newest
+    assert_eq!(l0_sstables, &vec![5, 4, 3, 2]);
+
+    // SST 6 is flushed while the compaction is running, and it should stay in front of the output.
+    let (state, removed) = controller.apply_compaction_result(
+        &mock_state(&[(6, 1), (5, 1), (4, 1), (3, 1), (2, 1), (1, 1)], 10),
+        &task,
+        &[7],
+    );
+    assert_eq!(state.l0_sstables, vec![6, 7, 1]);
+    assert_eq!(removed, vec![5, 4, 3, 2]);
+
+    // the output of an earlier intra-L0 compaction is not merged again with fewer, smaller SSTs
+    let task = controller
+        .generate_compaction_task(&mock_state(&[(10, 1), (9, 1), (8, 1), (7, 4), (1, 1)], 10))
+        .unwrap();
+    let CompactionTask::IntraL0 { ref l0_sstables } = task else {
+        panic!("expect intra-L0 compaction task, got {:?}", task);
+    };
+    assert_eq!(l0_sstables, &vec![10, 9, 8]);
+}
+
+/// A state with `num_l0_sstables` L0 SSTs, and one SST of the given size in each of L1 and L2.
+fn mock_leveled_state(num_l0_sstables: usize, l1_size: u64, l2_size: u64) -> LsmStorageState {
+    let l0_sstables = (1..=num_l0_sstables)
+        .rev()
+        .map(|id| (id, 1))
+        .collect::<Vec<_>>();
+    let mut state = mock_state(&l0_sstables, 0);
+    state.levels = vec![(1, vec![100]), (2, vec![200])];
+    state.sstables.insert(100, mock_sst(100, l1_size));
+    state.sstables.insert(200, mock_sst(200, l2_size));
+    state
+}
+
+#[test]
+fn test_leveled_intra_l0_compaction_task() {
+    const MB: u64 = 1024 * 1024;
+    let controller = |trigger| {
+        CompactionController::Leveled(LeveledCompactionController::new(LeveledCompactionOptions {
+            level_size_multiplier: 10,
+            level0_file_num_compaction_trigger: 2,
+            max_levels: 2,
+            base_level_size_mb: 1,
+            level0_file_num_intra_compaction_trigger: trigger,
+        }))
+    };
+    // L2 is 20MB, so L1 is the base level with a target size of 2MB
+
+    // L0 is compacted into the base level when the base level is within its target size
+    let task = controller(Some(4))
+        .generate_compaction_task(&mock_leveled_state(5, MB, 20 * MB))
+        .unwrap();
+    let CompactionTask::Leveled(ref task) = task else {
+        panic!("expect leveled compaction task, got {:?}", task);
+    };
+    assert_eq!(task.upper_level, None);
+
+    // when the base level is over its target size, L0 is merged within L0 instead
+    let task = controller(Some(4))
+        .generate_compaction_task(&mock_leveled_state(5, 3 * MB, 20 * MB))
+        .unwrap();
+    let CompactionTask::IntraL0 { ref l0_sstables } = task else {
+        panic!("expect intra-L0 compaction task, got {:?}", task);
+    };
+    assert_eq!(l0_sstables, &vec![5, 4, 3, 2]);
+
+    // and the base level is compacted first if L0 is not congested enough for intra-L0 compaction
+    let task = controller(Some(4))
+        .generate_compaction_task(&mock_leveled_state(3, 3 * MB, 20 * MB))
+        .unwrap();
+    let CompactionTask::Leveled(ref task) = task else {
+        panic!("expect leveled compaction task, got {:?}", task);
+    };
+    assert_eq!(task.upper_level, Some(1));
+
+    // without intra-L0 compaction, L0 is always compacted into the base level first
+    let task = controller(None)
+        .generate_compaction_task(&mock_leveled_state(5, 3 * MB, 20 * MB))
+        .unwrap();
+    let CompactionTask::Leveled(ref task) = task else {
+        panic!("expect leveled compaction task, got {:?}", task);
+    };
+    assert_eq!(task.upper_level, None);
+}
+
+#[test]
+fn test_invalid_intra_l0_compaction_trigger() {
+    let options = CompactionOptions::Simple(simple_options_with_intra_l0(2));
+    assert!(options.validate().is_err());
+    let dir = tempdir().unwrap();
+    assert!(MiniLsm::open(&dir, LsmStorageOptions::default_for_week2_test(options)).is_err());
+    assert!(CompactionOptions::Simple(simple_options_with_intra_l0(3))
+        .validate()
+        .is_ok());
+}
+
+fn wait_for_l0_sstables_below(storage: &MiniLsm, count: usize) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while storage.inner.state.read().l0_sstables.len() >= count {
+        if Instant::now() > deadline {
+            panic!(
+                "timeout waiting for fewer than {} L0 SSTs, got {:?}",
+                count,
+                storage.inner.state.read().l0_sstables
+            );
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn test_integration_intra_l0_compaction() {
+    let dir = tempdir().unwrap();
+    let mut compaction_options = simple_options_with_intra_l0(4);
+    compaction_options.size_ratio_percent = 50;
+    let mut options =
+        LsmStorageOptions::default_for_week2_test(CompactionOptions::Simple(compaction_options));
+    options.target_sst_size = 1024;
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    // fill L1 with enough SSTs that the size ratio holds L0 back
+    for round in 0..2 {
+        for i in 0..200 {
+            storage
+                .put(format!("base{:03}", i).as_bytes(), &[b'0' + round; 100])
+                .unwrap();
+        }
+        storage.force_flush().unwrap();
+    }
+    wait_for_l0_sstables_below(&storage, 2);
+    let l1_sstables = storage.inner.state.read().levels[0].1.clone();
+    assert!(l1_sstables.len() >= 2);
+
+    for i in 0..6 {
+        storage
+            .put(
+                format!("key{}", i).as_bytes(),
+                format!("value{}", i).as_bytes(),
+            )
+            .unwrap();
+        storage
+            .put(b"common", format!("value{}", i).as_bytes())
+            .unwrap();
+        storage.force_flush().unwrap();
+    }
+    wait_for_l0_sstables_below(&storage, 4);
+    assert_eq!(storage.inner.state.read().levels[0].1, l1_sstables);
+    for i in 0..6 {
+        assert_eq!(
+            storage.get(format!("key{}", i).as_bytes()).unwrap(),
+            Some(Bytes::from(format!("value{}", i)))
+        );
+    }
+    assert_eq!(storage.get(b"common").unwrap(), Some(Bytes::from("value5")));
+    let l0_sstables = storage.inner.state.read().l0_sstables.clone();
+    storage.close().unwrap();
+    drop(storage);
+
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    assert_eq!(storage.inner.state.read().l0_sstables, l0_sstables);
+    assert_eq!(storage.get(b"common").unwrap(), Some(Bytes::from("value5")));
+}
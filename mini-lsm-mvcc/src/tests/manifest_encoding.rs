@@ -0,0 +1,188 @@
+use bytes::{Buf, Bytes};
+use tempfile::tempdir;
+
+use crate::{
+    compact::{CompactionOptions, LeveledCompactionOptions},
+    lsm_storage::{LsmStorageOptions, LsmStorageState, MiniLsm},
+    manifest::{LevelEdit, ManifestRecord, ManifestSnapshot, VersionEdit},
+};
+
+use super::baseline_db::{baseline_db_options, copy_baseline_db};
+
+fn state(l0_sstables: &[usize], levels: &[(usize, &[usize])]) -> LsmStorageState {
+    let mut state = LsmStorageState::create(&LsmStorageOptions::default_for_week1_test());
+    state.l0_sstables = l0_sstables.to_vec();
+    state.levels = levels
+        .iter()
+        .map(|(id, ssts)| (*id, ssts.to_vec()))
+        .collect();
+    state
+}
+
+fn assert_edit_applies(old: &LsmStorageState, new: &LsmStorageState) -> VersionEdit {
+    let edit = VersionEdit::diff(old, new);
+    let mut applied = old.clone();
+    edit.apply(&mut applied).unwrap();
+    assert_eq!(applied.l0_sstables, new.l0_sstables);
+    assert_eq!(applied.levels, new.levels);
+    edit
+}
+
+#[test]
+fn test_version_edit() {
+    // leveled compaction from L1 to L2, with the output sorted among the SSTs kept in L2
+    let old = state(&[9, 8], &[(1, &[3]), (2, &[1, 4, 2])]);
+    let new = state(&[9, 8], &[(1, &[]), (2, &[1, 10, 11, 2])]);
+    let edit = assert_edit_applies(&old, &new);
+    assert_eq!(
+        edit,
+        VersionEdit {
+            levels: vec![
+                LevelEdit {
+                    level: Some(1),
+                    removed: vec![3],
+                    added: vec![],
+                },
+                LevelEdit {
+                    level: Some(2),
+                    removed: vec![4],
+                    added: vec![(1, 10), (2, 11)],
+                },
+            ],
+            level_ids: None,
+        }
+    );
+    assert_eq!(edit.added_ssts(), vec![10, 11]);
+    assert_eq!(edit.removed_ssts(), vec![3, 4]);
+
+    // flush and intra-L0 compaction
+    assert_edit_applies(&old, &state(&[12, 9, 8], &[(1, &[3]), (2, &[1, 4, 2])]));
+    assert_edit_applies(&old, &state(&[13], &[(1, &[3]), (2, &[1, 4, 2])]));
+
+    // tiered compaction replaces tiers with a new one, and tier 0 is not confused with L0
+    let old = state(&[], &[(7, &[7]), (5, &[5, 6]), (0, &[0, 1]), (2, &[2])]);
+    let new = state(&[], &[(7, &[7]), (8, &[8, 9, 10]), (2, &[2])]);
+    let edit = assert_edit_applies(&old, &new);
+    assert_eq!(edit.level_ids, Some(vec![7, 8, 2]));
+    assert_eq!(edit.added_ssts(), vec![8, 9, 10]);
+    assert_eq!(edit.removed_ssts(), vec![5, 6, 0, 1]);
+
+    // SSTs moved to another level are neither added nor removed
+    let edit = assert_edit_applies(
+        &state(&[4], &[(1, &[]), (2, &[])]),
+        &state(&[], &[(1, &[4]), (2, &[])]),
+    );
+    assert!(edit.added_ssts().is_empty());
+    assert!(edit.removed_ssts().is_empty());
+
+    // an edit does not apply to a state without the removed SSTs
+    let mut other = state(&[1], &[]);
+    assert!(edit.apply(&mut other).is_err());
+}
+
+#[test]
+fn test_manifest_record_encoding() {
+    let records = [
+        ManifestRecord::NewMemtable(300),
+        ManifestRecord::Flush(
+            vec![3, 2],
+            VersionEdit::diff(&state(&[1], &[]), &state(&[2, 1 << 40, 1], &[])),
+        ),
+        ManifestRecord::Compaction(VersionEdit::diff(
+            &state(&[], &[(0, &[0]), (2, &[2])]),
+            &state(&[], &[(3, &[3, 4])]),
+        )),
+        ManifestRecord::Snapshot(ManifestSnapshot {
+            l0_sstables: vec![5, 4],
+            levels: vec![(1, vec![1, 2]), (2, vec![])],
+            memtables: vec![6, 7],
+            next_sst_id: 8,
+        }),
+    ];
+    for record in records {
+        let mut buf = Vec::new();
+        record.encode(&mut buf).unwrap();
+        let mut encoded_again = Vec::new();
+        ManifestRecord::decode(&buf)
+            .unwrap()
+            .encode(&mut encoded_again)
+            .unwrap();
+        assert_eq!(buf, encoded_again);
+        assert!(ManifestRecord::decode(&buf[..buf.len() - 1]).is_err());
+        buf[0] = 2;
+        assert!(ManifestRecord::decode(&buf).is_err());
+    }
+}
+
+#[test]
+fn test_leveled_compaction_recovery() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::Leveled(
+        LeveledCompactionOptions {
+            level_size_multiplier: 2,
+            level0_file_num_compaction_trigger: 2,
+            max_levels: 3,
+            base_level_size_mb: 1,
+            level0_file_num_intra_compaction_trigger: None,
+        },
+    ));
+    // cut compaction outputs into several SSTs
+    options.block_size = 64;
+    options.target_sst_size = 256;
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    for round in 0..3 {
+        for i in 0..10 {
+            storage
+                .put(format!("key{}", i * 3 + round).as_bytes(), b"value")
+                .unwrap();
+        }
+        storage.force_flush().unwrap();
+        storage.inner.trigger_compaction().unwrap();
+    }
+    let expected_state = storage.inner.state.read().clone();
+    assert!(expected_state.levels.iter().any(|(_, ssts)| ssts.len() > 1));
+    storage.close().unwrap();
+    drop(storage);
+
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    let state = storage.inner.state.read().clone();
+    assert_eq!(state.l0_sstables, expected_state.l0_sstables);
+    assert_eq!(state.levels, expected_state.levels);
+    for i in 0..30 {
+        assert_eq!(
+            storage.get(format!("key{}", i).as_bytes()).unwrap(),
+            Some(Bytes::from("value"))
+        );
+    }
+}
+
+#[test]
+fn test_json_manifest_migration() {
+    let dir = tempdir().unwrap();
+    copy_baseline_db(dir.path());
+    let options = baseline_db_options();
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    let levels = storage.inner.state.read().levels.clone();
+    assert_eq!(levels[3].1, vec![6]);
+    assert_eq!(
+        storage.get(b"1000").unwrap(),
+        Some(Bytes::from("value1000@0"))
+    );
+    drop(storage);
+
+    // the manifest is rewritten in the binary encoding
+    assert!(!dir.path().join("MANIFEST").exists());
+    let manifest = std::fs::read(dir.path().join("MANIFEST-000001")).unwrap();
+    let mut buf = &manifest[..];
+    while buf.has_remaining() {
+        let len = buf.get_u64() as usize;
+        assert_eq!(buf[0], 1);
+        buf.advance(len + 4);
+    }
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    assert_eq!(storage.inner.state.read().levels, levels);
+    assert_eq!(
+        storage.get(b"1000").unwrap(),
+        Some(Bytes::from("value1000@0"))
+    );
+}
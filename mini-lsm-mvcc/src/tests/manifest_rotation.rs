@@ -0,0 +1,108 @@
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::{CompactionOptions, TieredCompactionOptions},
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+};
+
+fn manifest_files(dir: &std::path::Path) -> Vec<String> {
+    let mut files = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name.starts_with("MANIFEST"))
+        .collect::<Vec<_>>();
+    files.sort();
+    files
+}
+
+#[test]
+fn test_manifest_rotation() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::Tiered(
+        TieredCompactionOptions {
+            num_tiers: 3,
+            max_size_amplification_percent: 200,
+            size_ratio: 1,
+            min_merge_width: 2,
+        },
+    ));
+    options.enable_wal = true;
+    options.manifest_max_size = 256;
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    assert_eq!(manifest_files(dir.path()), vec!["MANIFEST-000001"]);
+
+    for round in 0..20 {
+        for i in 0..10 {
+            storage
+                .put(
+                    format!("key{}", i).as_bytes(),
+                    format!("value{}", round).as_bytes(),
+                )
+                .unwrap();
+        }
+        storage.force_flush().unwrap();
+        storage.inner.trigger_compaction().unwrap();
+    }
+    storage.put(b"unflushed", b"value").unwrap();
+
+    // only the manifest in use is kept, and it stays small
+    let files = manifest_files(dir.path());
+    assert_eq!(files.len(), 1);
+    assert_ne!(files[0], "MANIFEST-000001");
+    assert_eq!(
+        std::fs::read_to_string(dir.path().join("CURRENT"))
+            .unwrap()
+            .trim(),
+        files[0]
+    );
+    assert!(std::fs::metadata(dir.path().join(&files[0])).unwrap().len() < 512);
+
+    let expected_levels = storage.inner.state.read().levels.clone();
+    storage.inner.sync().unwrap();
+    drop(storage);
+
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    assert_eq!(storage.inner.state.read().levels, expected_levels);
+    for i in 0..10 {
+        assert_eq!(
+            storage.get(format!("key{}", i).as_bytes()).unwrap(),
+            Some(Bytes::from("value19"))
+        );
+    }
+    assert_eq!(
+        storage.get(b"unflushed").unwrap(),
+        Some(Bytes::from("value"))
+    );
+}
+
+#[test]
+fn test_manifest_legacy_file() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"a", b"1").unwrap();
+    storage.force_flush().unwrap();
+    storage.close().unwrap();
+    drop(storage);
+
+    // a directory written before the manifest is rotated only has the MANIFEST file
+    std::fs::rename(
+        dir.path().join("MANIFEST-000001"),
+        dir.path().join("MANIFEST"),
+    )
+    .unwrap();
+    std::fs::remove_file(dir.path().join("CURRENT")).unwrap();
+
+    let mut options = options;
+    options.manifest_max_size = 0;
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    assert_eq!(storage.get(b"a").unwrap(), Some(Bytes::from("1")));
+    // the legacy file is replaced on the first rotation
+    assert_eq!(manifest_files(dir.path()), vec!["MANIFEST-000001"]);
+    storage.close().unwrap();
+    drop(storage);
+
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    assert_eq!(storage.get(b"a").unwrap(), Some(Bytes::from("1")));
+}
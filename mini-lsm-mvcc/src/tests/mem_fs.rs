@@ -0,0 +1,129 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use bytes::Bytes;
+
+use crate::{
+    backup::BackupEngine,
+    compact::{CompactionOptions, SimpleLeveledCompactionOptions},
+    env::{FileSystem, MemFileSystem},
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+};
+
+/// A path that does not exist on the local file system.
+const DB_DIR: &str = "/mini-lsm-mem-fs-test/db";
+
+fn options(fs: &MemFileSystem) -> LsmStorageOptions {
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::Simple(
+        SimpleLeveledCompactionOptions {
+            size_ratio_percent: 200,
+            level0_file_num_compaction_trigger: 2,
+            max_levels: 3,
+            level0_file_num_intra_compaction_trigger: None,
+        },
+    ));
+    options.enable_wal = true;
+    options.target_sst_size = 1024;
+    options.fs = Arc::new(fs.clone());
+    options
+}
+
+fn key_of(i: usize) -> Vec<u8> {
+    format!("key{:05}", i).into_bytes()
+}
+
+#[test]
+fn test_storage_on_mem_fs() {
+    let fs = MemFileSystem::new();
+    let storage = MiniLsm::open(DB_DIR, options(&fs)).unwrap();
+    for i in 0..500 {
+        storage
+            .put(&key_of(i), format!("value{}", i).as_bytes())
+            .unwrap();
+        if i % 100 == 99 {
+            storage.force_flush().unwrap();
+        }
+    }
+    storage.inner.trigger_compaction().unwrap();
+    storage.delete(&key_of(0)).unwrap();
+    storage.put(&key_of(1), b"new").unwrap();
+
+    let files = fs.list(Path::new(DB_DIR)).unwrap();
+    assert!(files.iter().any(|name| name.ends_with(".sst")));
+    assert!(files.iter().any(|name| name.ends_with(".wal")));
+    assert!(files.iter().any(|name| name == "CURRENT"));
+    assert!(!Path::new(DB_DIR).exists());
+    assert!(MiniLsm::open(DB_DIR, options(&fs)).is_err());
+
+    storage.close().unwrap();
+    drop(storage);
+    // the files are shared by the clones of the file system, but not by another one
+    let storage = MiniLsm::open(DB_DIR, options(&fs)).unwrap();
+    assert_eq!(storage.get(&key_of(0)).unwrap(), None);
+    assert_eq!(storage.get(&key_of(1)).unwrap(), Some(Bytes::from("new")));
+    for i in 2..500 {
+        assert_eq!(
+            storage.get(&key_of(i)).unwrap(),
+            Some(Bytes::from(format!("value{}", i)))
+        );
+    }
+    let other = MiniLsm::open(DB_DIR, options(&MemFileSystem::new())).unwrap();
+    assert_eq!(other.get(&key_of(2)).unwrap(), None);
+}
+
+#[test]
+fn test_checkpoint_on_mem_fs() {
+    let fs = MemFileSystem::new();
+    let storage = MiniLsm::open(DB_DIR, options(&fs)).unwrap();
+    storage.put(b"a", b"1").unwrap();
+    storage.force_flush().unwrap();
+    storage.put(b"b", b"1").unwrap();
+    let checkpoint_dir = Path::new(DB_DIR).with_file_name("checkpoint");
+    storage.create_checkpoint(&checkpoint_dir).unwrap();
+    storage.put(b"c", b"1").unwrap();
+    assert!(!checkpoint_dir.exists());
+
+    let checkpoint = MiniLsm::open(&checkpoint_dir, options(&fs)).unwrap();
+    assert_eq!(checkpoint.get(b"a").unwrap(), Some(Bytes::from("1")));
+    assert_eq!(checkpoint.get(b"b").unwrap(), Some(Bytes::from("1")));
+    assert_eq!(checkpoint.get(b"c").unwrap(), None);
+}
+
+#[test]
+fn test_backup_on_mem_fs() {
+    let fs = MemFileSystem::new();
+    let storage = MiniLsm::open(DB_DIR, options(&fs)).unwrap();
+    storage.put(b"a", b"1").unwrap();
+    storage.force_flush().unwrap();
+    storage.put(b"b", b"1").unwrap();
+    let backup_dir = Path::new(DB_DIR).with_file_name("backup");
+    let backup_engine = BackupEngine::open(&backup_dir, &options(&fs)).unwrap();
+    backup_engine.create_backup(&storage).unwrap();
+    backup_engine.verify_backup(1).unwrap();
+    assert!(!backup_dir.exists());
+
+    let restore_dir = Path::new(DB_DIR).with_file_name("restore");
+    backup_engine.restore_backup(1, &restore_dir).unwrap();
+    let restored = MiniLsm::open(&restore_dir, options(&fs)).unwrap();
+    assert_eq!(restored.get(b"a").unwrap(), Some(Bytes::from("1")));
+    assert_eq!(restored.get(b"b").unwrap(), Some(Bytes::from("1")));
+
+    backup_engine.delete_backup(1).unwrap();
+    assert!(fs.list(&backup_dir.join("shared")).unwrap().is_empty());
+    assert!(fs
+        .list_dirs(&backup_dir.join("private"))
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn test_create_new_file() {
+    let fs = MemFileSystem::new();
+    let dir = Path::new(DB_DIR);
+    fs.create_dir_all(dir).unwrap();
+    let mut file = fs.create_new(&dir.join("00001.wal")).unwrap();
+    file.append(b"data").unwrap();
+    assert!(fs.create_new(&dir.join("00001.wal")).is_err());
+    assert_eq!(fs.read(&dir.join("00001.wal")).unwrap(), b"data");
+    assert!(fs.create_new(dir).is_err());
+}
@@ -0,0 +1,115 @@
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::{CompactionOptions, SimpleLeveledCompactionOptions},
+    env::{FileSystem, PosixFileSystem},
+    key::KeySlice,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+    table::{FileObject, SsTable, SsTableBuilder},
+};
+
+fn key_of(i: usize) -> Vec<u8> {
+    format!("key{:05}", i).into_bytes()
+}
+
+#[test]
+fn test_mmap_file_object() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("1.sst");
+    let mut builder = SsTableBuilder::new(128);
+    for i in 0..100 {
+        builder.add(KeySlice::from_slice(&key_of(i), 1), b"value");
+    }
+    builder.build_for_test(&path).unwrap();
+
+    let file = FileObject::open_mmap_with_fs(&PosixFileSystem, &path).unwrap();
+    assert_eq!(file.size(), PosixFileSystem.file_size(&path).unwrap());
+    let data = PosixFileSystem.read(&path).unwrap();
+    assert_eq!(file.read(10, 20).unwrap(), &data[10..30]);
+    // reads are slices of the mapping instead of copies
+    let first = file.read_bytes(10, 20).unwrap();
+    let second = file.read_bytes(10, 20).unwrap();
+    assert_eq!(first, &data[10..30]);
+    assert_eq!(first.as_ptr(), second.as_ptr());
+    assert!(file.read_bytes(file.size() - 10, 20).is_err());
+
+    // the mapping stays valid after the file is removed
+    let sst = SsTable::open(1, None, file).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    for idx in 0..sst.num_of_blocks() {
+        sst.read_block(idx).unwrap();
+    }
+}
+
+#[test]
+fn test_storage_with_mmap_reads() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::Simple(
+        SimpleLeveledCompactionOptions {
+            size_ratio_percent: 200,
+            level0_file_num_compaction_trigger: 2,
+            max_levels: 3,
+            level0_file_num_intra_compaction_trigger: None,
+        },
+    ));
+    options.enable_wal = true;
+    options.target_sst_size = 1024;
+    options.mmap_reads = true;
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    for i in 0..500 {
+        storage
+            .put(&key_of(i), format!("value{}", i).as_bytes())
+            .unwrap();
+        if i % 100 == 99 {
+            storage.force_flush().unwrap();
+        }
+    }
+    storage.inner.trigger_compaction().unwrap();
+    storage.delete(&key_of(0)).unwrap();
+    for i in 1..500 {
+        assert_eq!(
+            storage.get(&key_of(i)).unwrap(),
+            Some(Bytes::from(format!("value{}", i)))
+        );
+    }
+    storage.close().unwrap();
+    drop(storage);
+
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    assert_eq!(storage.get(&key_of(0)).unwrap(), None);
+    for i in 1..500 {
+        assert_eq!(
+            storage.get(&key_of(i)).unwrap(),
+            Some(Bytes::from(format!("value{}", i)))
+        );
+    }
+}
+
+#[test]
+fn test_cached_blocks_of_compacted_ssts_are_dropped() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.mmap_reads = true;
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    for i in 0..300 {
+        storage
+            .put(&key_of(i), format!("value{}", i).as_bytes())
+            .unwrap();
+        if i % 100 == 99 {
+            storage.force_flush().unwrap();
+        }
+    }
+    for i in 0..300 {
+        storage.get(&key_of(i)).unwrap();
+    }
+    assert!(storage.inner.block_cache.size() > 0);
+    storage.force_full_compaction().unwrap();
+    // the blocks of the compacted SSTs would keep their deleted files mapped
+    assert_eq!(storage.inner.block_cache.size(), 0);
+    assert_eq!(
+        storage.get(&key_of(0)).unwrap(),
+        Some(Bytes::from("value0"))
+    );
+    assert!(storage.inner.block_cache.size() > 0);
+}
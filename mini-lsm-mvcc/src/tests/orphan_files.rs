@@ -0,0 +1,121 @@
+use std::path::Path;
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    lsm_storage::{LsmStorageOptions, MiniLsm, OrphanFileMode},
+};
+
+const ORPHAN_FILES: [&str; 4] = ["99999.sst", "99998.wal", "MANIFEST-000099", "CURRENT.tmp"];
+
+fn options(orphan_file_mode: OrphanFileMode) -> LsmStorageOptions {
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    options.orphan_file_mode = orphan_file_mode;
+    options
+}
+
+/// Creates a storage with an SST and an unflushed write, and plants the orphan files next to them.
+fn create_storage_with_orphans(dir: &Path) -> Vec<String> {
+    let storage = MiniLsm::open(dir, options(OrphanFileMode::Delete)).unwrap();
+    storage.put(b"a", b"1").unwrap();
+    storage.force_flush().unwrap();
+    storage.put(b"b", b"2").unwrap();
+    storage.inner.sync().unwrap();
+    drop(storage);
+
+    let mut files = list_files(dir);
+    for name in ORPHAN_FILES {
+        std::fs::write(dir.join(name), b"orphan").unwrap();
+    }
+    std::fs::write(dir.join("notes.txt"), b"not a storage file").unwrap();
+    files.push("notes.txt".to_string());
+    files.sort();
+    files
+}
+
+fn list_files(dir: &Path) -> Vec<String> {
+    let mut files = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap())
+        .filter(|entry| entry.file_type().unwrap().is_file())
+        .map(|entry| entry.file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    files.sort();
+    files
+}
+
+fn check_data(storage: &MiniLsm) {
+    assert_eq!(storage.get(b"a").unwrap(), Some(Bytes::from("1")));
+    assert_eq!(storage.get(b"b").unwrap(), Some(Bytes::from("2")));
+}
+
+#[test]
+fn test_orphan_files_deleted() {
+    let dir = tempdir().unwrap();
+    let files = create_storage_with_orphans(dir.path());
+    let storage = MiniLsm::open(&dir, options(OrphanFileMode::Delete)).unwrap();
+    check_data(&storage);
+    let files_after_open = list_files(dir.path());
+    for name in ORPHAN_FILES {
+        assert!(!files_after_open.contains(&name.to_string()), "{}", name);
+    }
+    // the files of the storage are kept, plus the WAL of the new memtable
+    for name in &files {
+        assert!(files_after_open.contains(name), "{}", name);
+    }
+    assert_eq!(files_after_open.len(), files.len() + 1);
+}
+
+#[test]
+fn test_orphan_files_quarantined() {
+    let dir = tempdir().unwrap();
+    create_storage_with_orphans(dir.path());
+    let storage = MiniLsm::open(&dir, options(OrphanFileMode::Quarantine)).unwrap();
+    check_data(&storage);
+    let quarantine = dir.path().join("quarantine");
+    let mut expected = ORPHAN_FILES.map(|name| name.to_string()).to_vec();
+    expected.sort();
+    assert_eq!(list_files(&quarantine), expected);
+    for name in ORPHAN_FILES {
+        assert!(!dir.path().join(name).exists());
+    }
+    drop(storage);
+
+    // quarantined files are not touched again
+    let storage = MiniLsm::open(&dir, options(OrphanFileMode::Delete)).unwrap();
+    check_data(&storage);
+    assert_eq!(list_files(&quarantine), expected);
+}
+
+#[test]
+fn test_missing_referenced_files() {
+    let dir = tempdir().unwrap();
+    create_storage_with_orphans(dir.path());
+    let referenced = list_files(dir.path())
+        .into_iter()
+        .filter(|name| !ORPHAN_FILES.contains(&name.as_str()))
+        .filter(|name| name.ends_with(".sst") || name.ends_with(".wal"))
+        .collect::<Vec<_>>();
+    assert!(referenced.iter().any(|name| name.ends_with(".sst")));
+    assert!(referenced.iter().any(|name| name.ends_with(".wal")));
+    for name in &referenced {
+        std::fs::remove_file(dir.path().join(name)).unwrap();
+    }
+    let err = MiniLsm::open(&dir, options(OrphanFileMode::Delete))
+        .err()
+        .unwrap()
+        .to_string();
+    for name in &referenced {
+        assert!(
+            err.contains(name.as_str()),
+            "{} not reported in {}",
+            name,
+            err
+        );
+    }
+    // nothing is cleaned up when the storage cannot be opened
+    assert!(dir.path().join(ORPHAN_FILES[0]).exists());
+}
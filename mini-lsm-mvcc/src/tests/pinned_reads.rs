@@ -0,0 +1,96 @@
+use std::sync::Arc;
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    block::{Block, BlockBuilder, BlockIterator},
+    key::KeySlice,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+};
+
+fn contains(buf: &Bytes, slice: &Bytes) -> bool {
+    let range = buf.as_ptr_range();
+    range.start <= slice.as_ptr() && slice.as_ptr_range().end <= range.end
+}
+
+#[test]
+fn test_block_decode_shares_buffer() {
+    let mut builder = BlockBuilder::new(4096);
+    for i in 0..10 {
+        assert!(builder.add(
+            KeySlice::from_slice(format!("key{}", i).as_bytes(), 1),
+            format!("value{}", i).as_bytes()
+        ));
+    }
+    let encoded = builder.build().encode();
+    let block = Arc::new(Block::decode_bytes(encoded.clone()));
+    assert_eq!(block.encode(), encoded);
+
+    let mut iter = BlockIterator::create_and_seek_to_first(block);
+    for i in 0..10 {
+        let value = iter.value_bytes();
+        assert_eq!(value, format!("value{}", i));
+        assert!(contains(&encoded, &value));
+        iter.next();
+    }
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_get_shares_buffer() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week1_test();
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    for i in 0..100 {
+        storage
+            .put(format!("key{:03}", i).as_bytes(), b"flushed")
+            .unwrap();
+    }
+    storage.force_flush().unwrap();
+    storage.put(b"key000", b"memtable").unwrap();
+
+    // values in memtables are the buffers of the memtables
+    let first = storage.get(b"key000").unwrap().unwrap();
+    let second = storage.get(b"key000").unwrap().unwrap();
+    assert_eq!(first, Bytes::from("memtable"));
+    assert_eq!(first.as_ptr(), second.as_ptr());
+
+    // values in SSTs are slices of the cached blocks
+    let first = storage.get(b"key050").unwrap().unwrap();
+    let second = storage.get(b"key050").unwrap().unwrap();
+    assert_eq!(first, Bytes::from("flushed"));
+    assert_eq!(first.as_ptr(), second.as_ptr());
+}
+
+#[test]
+fn test_block_seek_matches_scan() {
+    let keys: Vec<(&[u8], u64)> = vec![
+        (b"a", 1),
+        (b"ab", 3),
+        (b"ab", 2),
+        (b"abc", 5),
+        (b"abd", 1),
+        (b"b", 4),
+        (b"bcd", 2),
+    ];
+    let mut builder = BlockBuilder::new(4096);
+    for (key, ts) in &keys {
+        assert!(builder.add(KeySlice::from_slice(key, *ts), b"value"));
+    }
+    let block = Arc::new(builder.build());
+    let targets: Vec<&[u8]> = vec![
+        b"", b"a", b"aa", b"ab", b"abc", b"abcd", b"abz", b"b", b"bc", b"c",
+    ];
+    for target in targets {
+        for ts in 0..7 {
+            let key = KeySlice::from_slice(target, ts);
+            let expected = keys
+                .iter()
+                .map(|(k, t)| KeySlice::from_slice(k, *t))
+                .find(|k| *k >= key);
+            let iter = BlockIterator::create_and_seek_to_key(block.clone(), key);
+            assert_eq!(iter.is_valid().then(|| iter.key()), expected);
+        }
+    }
+}
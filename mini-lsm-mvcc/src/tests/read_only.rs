@@ -0,0 +1,209 @@
+use std::ops::Bound;
+use std::path::Path;
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    lsm_storage::{LsmStorageInner, LsmStorageOptions, MiniLsm},
+};
+
+fn options() -> LsmStorageOptions {
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    options.block_size = 64;
+    options.target_sst_size = 512;
+    // memtables are only flushed by the test
+    options.num_memtable_limit = 1000;
+    options
+}
+
+fn key_of(i: usize) -> Vec<u8> {
+    format!("key{:03}", i).into_bytes()
+}
+
+/// The names, sizes and contents of all files in the directory.
+fn dir_contents(dir: &Path) -> Vec<(String, Vec<u8>)> {
+    let mut files = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.is_file())
+        .map(|path| {
+            (
+                path.file_name().unwrap().to_str().unwrap().to_string(),
+                std::fs::read(&path).unwrap(),
+            )
+        })
+        .collect::<Vec<_>>();
+    files.sort();
+    files
+}
+
+/// Creates a storage with a level of many SSTs, two L0 SSTs overwriting the first and last keys, and an unflushed
+/// write. Returns the SSTs in L1 and L0.
+fn create_storage(dir: &Path) -> (Vec<usize>, Vec<usize>) {
+    let storage = MiniLsm::open(dir, options()).unwrap();
+    for i in 0..300 {
+        storage.put(&key_of(i), b"v1").unwrap();
+    }
+    storage.force_flush().unwrap();
+    storage.inner.force_flush_imm_memtables().unwrap();
+    storage.force_full_compaction().unwrap();
+    storage.put(&key_of(0), b"v2").unwrap();
+    storage.force_flush().unwrap();
+    storage.put(&key_of(299), b"v2").unwrap();
+    storage.force_flush().unwrap();
+    storage.put(&key_of(150), b"v3").unwrap();
+    storage.close().unwrap();
+    let state = storage.inner.state.read();
+    (state.levels[0].1.clone(), state.l0_sstables.clone())
+}
+
+fn expected_value(i: usize) -> &'static str {
+    match i {
+        0 | 299 => "v2",
+        150 => "v3",
+        _ => "v1",
+    }
+}
+
+#[test]
+fn test_open_read_only() {
+    let dir = tempdir().unwrap();
+    create_storage(dir.path());
+    let contents = dir_contents(dir.path());
+
+    let storage = MiniLsm::open_read_only(dir.path(), options()).unwrap();
+    for i in 0..300 {
+        assert_eq!(
+            storage.get(&key_of(i)).unwrap(),
+            Some(Bytes::from(expected_value(i)))
+        );
+    }
+    assert!(storage.put(b"key", b"value").is_err());
+    assert!(storage.delete(&key_of(0)).is_err());
+    let txn = storage.new_txn().unwrap();
+    txn.put(b"key", b"value");
+    assert!(txn.commit().is_err());
+    assert!(storage.force_flush().is_err());
+    assert!(storage
+        .create_checkpoint(dir.path().join("checkpoint"))
+        .is_err());
+    storage.close().unwrap();
+    drop(storage);
+    assert_eq!(dir_contents(dir.path()), contents);
+
+    // the storage is still writable by a normal open
+    let storage = MiniLsm::open(dir.path(), options()).unwrap();
+    storage.put(b"key", b"value").unwrap();
+    assert_eq!(storage.get(&key_of(150)).unwrap(), Some(Bytes::from("v3")));
+}
+
+#[test]
+fn test_open_read_only_missing_dir() {
+    let dir = tempdir().unwrap();
+    assert!(MiniLsm::open_read_only(dir.path().join("db"), options()).is_err());
+    assert!(!dir.path().join("db").exists());
+}
+
+#[test]
+fn test_open_read_only_corrupted_wal_tail() {
+    let dir = tempdir().unwrap();
+    {
+        let storage = MiniLsm::open(dir.path(), options()).unwrap();
+        storage.put(b"a", b"1").unwrap();
+        storage.put(b"b", b"1").unwrap();
+        storage.close().unwrap();
+    }
+    let wal = std::fs::read_dir(dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "wal"))
+        .max_by_key(|path| std::fs::metadata(path).unwrap().len())
+        .unwrap();
+    let mut data = std::fs::read(&wal).unwrap();
+    let len = data.len();
+    data[len - 1] ^= 0xff;
+    std::fs::write(&wal, &data).unwrap();
+
+    let storage = MiniLsm::open_read_only(dir.path(), options()).unwrap();
+    assert_eq!(storage.get(b"a").unwrap(), Some(Bytes::from("1")));
+    assert_eq!(storage.get(b"b").unwrap(), None);
+    // the corrupted record is not truncated
+    assert_eq!(std::fs::read(&wal).unwrap(), data);
+}
+
+#[test]
+fn test_open_safe_mode() {
+    let dir = tempdir().unwrap();
+    let (l1_ssts, l0_ssts) = create_storage(dir.path());
+    assert!(l1_ssts.len() >= 8);
+    let sst_path = |id: usize| LsmStorageInner::path_of_sst_static(dir.path(), id);
+    // a corrupted block, a corrupted meta, and a missing L0 SST with the latest key299
+    let mut data = std::fs::read(sst_path(l1_ssts[2])).unwrap();
+    data[0] ^= 0xff;
+    std::fs::write(sst_path(l1_ssts[2]), data).unwrap();
+    std::fs::write(sst_path(l1_ssts[5]), b"bad").unwrap();
+    std::fs::remove_file(sst_path(l0_ssts[0])).unwrap();
+    assert!(MiniLsm::open(dir.path(), options()).is_err());
+    let contents = dir_contents(dir.path());
+
+    let storage = MiniLsm::open_safe_mode(dir.path(), options()).unwrap();
+    let unavailable_ssts = storage.unavailable_ssts().to_vec();
+    assert_eq!(unavailable_ssts.len(), 3);
+    let l0 = unavailable_ssts
+        .iter()
+        .find(|sst| sst.sst_id == l0_ssts[0])
+        .unwrap();
+    assert_eq!(l0.level, 0);
+    assert_eq!(
+        (l0.lower.clone(), l0.upper.clone()),
+        (Bound::Unbounded, Bound::Unbounded)
+    );
+    let block = unavailable_ssts
+        .iter()
+        .find(|sst| sst.sst_id == l1_ssts[2])
+        .unwrap();
+    assert_eq!(block.level, 1);
+    assert!(matches!(block.lower, Bound::Included(_)));
+    let meta = unavailable_ssts
+        .iter()
+        .find(|sst| sst.sst_id == l1_ssts[5])
+        .unwrap();
+    assert_eq!(meta.level, 1);
+    assert!(matches!(meta.lower, Bound::Excluded(_)));
+    assert!(matches!(meta.upper, Bound::Excluded(_)));
+
+    let in_range = |key: &[u8], lower: &Bound<Bytes>, upper: &Bound<Bytes>| {
+        let above = match lower {
+            Bound::Included(lower) => key >= &lower[..],
+            Bound::Excluded(lower) => key > &lower[..],
+            Bound::Unbounded => true,
+        };
+        let below = match upper {
+            Bound::Included(upper) => key <= &upper[..],
+            Bound::Excluded(upper) => key < &upper[..],
+            Bound::Unbounded => true,
+        };
+        above && below
+    };
+    let mut num_missing = 0;
+    for i in 0..300 {
+        let key = key_of(i);
+        let value = storage.get(&key).unwrap();
+        if in_range(&key, &block.lower, &block.upper) || in_range(&key, &meta.lower, &meta.upper) {
+            assert_eq!(value, None);
+            num_missing += 1;
+        } else if i == 299 {
+            // the older version in L1 is read instead
+            assert_eq!(value, Some(Bytes::from("v1")));
+        } else {
+            assert_eq!(value, Some(Bytes::from(expected_value(i))));
+        }
+    }
+    assert!(num_missing > 0);
+    assert!(storage.put(b"key", b"value").is_err());
+    drop(storage);
+    assert_eq!(dir_contents(dir.path()), contents);
+}
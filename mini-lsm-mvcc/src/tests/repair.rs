@@ -0,0 +1,150 @@
+use std::path::Path;
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::{CompactionOptions, SimpleLeveledCompactionOptions},
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+    repair::repair_db,
+};
+
+fn options(compaction_options: CompactionOptions) -> LsmStorageOptions {
+    let mut options = LsmStorageOptions::default_for_week2_test(compaction_options);
+    options.enable_wal = true;
+    options
+}
+
+fn simple_options() -> CompactionOptions {
+    CompactionOptions::Simple(SimpleLeveledCompactionOptions {
+        size_ratio_percent: 200,
+        level0_file_num_compaction_trigger: 2,
+        max_levels: 3,
+        level0_file_num_intra_compaction_trigger: None,
+    })
+}
+
+/// Creates a storage with a compacted run, overlapping L0 SSTs on top of it and an unflushed memtable, where
+/// each layer overwrites some keys of the layer below.
+fn create_storage(dir: &Path) {
+    let storage = MiniLsm::open(dir, options(CompactionOptions::NoCompaction)).unwrap();
+    for i in 0..100 {
+        storage
+            .put(format!("key{:03}", i).as_bytes(), b"v1")
+            .unwrap();
+    }
+    storage.force_flush().unwrap();
+    storage.delete(b"key010").unwrap();
+    storage.force_flush().unwrap();
+    storage.force_full_compaction().unwrap();
+    for i in 0..50 {
+        storage
+            .put(format!("key{:03}", i * 2).as_bytes(), b"v2")
+            .unwrap();
+    }
+    storage.force_flush().unwrap();
+    storage.put(b"key000", b"v3").unwrap();
+    storage.force_flush().unwrap();
+    storage.put(b"key001", b"v4").unwrap();
+    storage.inner.sync().unwrap();
+}
+
+fn check_data(storage: &MiniLsm) {
+    assert_eq!(storage.get(b"key000").unwrap(), Some(Bytes::from("v3")));
+    assert_eq!(storage.get(b"key001").unwrap(), Some(Bytes::from("v4")));
+    assert_eq!(storage.get(b"key002").unwrap(), Some(Bytes::from("v2")));
+    assert_eq!(storage.get(b"key003").unwrap(), Some(Bytes::from("v1")));
+    assert_eq!(storage.get(b"key010").unwrap(), Some(Bytes::from("v2")));
+    assert_eq!(storage.get(b"key011").unwrap(), Some(Bytes::from("v1")));
+    assert_eq!(storage.get(b"key099").unwrap(), Some(Bytes::from("v1")));
+}
+
+fn corrupt_manifest(dir: &Path) {
+    std::fs::write(dir.join("MANIFEST-000001"), b"not a manifest").unwrap();
+}
+
+#[test]
+fn test_repair_corrupted_manifest() {
+    let dir = tempdir().unwrap();
+    create_storage(dir.path());
+    corrupt_manifest(dir.path());
+    assert!(MiniLsm::open(&dir, options(CompactionOptions::NoCompaction)).is_err());
+
+    let report = repair_db(&dir, &options(CompactionOptions::NoCompaction)).unwrap();
+    assert_eq!(report.sst_ids.len(), 3);
+    assert!(report.corrupted_sst_ids.is_empty());
+    assert!(!report.wal_ids.is_empty());
+    assert!(dir.path().join("quarantine/MANIFEST-000001").exists());
+
+    let storage = MiniLsm::open(&dir, options(CompactionOptions::NoCompaction)).unwrap();
+    check_data(&storage);
+    storage.put(b"key100", b"v5").unwrap();
+    storage.force_flush().unwrap();
+    assert_eq!(storage.get(b"key100").unwrap(), Some(Bytes::from("v5")));
+    check_data(&storage);
+}
+
+#[test]
+fn test_repair_into_levels() {
+    let dir = tempdir().unwrap();
+    create_storage(dir.path());
+    for entry in std::fs::read_dir(&dir).unwrap() {
+        let path = entry.unwrap().path();
+        let name = path.file_name().unwrap().to_str().unwrap();
+        if name.starts_with("MANIFEST") || name == "CURRENT" {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    repair_db(&dir, &options(simple_options())).unwrap();
+    let storage = MiniLsm::open(&dir, options(simple_options())).unwrap();
+    {
+        let state = storage.inner.state.read();
+        // the compacted run goes to the bottom level and the overlapping SSTs above it
+        assert_eq!(state.levels.len(), 3);
+        assert_eq!(state.levels[2].1.len(), 1);
+        assert_eq!(state.levels[1].1.len(), 1);
+        assert_eq!(state.levels[0].1.len(), 1);
+        assert!(state.l0_sstables.is_empty());
+    }
+    check_data(&storage);
+}
+
+#[test]
+fn test_repair_quarantines_corrupted_sst() {
+    let dir = tempdir().unwrap();
+    create_storage(dir.path());
+    corrupt_manifest(dir.path());
+    // corrupt the SST holding the latest value of key000
+    let storage = {
+        let mut ssts = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "sst"))
+            .collect::<Vec<_>>();
+        ssts.sort_by_key(|path| {
+            path.file_stem()
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .parse::<usize>()
+                .unwrap()
+        });
+        let path = ssts.last().unwrap();
+        let mut data = std::fs::read(path).unwrap();
+        data[4] ^= 0xff;
+        std::fs::write(path, data).unwrap();
+        path.file_name().unwrap().to_owned()
+    };
+
+    let report = repair_db(&dir, &options(CompactionOptions::NoCompaction)).unwrap();
+    assert_eq!(report.sst_ids.len(), 2);
+    assert_eq!(report.corrupted_sst_ids.len(), 1);
+    assert!(dir.path().join("quarantine").join(&storage).exists());
+    assert!(!dir.path().join(&storage).exists());
+
+    let storage = MiniLsm::open(&dir, options(CompactionOptions::NoCompaction)).unwrap();
+    assert_eq!(storage.get(b"key000").unwrap(), Some(Bytes::from("v2")));
+    assert_eq!(storage.get(b"key001").unwrap(), Some(Bytes::from("v4")));
+    assert_eq!(storage.get(b"key003").unwrap(), Some(Bytes::from("v1")));
+}
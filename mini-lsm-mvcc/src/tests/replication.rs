@@ -0,0 +1,146 @@
+use std::{ops::Bound, time::Duration};
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::{CompactionOptions, SimpleLeveledCompactionOptions},
+    iterators::StorageIterator,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+    replication::{MiniLsmReplica, ReplicationPrimary, REPLICA_QUEUE_CAPACITY},
+};
+
+fn options() -> LsmStorageOptions {
+    LsmStorageOptions::default_for_week2_test(CompactionOptions::Simple(
+        SimpleLeveledCompactionOptions {
+            size_ratio_percent: 200,
+            level0_file_num_compaction_trigger: 2,
+            max_levels: 3,
+            level0_file_num_intra_compaction_trigger: None,
+        },
+    ))
+}
+
+fn scan_all(mut iter: impl for<'a> StorageIterator<KeyType<'a> = &'a [u8]>) -> Vec<(Bytes, Bytes)> {
+    let mut result = Vec::new();
+    while iter.is_valid() {
+        result.push((
+            Bytes::copy_from_slice(iter.key()),
+            Bytes::copy_from_slice(iter.value()),
+        ));
+        iter.next().unwrap();
+    }
+    result
+}
+
+fn assert_replicated(storage: &MiniLsm, replica: &MiniLsmReplica) {
+    let ts = storage.inner.mvcc().latest_commit_ts();
+    replica.wait_for_ts(ts, Duration::from_secs(10)).unwrap();
+    assert_eq!(replica.replicated_ts(), ts);
+    assert_eq!(
+        scan_all(replica.scan(Bound::Unbounded, Bound::Unbounded).unwrap()),
+        scan_all(storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap())
+    );
+}
+
+#[test]
+fn test_replication() {
+    let primary_dir = tempdir().unwrap();
+    let storage = MiniLsm::open(&primary_dir, options()).unwrap();
+    for i in 0..100 {
+        storage
+            .put(format!("key{:03}", i).as_bytes(), b"value0")
+            .unwrap();
+    }
+    storage.force_flush().unwrap();
+    storage.put(b"key000", b"in memtable").unwrap();
+    storage.delete(b"key001").unwrap();
+
+    let primary: ReplicationPrimary = storage.start_replication("127.0.0.1:0").unwrap();
+    let replica_dir = tempdir().unwrap();
+    let replica =
+        MiniLsmReplica::connect(primary.local_addr(), replica_dir.path(), options()).unwrap();
+    assert_replicated(&storage, &replica);
+    assert_eq!(
+        replica.get(b"key000").unwrap(),
+        Some(Bytes::from("in memtable"))
+    );
+    assert_eq!(replica.get(b"key001").unwrap(), None);
+
+    // writes, flushes and compactions after the snapshot are streamed
+    for round in 1..=4 {
+        for i in 0..100 {
+            storage
+                .put(
+                    format!("key{:03}", i).as_bytes(),
+                    format!("value{}", round).as_bytes(),
+                )
+                .unwrap();
+        }
+        storage
+            .delete(format!("key{:03}", round).as_bytes())
+            .unwrap();
+        storage.force_flush().unwrap();
+    }
+    for _ in 0..4 {
+        storage.inner.trigger_compaction().unwrap();
+    }
+    assert!(storage.inner.state.read().l0_sstables.len() < 4);
+    storage.put(b"key100", b"new").unwrap();
+    assert_replicated(&storage, &replica);
+    assert_eq!(replica.get(b"key100").unwrap(), Some(Bytes::from("new")));
+    assert_eq!(replica.get(b"key004").unwrap(), None);
+    assert_eq!(replica.get(b"key005").unwrap(), Some(Bytes::from("value4")));
+    assert!(replica.replication_error().is_none());
+
+    // the replica stops receiving updates after the primary stops
+    drop(primary);
+    storage.put(b"key101", b"new").unwrap();
+    let ts = storage.inner.mvcc().latest_commit_ts();
+    assert!(replica.wait_for_ts(ts, Duration::from_secs(10)).is_err());
+    assert_eq!(replica.get(b"key101").unwrap(), None);
+    replica.close().unwrap();
+
+    // a replica cannot bootstrap into a used directory
+    assert!(MiniLsmReplica::connect("127.0.0.1:1", replica_dir.path(), options()).is_err());
+}
+
+#[test]
+fn test_lagging_replica_is_disconnected() {
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(&dir, options()).unwrap();
+    let (_snapshot, rx) = storage.inner.add_replica().unwrap();
+    for i in 0..REPLICA_QUEUE_CAPACITY {
+        storage
+            .put(format!("key{}", i).as_bytes(), b"value")
+            .unwrap();
+    }
+    assert_eq!(storage.inner.replicas.lock().len(), 1);
+    // the replica never reads its updates, so the next one is dropped with the replica
+    storage.put(b"key", b"value").unwrap();
+    assert!(storage.inner.replicas.lock().is_empty());
+    assert_eq!(rx.len(), REPLICA_QUEUE_CAPACITY);
+}
+
+#[test]
+fn test_replica_update_holds_compacted_ssts() {
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(
+        &dir,
+        LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction),
+    )
+    .unwrap();
+    let (_snapshot, rx) = storage.inner.add_replica().unwrap();
+    storage.put(b"key", b"value").unwrap();
+    storage.force_flush().unwrap();
+    let flushed_sst = storage.inner.state.read().l0_sstables[0];
+    let flushed_sst_size = storage.inner.state.read().sstables[&flushed_sst].table_size();
+    // the flushed SST is compacted away before its update is sent to the replica
+    storage.force_full_compaction().unwrap();
+    assert!(!storage.inner.path_of_sst(flushed_sst).exists());
+    let mut data = Vec::new();
+    for update in rx.try_iter() {
+        update.write_to(&mut data).unwrap();
+    }
+    assert!(data.len() as u64 > flushed_sst_size);
+}
@@ -0,0 +1,98 @@
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::{CompactionOptions, SimpleLeveledCompactionOptions},
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+};
+
+fn options() -> LsmStorageOptions {
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    options
+}
+
+#[test]
+fn test_secondary_catch_up() {
+    let dir = tempdir().unwrap();
+    let primary = MiniLsm::open(&dir, options()).unwrap();
+    primary.put(b"a", b"1").unwrap();
+    primary.force_flush().unwrap();
+    primary.put(b"b", b"1").unwrap();
+    primary.sync().unwrap();
+
+    let secondary = MiniLsm::open_as_secondary(&dir, options()).unwrap();
+    assert_eq!(secondary.get(b"a").unwrap(), Some(Bytes::from("1")));
+    assert_eq!(secondary.get(b"b").unwrap(), Some(Bytes::from("1")));
+    assert!(secondary.put(b"c", b"1").is_err());
+
+    primary.put(b"a", b"2").unwrap();
+    primary.put(b"c", b"2").unwrap();
+    primary.force_flush().unwrap();
+    primary.force_full_compaction().unwrap();
+    primary.delete(b"b").unwrap();
+    primary.sync().unwrap();
+    // the secondary keeps its state, and the SSTs deleted by the compaction stay readable
+    assert_eq!(secondary.get(b"a").unwrap(), Some(Bytes::from("1")));
+    assert_eq!(secondary.get(b"c").unwrap(), None);
+
+    secondary.try_catch_up_with_primary().unwrap();
+    assert_eq!(secondary.get(b"a").unwrap(), Some(Bytes::from("2")));
+    assert_eq!(secondary.get(b"b").unwrap(), None);
+    assert_eq!(secondary.get(b"c").unwrap(), Some(Bytes::from("2")));
+
+    // writes in the active memtable of the primary
+    let snapshot = secondary.new_txn().unwrap();
+    primary.put(b"a", b"3").unwrap();
+    primary.put(b"d", b"3").unwrap();
+    primary.sync().unwrap();
+    secondary.try_catch_up_with_primary().unwrap();
+    assert_eq!(secondary.get(b"a").unwrap(), Some(Bytes::from("3")));
+    assert_eq!(secondary.get(b"d").unwrap(), Some(Bytes::from("3")));
+    // a snapshot taken before the catch-up still reads the old versions
+    assert_eq!(snapshot.get(b"a").unwrap(), Some(Bytes::from("2")));
+    assert_eq!(snapshot.get(b"d").unwrap(), None);
+    secondary.close().unwrap();
+}
+
+#[test]
+fn test_secondary_catch_up_with_manifest_rotation() {
+    let dir = tempdir().unwrap();
+    let mut options = options();
+    options.manifest_max_size = 256;
+    options.compaction_options = CompactionOptions::Simple(SimpleLeveledCompactionOptions {
+        size_ratio_percent: 200,
+        level0_file_num_compaction_trigger: 2,
+        max_levels: 3,
+        level0_file_num_intra_compaction_trigger: None,
+    });
+    let primary = MiniLsm::open(&dir, options.clone()).unwrap();
+    let secondary = MiniLsm::open_as_secondary(&dir, options.clone()).unwrap();
+    for round in 0..10 {
+        for i in 0..20 {
+            primary
+                .put(
+                    format!("key{:02}", i).as_bytes(),
+                    format!("value{}", round).as_bytes(),
+                )
+                .unwrap();
+        }
+        primary.force_flush().unwrap();
+        primary.inner.trigger_compaction().unwrap();
+        primary.sync().unwrap();
+        secondary.try_catch_up_with_primary().unwrap();
+        for i in 0..20 {
+            assert_eq!(
+                secondary.get(format!("key{:02}", i).as_bytes()).unwrap(),
+                Some(Bytes::from(format!("value{}", round)))
+            );
+        }
+    }
+}
+
+#[test]
+fn test_secondary_missing_dir() {
+    let dir = tempdir().unwrap();
+    assert!(MiniLsm::open_as_secondary(dir.path().join("db"), options()).is_err());
+    assert!(!dir.path().join("db").exists());
+}
@@ -0,0 +1,125 @@
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    lsm_storage::{LsmStorageOptions, MiniLsm, WriteBatchRecord},
+};
+
+/// A committed batch as `(commit_ts, [(key, value or None for deletes)])`.
+type Update = (u64, Vec<(Bytes, Option<Bytes>)>);
+
+fn collect_updates(storage: &MiniLsm, ts: u64) -> anyhow::Result<Vec<Update>> {
+    let mut updates = Vec::new();
+    for batch in storage.updates_since(ts)? {
+        let batch = batch?;
+        let records = batch
+            .records
+            .into_iter()
+            .map(|record| match record {
+                WriteBatchRecord::Put(key, value) => (key, Some(value)),
+                WriteBatchRecord::Del(key) => (key, None),
+            })
+            .collect();
+        updates.push((batch.commit_ts, records));
+    }
+    Ok(updates)
+}
+
+fn options_with_wal_retention(wal_retention_size: usize) -> LsmStorageOptions {
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    options.wal_retention_size = wal_retention_size;
+    options
+}
+
+#[test]
+fn test_updates_since() {
+    let dir = tempdir().unwrap();
+    let options = options_with_wal_retention(1 << 20);
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"a", b"1").unwrap();
+    storage
+        .write_batch(&[
+            WriteBatchRecord::Put(b"b", b"2"),
+            WriteBatchRecord::Put(b"c", b"2"),
+        ])
+        .unwrap();
+    storage.force_flush().unwrap();
+    storage.delete(b"a").unwrap();
+    storage.put(b"d", b"4").unwrap();
+
+    let expected = vec![
+        (1, vec![(Bytes::from("a"), Some(Bytes::from("1")))]),
+        (
+            2,
+            vec![
+                (Bytes::from("b"), Some(Bytes::from("2"))),
+                (Bytes::from("c"), Some(Bytes::from("2"))),
+            ],
+        ),
+        (3, vec![(Bytes::from("a"), None)]),
+        (4, vec![(Bytes::from("d"), Some(Bytes::from("4")))]),
+    ];
+    assert_eq!(collect_updates(&storage, 0).unwrap(), expected);
+    assert_eq!(collect_updates(&storage, 2).unwrap(), expected[2..]);
+    assert!(collect_updates(&storage, 4).unwrap().is_empty());
+    storage.close().unwrap();
+    drop(storage);
+
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    assert_eq!(collect_updates(&storage, 0).unwrap(), expected);
+    storage.put(b"e", b"5").unwrap();
+    assert_eq!(
+        collect_updates(&storage, 4).unwrap(),
+        vec![(5, vec![(Bytes::from("e"), Some(Bytes::from("5")))])]
+    );
+}
+
+#[test]
+fn test_updates_since_discarded() {
+    let dir = tempdir().unwrap();
+    // the archived WALs never fit, so they are deleted after the flush
+    let storage = MiniLsm::open(&dir, options_with_wal_retention(1)).unwrap();
+    storage.put(b"a", b"1").unwrap();
+    storage.put(b"b", b"2").unwrap();
+    storage.force_flush().unwrap();
+    assert!(collect_updates(&storage, 0).is_err());
+    assert!(collect_updates(&storage, 2).unwrap().is_empty());
+    storage.put(b"c", b"3").unwrap();
+    assert!(collect_updates(&storage, 1).is_err());
+    assert_eq!(
+        collect_updates(&storage, 2).unwrap(),
+        vec![(3, vec![(Bytes::from("c"), Some(Bytes::from("3")))])]
+    );
+
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(
+        &dir,
+        LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction),
+    )
+    .unwrap();
+    assert!(storage.updates_since(0).is_err());
+}
+
+#[test]
+fn test_updates_since_commit_ts_gap() {
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(&dir, options_with_wal_retention(1)).unwrap();
+    storage.put(b"a", b"1").unwrap();
+    storage.put(b"b", b"2").unwrap();
+    storage.force_flush().unwrap();
+    // commit timestamps taken without writing a batch to the new WAL
+    storage.inner.mvcc().update_commit_ts(5);
+    storage.put(b"c", b"6").unwrap();
+    let expected = vec![(6, vec![(Bytes::from("c"), Some(Bytes::from("6")))])];
+    assert_eq!(collect_updates(&storage, 2).unwrap(), expected);
+    assert_eq!(collect_updates(&storage, 4).unwrap(), expected);
+    assert!(collect_updates(&storage, 1).is_err());
+    storage.close().unwrap();
+    drop(storage);
+
+    let storage = MiniLsm::open(&dir, options_with_wal_retention(1)).unwrap();
+    assert_eq!(collect_updates(&storage, 2).unwrap(), expected);
+    assert!(collect_updates(&storage, 1).is_err());
+}
@@ -0,0 +1,122 @@
+use bytes::Bytes;
+use crossbeam_skiplist::SkipMap;
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    env::PosixFileSystem,
+    key::KeySlice,
+    lsm_storage::{LsmStorageOptions, MiniLsm, WriteBatchRecord},
+    wal::{Wal, WalRecoveryMode},
+};
+
+#[test]
+fn test_wal_recover_whole_batches() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("full.wal");
+    let wal = Wal::create(&PosixFileSystem, &path).unwrap();
+    wal.put_batch(&[
+        (KeySlice::for_testing_from_slice_with_ts(b"a", 1), b"1"),
+        (KeySlice::for_testing_from_slice_with_ts(b"b", 1), b"1"),
+        (KeySlice::for_testing_from_slice_with_ts(b"c", 1), b""),
+    ])
+    .unwrap();
+    wal.put_batch(&[
+        (KeySlice::for_testing_from_slice_with_ts(b"a", 2), b"2"),
+        (KeySlice::for_testing_from_slice_with_ts(b"d", 2), b"2"),
+    ])
+    .unwrap();
+    wal.sync().unwrap();
+    assert!(wal
+        .put_batch(&[
+            (KeySlice::for_testing_from_slice_with_ts(b"a", 3), b"3"),
+            (KeySlice::for_testing_from_slice_with_ts(b"b", 4), b"4"),
+        ])
+        .is_err());
+    drop(wal);
+    let data = std::fs::read(&path).unwrap();
+
+    // simulate a crash at every point of writing the WAL
+    let mut recovered_sizes = Vec::new();
+    for len in 0..=data.len() {
+        let path = dir.path().join(format!("{}.wal", len));
+        std::fs::write(&path, &data[..len]).unwrap();
+        let map = SkipMap::new();
+        let (wal, _) =
+            Wal::recover(&PosixFileSystem, &path, &map, WalRecoveryMode::default()).unwrap();
+        assert!(
+            [0, 3, 5].contains(&map.len()),
+            "recovered part of a batch at length {}",
+            len
+        );
+        recovered_sizes.push(map.len());
+
+        // new batches can be appended after the incomplete batch is dropped
+        wal.put_batch(&[(KeySlice::for_testing_from_slice_with_ts(b"e", 5), b"5")])
+            .unwrap();
+        wal.sync().unwrap();
+        drop(wal);
+        let map_after_write = SkipMap::new();
+        Wal::recover(
+            &PosixFileSystem,
+            &path,
+            &map_after_write,
+            WalRecoveryMode::default(),
+        )
+        .unwrap();
+        assert_eq!(map_after_write.len(), map.len() + 1);
+    }
+    assert_eq!(recovered_sizes.first(), Some(&0));
+    assert_eq!(recovered_sizes.last(), Some(&5));
+
+    // a corrupted batch is rejected
+    let mut corrupted = data.clone();
+    *corrupted.last_mut().unwrap() ^= 1;
+    let path = dir.path().join("corrupted.wal");
+    std::fs::write(&path, &corrupted).unwrap();
+    assert!(Wal::recover(
+        &PosixFileSystem,
+        &path,
+        &SkipMap::new(),
+        WalRecoveryMode::AbsoluteConsistency
+    )
+    .is_err());
+}
+
+#[test]
+fn test_write_batch_atomic_on_recovery() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage
+        .write_batch(&[
+            WriteBatchRecord::Put(b"a", b"1"),
+            WriteBatchRecord::Put(b"b", b"1"),
+        ])
+        .unwrap();
+    let wal_path = storage
+        .inner
+        .path_of_wal(storage.inner.state.read().memtable.id());
+    storage.inner.sync().unwrap();
+    let first_batch_len = std::fs::metadata(&wal_path).unwrap().len();
+    storage
+        .write_batch(&[
+            WriteBatchRecord::Put(b"a", b"2"),
+            WriteBatchRecord::Del(b"b"),
+            WriteBatchRecord::Put(b"c", b"2"),
+        ])
+        .unwrap();
+    storage.close().unwrap();
+    drop(storage);
+
+    // crash in the middle of writing the second batch
+    let data = std::fs::read(&wal_path).unwrap();
+    let len = (first_batch_len as usize + data.len()) / 2;
+    std::fs::write(&wal_path, &data[..len]).unwrap();
+
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    assert_eq!(storage.get(b"a").unwrap(), Some(Bytes::from("1")));
+    assert_eq!(storage.get(b"b").unwrap(), Some(Bytes::from("1")));
+    assert_eq!(storage.get(b"c").unwrap(), None);
+}
@@ -0,0 +1,177 @@
+use std::{path::Path, sync::Arc};
+
+use bytes::Bytes;
+use crossbeam_skiplist::SkipMap;
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    env::PosixFileSystem,
+    key::{KeyBytes, KeySlice},
+    lsm_storage::{LsmStorageInner, LsmStorageOptions},
+    wal::{Wal, WalRecoveryMode},
+};
+
+const ALL_MODES: [WalRecoveryMode; 3] = [
+    WalRecoveryMode::TolerateCorruptedTailRecords,
+    WalRecoveryMode::AbsoluteConsistency,
+    WalRecoveryMode::PointInTime,
+];
+
+/// Writes 3 batches with 2, 1 and 2 entries, and returns the WAL content and the end offsets of the header and each
+/// batch.
+fn generate_wal(path: &Path) -> (Vec<u8>, Vec<usize>) {
+    let wal = Wal::create(&PosixFileSystem, path).unwrap();
+    wal.sync().unwrap();
+    let mut batch_ends = vec![std::fs::metadata(path).unwrap().len() as usize];
+    let batches: [&[(&[u8], &[u8])]; 3] = [
+        &[(b"a", b"1"), (b"b", b"1")],
+        &[(b"c", b"2")],
+        &[(b"a", b"3"), (b"d", b"")],
+    ];
+    for (ts, batch) in (1..).zip(batches) {
+        let data = batch
+            .iter()
+            .map(|(key, value)| (KeySlice::for_testing_from_slice_with_ts(key, ts), *value))
+            .collect::<Vec<_>>();
+        wal.put_batch(&data).unwrap();
+        wal.sync().unwrap();
+        batch_ends.push(std::fs::metadata(path).unwrap().len() as usize);
+    }
+    (std::fs::read(path).unwrap(), batch_ends)
+}
+
+fn recover(path: &Path, data: &[u8], mode: WalRecoveryMode) -> anyhow::Result<usize> {
+    std::fs::write(path, data).unwrap();
+    let map = SkipMap::<KeyBytes, Bytes>::new();
+    let (_, fully_recovered) = Wal::recover(&PosixFileSystem, path, &map, mode)?;
+    // the header of a WAL torn while it is created is completed
+    assert_eq!(
+        fully_recovered,
+        std::fs::read(path).unwrap().starts_with(data)
+    );
+    Ok(map.len())
+}
+
+#[test]
+fn test_wal_truncated_at_every_offset() {
+    let dir = tempdir().unwrap();
+    let (data, batch_ends) = generate_wal(&dir.path().join("full.wal"));
+    let entries_before = |len: usize| match batch_ends.iter().filter(|end| **end <= len).count() {
+        0 | 1 => 0,
+        2 => 2,
+        3 => 3,
+        _ => 5,
+    };
+    let path = dir.path().join("truncated.wal");
+    for len in 0..=data.len() {
+        let at_batch_boundary = len <= batch_ends[0] || batch_ends.contains(&len);
+        for mode in ALL_MODES {
+            let result = recover(&path, &data[..len], mode);
+            if mode == WalRecoveryMode::AbsoluteConsistency && !at_batch_boundary {
+                assert!(result.is_err(), "{:?} recovered a torn WAL", mode);
+                continue;
+            }
+            assert_eq!(
+                result.unwrap(),
+                entries_before(len),
+                "{:?} at {}",
+                mode,
+                len
+            );
+            // the incomplete batch is removed from the file
+            let expected_len = batch_ends
+                .iter()
+                .copied()
+                .filter(|end| *end <= len)
+                .max()
+                .unwrap_or(batch_ends[0]);
+            assert_eq!(
+                std::fs::metadata(&path).unwrap().len() as usize,
+                expected_len
+            );
+        }
+    }
+}
+
+#[test]
+fn test_wal_corrupted_record() {
+    let dir = tempdir().unwrap();
+    let (data, batch_ends) = generate_wal(&dir.path().join("full.wal"));
+    let path = dir.path().join("corrupted.wal");
+
+    // corruption in the last batch is treated as a torn tail
+    let mut corrupted = data.clone();
+    corrupted[batch_ends[2] + 6] ^= 1;
+    assert_eq!(
+        recover(
+            &path,
+            &corrupted,
+            WalRecoveryMode::TolerateCorruptedTailRecords
+        )
+        .unwrap(),
+        3
+    );
+    assert_eq!(
+        recover(&path, &corrupted, WalRecoveryMode::PointInTime).unwrap(),
+        3
+    );
+    assert!(recover(&path, &corrupted, WalRecoveryMode::AbsoluteConsistency).is_err());
+
+    // corruption in the middle is only recovered by point-in-time recovery
+    let mut corrupted = data.clone();
+    corrupted[batch_ends[1] + 6] ^= 1;
+    assert!(recover(
+        &path,
+        &corrupted,
+        WalRecoveryMode::TolerateCorruptedTailRecords
+    )
+    .is_err());
+    assert_eq!(
+        recover(&path, &corrupted, WalRecoveryMode::PointInTime).unwrap(),
+        2
+    );
+    assert_eq!(
+        std::fs::metadata(&path).unwrap().len() as usize,
+        batch_ends[1]
+    );
+    assert!(recover(&path, &corrupted, WalRecoveryMode::AbsoluteConsistency).is_err());
+}
+
+#[test]
+fn test_point_in_time_recovery_drops_later_wals() {
+    for mode in [
+        WalRecoveryMode::TolerateCorruptedTailRecords,
+        WalRecoveryMode::PointInTime,
+    ] {
+        let dir = tempdir().unwrap();
+        let mut options =
+            LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+        options.enable_wal = true;
+        options.wal_recovery_mode = mode;
+        let storage = Arc::new(LsmStorageInner::open(&dir, options.clone()).unwrap());
+        storage.put(b"a", b"1").unwrap();
+        storage.put(b"b", b"1").unwrap();
+        let first_wal = storage.path_of_wal(storage.state.read().memtable.id());
+        storage
+            .force_freeze_memtable(&storage.state_lock.lock())
+            .unwrap();
+        storage.put(b"c", b"2").unwrap();
+        storage.sync().unwrap();
+        drop(storage);
+
+        // tear the tail of the first WAL
+        let data = std::fs::read(&first_wal).unwrap();
+        std::fs::write(&first_wal, &data[..data.len() - 1]).unwrap();
+
+        let storage = Arc::new(LsmStorageInner::open(&dir, options).unwrap());
+        assert_eq!(storage.get(b"a").unwrap(), Some(Bytes::from("1")));
+        assert_eq!(storage.get(b"b").unwrap(), None);
+        let c = storage.get(b"c").unwrap();
+        if mode == WalRecoveryMode::PointInTime {
+            assert_eq!(c, None);
+        } else {
+            assert_eq!(c, Some(Bytes::from("2")));
+        }
+    }
+}
@@ -1,94 +1,492 @@
-use std::fs::{File, OpenOptions};
+use std::collections::VecDeque;
 use std::hash::Hasher;
-use std::io::{BufWriter, Read, Write};
 use std::path::Path;
 use std::sync::Arc;
 
-use anyhow::{bail, Context, Result};
+use anyhow::{bail, ensure, Context, Result};
 use bytes::{Buf, BufMut, Bytes};
 use crossbeam_skiplist::SkipMap;
 use parking_lot::Mutex;
 
+use crate::env::{FileSystem, RandomAccessFile, WritableFile};
 use crate::key::{KeyBytes, KeySlice};
+use crate::lsm_storage::WriteBatchRecord;
+
+const LEN_SIZE: usize = std::mem::size_of::<u32>();
+const CHECKSUM_SIZE: usize = std::mem::size_of::<u32>();
+/// The body of the record written by `Wal::put_start_ts`: a zero count and the ts.
+const START_TS_BODY_SIZE: usize = std::mem::size_of::<u32>() + std::mem::size_of::<u64>();
+
+/// Written at the beginning of each WAL with batch records: a magic number and the format version. WALs
+/// without it are written before batch records, with one record per key.
+const WAL_HEADER: &[u8] = b"MLSMWAL\x01";
+
+/// The record format of a WAL file.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum WalFormat {
+    /// `| key_len (u16) | key | ts (u64) | value_len (u16) | value | checksum (u32) |` for each key, without a
+    /// header.
+    Legacy,
+    /// Batch records after `WAL_HEADER`.
+    Batch,
+}
+
+impl WalFormat {
+    /// Detect the format of the WAL in the buffer, and return it with the offset of the first record. A
+    /// WAL with an incomplete header is left by a crash in the middle of creating it, and has no records.
+    fn detect(buf: &[u8]) -> (Self, usize) {
+        if buf.starts_with(WAL_HEADER) {
+            (Self::Batch, WAL_HEADER.len())
+        } else if WAL_HEADER.starts_with(buf) {
+            (Self::Batch, buf.len())
+        } else {
+            (Self::Legacy, 0)
+        }
+    }
+
+    /// The length of the record at the beginning of the buffer, if its header is complete.
+    fn record_len(self, buf: &[u8]) -> Option<usize> {
+        match self {
+            Self::Legacy => {
+                let key_len = buf.get(..2)?.get_u16() as usize;
+                let value_len_offset = 2 + key_len + std::mem::size_of::<u64>();
+                let value_len = buf.get(value_len_offset..value_len_offset + 2)?.get_u16() as usize;
+                Some(value_len_offset + 2 + value_len + CHECKSUM_SIZE)
+            }
+            Self::Batch => {
+                let mut header = buf.get(..LEN_SIZE)?;
+                Some(LEN_SIZE + header.get_u32() as usize + CHECKSUM_SIZE)
+            }
+        }
+    }
+
+    /// Decode the record at the beginning of the buffer, and return its length and entries.
+    fn decode_record(self, buf: &[u8]) -> Result<(usize, Vec<(KeyBytes, Bytes)>)> {
+        match self {
+            Self::Legacy => Wal::decode_legacy_record(buf),
+            Self::Batch => Wal::decode_batch(buf),
+        }
+    }
+}
+
+/// How to handle incomplete or corrupted records when recovering from WALs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WalRecoveryMode {
+    /// Drop an incomplete or corrupted record at the end of a WAL, which is left by a crash in the middle of
+    /// a write, and fail on corruptions elsewhere.
+    #[default]
+    TolerateCorruptedTailRecords,
+    /// Fail on any incomplete or corrupted record.
+    AbsoluteConsistency,
+    /// Stop at the first incomplete or corrupted record, and drop everything after it, including the
+    /// later WALs, so that the recovered state is consistent as of some point in time.
+    PointInTime,
+}
 
 pub struct Wal {
-    file: Arc<Mutex<BufWriter<File>>>,
+    file: Arc<Mutex<Box<dyn WritableFile>>>,
 }
 
 impl Wal {
-    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+    pub fn create(fs: &dyn FileSystem, path: impl AsRef<Path>) -> Result<Self> {
+        let mut file = fs
+            .create_new(path.as_ref())
+            .context("failed to create WAL")?;
+        file.append(WAL_HEADER)?;
         Ok(Self {
-            file: Arc::new(Mutex::new(BufWriter::new(
-                OpenOptions::new()
-                    .read(true)
-                    .create_new(true)
-                    .write(true)
-                    .open(path)
-                    .context("failed to create WAL")?,
-            ))),
+            file: Arc::new(Mutex::new(file)),
         })
     }
 
-    pub fn recover(path: impl AsRef<Path>, skiplist: &SkipMap<KeyBytes, Bytes>) -> Result<Self> {
+    /// Recover the batches in the WAL into the skiplist. Returns the WAL and whether all records in the
+    /// file are recovered. Records that are not recovered are removed from the file, so that new batches
+    /// are not appended after them. A WAL written before batch records is rewritten with batch records
+    /// after it is recovered.
+    pub fn recover(
+        fs: &dyn FileSystem,
+        path: impl AsRef<Path>,
+        skiplist: &SkipMap<KeyBytes, Bytes>,
+        mode: WalRecoveryMode,
+    ) -> Result<(Self, bool)> {
         let path = path.as_ref();
-        let mut file = OpenOptions::new()
-            .read(true)
-            .append(true)
-            .open(path)
-            .context("failed to recover from WAL")?;
-        let mut buf = Vec::new();
-        file.read_to_end(&mut buf)?;
-        let mut rbuf: &[u8] = buf.as_slice();
-        while rbuf.has_remaining() {
-            let mut hasher = crc32fast::Hasher::new();
-            let key_len = rbuf.get_u16() as usize;
-            hasher.write_u16(key_len as u16);
-            let key = Bytes::copy_from_slice(&rbuf[..key_len]);
-            hasher.write(&key);
-            rbuf.advance(key_len);
-            let ts = rbuf.get_u64();
-            hasher.write_u64(ts);
-            let value_len = rbuf.get_u16() as usize;
-            hasher.write_u16(value_len as u16);
-            let value = Bytes::copy_from_slice(&rbuf[..value_len]);
-            hasher.write(&value);
-            rbuf.advance(value_len);
-            let checksum = rbuf.get_u32();
-            if hasher.finalize() != checksum {
-                bail!("checksum mismatch");
+        let buf = fs.read(path).context("failed to recover from WAL")?;
+        let (format, offset) = Self::replay(path, &buf, skiplist, mode)?;
+        let fully_recovered = offset == buf.len();
+        if format == WalFormat::Legacy {
+            Self::rewrite_legacy(fs, path, &buf[..offset])?;
+        } else if offset < WAL_HEADER.len() {
+            // complete the header of a WAL torn while it is created, so that batches can be appended to it
+            fs.truncate(path, 0)?;
+            let mut file = fs.open_append(path)?;
+            file.append(WAL_HEADER)?;
+            file.sync()?;
+        } else if !fully_recovered {
+            fs.truncate(path, offset as u64)?;
+        }
+        Ok((
+            Self {
+                file: Arc::new(Mutex::new(fs.open_append(path)?)),
+            },
+            fully_recovered,
+        ))
+    }
+
+    /// Read the batches in the WAL into the skiplist like `recover`, but leave the file untouched. Returns
+    /// whether all records in the file are read.
+    pub fn read(
+        fs: &dyn FileSystem,
+        path: impl AsRef<Path>,
+        skiplist: &SkipMap<KeyBytes, Bytes>,
+        mode: WalRecoveryMode,
+    ) -> Result<bool> {
+        let path = path.as_ref();
+        let buf = fs.read(path).context("failed to read WAL")?;
+        let (_, offset) = Self::replay(path, &buf, skiplist, mode)?;
+        Ok(offset == buf.len())
+    }
+
+    /// Replace a WAL written before batch records with one batch record for each timestamp of its
+    /// records. The new WAL is written next to it and renamed over it, so that a crash leaves either of them.
+    fn rewrite_legacy(fs: &dyn FileSystem, path: &Path, buf: &[u8]) -> Result<()> {
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        while offset < buf.len() {
+            let (record_len, mut record) = Self::decode_legacy_record(&buf[offset..])?;
+            entries.append(&mut record);
+            offset += record_len;
+        }
+        let mut data = WAL_HEADER.to_vec();
+        for batch in entries.chunk_by(|(a, _), (b, _)| a.ts() == b.ts()) {
+            let batch = batch
+                .iter()
+                .map(|(key, value)| (key.as_key_slice(), &value[..]))
+                .collect::<Vec<_>>();
+            Self::encode_batch(&batch, &mut data)?;
+        }
+        let tmp_path = path.with_extension("wal.tmp");
+        fs.write(&tmp_path, &data)?;
+        fs.rename(&tmp_path, path)?;
+        if let Some(dir) = path.parent() {
+            fs.sync_dir(dir)?;
+        }
+        Ok(())
+    }
+
+    /// Insert the records in the buffer into the skiplist, and return the format of the WAL and the offset of
+    /// the first record not recovered.
+    fn replay(
+        path: &Path,
+        buf: &[u8],
+        skiplist: &SkipMap<KeyBytes, Bytes>,
+        mode: WalRecoveryMode,
+    ) -> Result<(WalFormat, usize)> {
+        let (format, mut offset) = WalFormat::detect(buf);
+        while offset < buf.len() {
+            let (record_len, batch) = match format.decode_record(&buf[offset..]) {
+                Ok(record) => record,
+                Err(e) => {
+                    // a record reaching the end of the file is the one being written when the crash happened
+                    let is_tail = format
+                        .record_len(&buf[offset..])
+                        .is_none_or(|record_len| offset + record_len >= buf.len());
+                    if mode == WalRecoveryMode::AbsoluteConsistency
+                        || (mode == WalRecoveryMode::TolerateCorruptedTailRecords && !is_tail)
+                    {
+                        return Err(e.context(format!(
+                            "corrupted WAL record at offset {} of {}",
+                            offset,
+                            path.display()
+                        )));
+                    }
+                    eprintln!(
+                        "dropping WAL records after offset {} of {}: {:#}",
+                        offset,
+                        path.display(),
+                        e
+                    );
+                    break;
+                }
+            };
+            for (key, value) in batch {
+                skiplist.insert(key, value);
             }
-            skiplist.insert(KeyBytes::from_bytes_with_ts(key, ts), value);
+            offset += record_len;
         }
-        Ok(Self {
-            file: Arc::new(Mutex::new(BufWriter::new(file))),
-        })
+        Ok((format, offset))
+    }
+
+    /// Decode the batch record at the beginning of the buffer, and return its length and entries.
+    ///
+    /// A batch record is `| body_len (u32) | body | checksum of body (u32) |`, where the body is
+    /// `| count (u32) | commit_ts (u64) | (key_len (u16) | key | value_len (u16) | value) * count |`.
+    pub(crate) fn decode_batch(buf: &[u8]) -> Result<(usize, Vec<(KeyBytes, Bytes)>)> {
+        let Some(record_len) = WalFormat::Batch.record_len(buf) else {
+            bail!("incomplete record header");
+        };
+        if buf.len() < record_len {
+            bail!("incomplete record");
+        }
+        let mut body = &buf[LEN_SIZE..record_len - CHECKSUM_SIZE];
+        let checksum = (&buf[record_len - CHECKSUM_SIZE..]).get_u32();
+        if crc32fast::hash(body) != checksum {
+            bail!("checksum mismatch");
+        }
+
+        ensure!(body.remaining() >= 12, "malformed WAL batch");
+        let count = body.get_u32() as usize;
+        let ts = body.get_u64();
+        let mut batch = Vec::new();
+        for _ in 0..count {
+            ensure!(body.remaining() >= 2, "malformed WAL batch");
+            let key_len = body.get_u16() as usize;
+            ensure!(body.remaining() >= key_len + 2, "malformed WAL batch");
+            let key = Bytes::copy_from_slice(&body[..key_len]);
+            body.advance(key_len);
+            let value_len = body.get_u16() as usize;
+            ensure!(body.remaining() >= value_len, "malformed WAL batch");
+            let value = Bytes::copy_from_slice(&body[..value_len]);
+            body.advance(value_len);
+            batch.push((KeyBytes::from_bytes_with_ts(key, ts), value));
+        }
+        ensure!(!body.has_remaining(), "malformed WAL batch");
+        Ok((record_len, batch))
+    }
+
+    /// Decode the record of a WAL written before batch records at the beginning of the buffer, and return
+    /// its length and entry.
+    fn decode_legacy_record(buf: &[u8]) -> Result<(usize, Vec<(KeyBytes, Bytes)>)> {
+        let Some(record_len) = WalFormat::Legacy.record_len(buf) else {
+            bail!("incomplete record header");
+        };
+        if buf.len() < record_len {
+            bail!("incomplete record");
+        }
+        let mut rbuf = &buf[..record_len];
+        let mut hasher = crc32fast::Hasher::new();
+        let key_len = rbuf.get_u16();
+        hasher.write_u16(key_len);
+        let key = Bytes::copy_from_slice(&rbuf[..key_len as usize]);
+        hasher.write(&key);
+        rbuf.advance(key_len as usize);
+        let ts = rbuf.get_u64();
+        hasher.write_u64(ts);
+        let value_len = rbuf.get_u16();
+        hasher.write_u16(value_len);
+        let value = Bytes::copy_from_slice(&rbuf[..value_len as usize]);
+        hasher.write(&value);
+        rbuf.advance(value_len as usize);
+        if hasher.finalize() != rbuf.get_u32() {
+            bail!("checksum mismatch");
+        }
+        Ok((
+            record_len,
+            vec![(KeyBytes::from_bytes_with_ts(key, ts), value)],
+        ))
     }
 
     pub fn put(&self, key: KeySlice, value: &[u8]) -> Result<()> {
+        self.put_batch(&[(key, value)])
+    }
+
+    /// Write the key-value pairs of one commit as a single batch record, so that recovery either sees all
+    /// of them or none of them. All keys must have the same timestamp.
+    pub fn put_batch(&self, data: &[(KeySlice, &[u8])]) -> Result<()> {
+        self.put_batches(&[data])
+    }
+
+    /// Write the batch records of a commit group with a single append.
+    pub fn put_batches(&self, batches: &[&[(KeySlice, &[u8])]]) -> Result<()> {
+        let mut buf = Vec::new();
+        for data in batches {
+            Self::encode_batch(data, &mut buf)?;
+        }
         let mut file = self.file.lock();
-        let mut buf: Vec<u8> =
-            Vec::with_capacity(key.raw_len() + value.len() + std::mem::size_of::<u16>());
-        let mut hasher = crc32fast::Hasher::new();
-        hasher.write_u16(key.key_len() as u16);
-        buf.put_u16(key.key_len() as u16);
-        hasher.write(key.key_ref());
-        buf.put_slice(key.key_ref());
-        hasher.write_u64(key.ts());
-        buf.put_u64(key.ts());
-        hasher.write_u16(value.len() as u16);
-        buf.put_u16(value.len() as u16);
-        buf.put_slice(value);
-        hasher.write(value);
-        // add checksum: week 2 day 7
-        buf.put_u32(hasher.finalize());
-        file.write_all(&buf)?;
+        file.append(&buf)?;
+        Ok(())
+    }
+
+    pub(crate) fn encode_batch(data: &[(KeySlice, &[u8])], buf: &mut Vec<u8>) -> Result<()> {
+        let Some((first_key, _)) = data.first() else {
+            return Ok(());
+        };
+        let ts = first_key.ts();
+        let mut body = Vec::with_capacity(
+            std::mem::size_of::<u32>()
+                + std::mem::size_of::<u64>()
+                + data
+                    .iter()
+                    .map(|(key, value)| {
+                        key.key_len() + value.len() + std::mem::size_of::<u16>() * 2
+                    })
+                    .sum::<usize>(),
+        );
+        body.put_u32(data.len() as u32);
+        body.put_u64(ts);
+        for (key, value) in data {
+            if key.ts() != ts {
+                bail!("all keys in a WAL batch must have the same timestamp");
+            }
+            body.put_u16(key.key_len() as u16);
+            body.put_slice(key.key_ref());
+            body.put_u16(value.len() as u16);
+            body.put_slice(value);
+        }
+        Self::encode_record(&body, buf);
         Ok(())
     }
 
+    fn encode_record(body: &[u8], buf: &mut Vec<u8>) {
+        buf.put_u32(body.len() as u32);
+        buf.put_slice(body);
+        // add checksum: week 2 day 7
+        buf.put_u32(crc32fast::hash(body));
+    }
+
+    /// Record that all batches committed after `ts` are in this WAL or a later one, so that `updates_since`
+    /// knows whether the discarded WALs before it are needed. It is written before any batch as a batch
+    /// record without keys, which recovery skips.
+    pub fn put_start_ts(&self, ts: u64) -> Result<()> {
+        let mut body = Vec::with_capacity(START_TS_BODY_SIZE);
+        body.put_u32(0);
+        body.put_u64(ts);
+        let mut buf = Vec::new();
+        Self::encode_record(&body, &mut buf);
+        self.file.lock().append(&buf)
+    }
+
+    /// Read the ts recorded by `put_start_ts` at the beginning of the WAL. WALs created before start
+    /// timestamps are recorded have none.
+    pub(crate) fn read_start_ts(file: &dyn RandomAccessFile) -> Result<Option<u64>> {
+        let len = WAL_HEADER.len() + LEN_SIZE + START_TS_BODY_SIZE + CHECKSUM_SIZE;
+        let buf = file.read_at(0, len.min(file.size()? as usize))?;
+        let Some(record) = buf.strip_prefix(WAL_HEADER) else {
+            return Ok(None);
+        };
+        match Self::decode_batch(record) {
+            Ok((_, batch)) if batch.is_empty() => Ok(Some(
+                (&record[LEN_SIZE + std::mem::size_of::<u32>()..]).get_u64(),
+            )),
+            _ => Ok(None),
+        }
+    }
+
     pub fn sync(&self) -> Result<()> {
+        self.file.lock().sync()
+    }
+
+    /// The length of the WAL after flushing the buffered records, which always ends at a record boundary.
+    pub fn flushed_len(&self) -> Result<u64> {
         let mut file = self.file.lock();
         file.flush()?;
-        file.get_mut().sync_all()?;
-        Ok(())
+        Ok(file.size())
+    }
+}
+
+/// A batch of writes committed together, as read back from the WALs.
+pub struct WalBatch {
+    pub commit_ts: u64,
+    pub records: Vec<WriteBatchRecord<Bytes>>,
+}
+
+/// Iterates over the committed batches in a sequence of WAL files, in commit order.
+pub struct WalBatchIterator {
+    files: VecDeque<Arc<dyn RandomAccessFile>>,
+    batches: VecDeque<WalBatch>,
+    end_ts: u64,
+}
+
+impl WalBatchIterator {
+    /// Create an iterator over the batches committed after `start_ts` and no later than `end_ts`. Fails if
+    /// some of them are no longer in the WALs.
+    pub(crate) fn create(
+        files: Vec<Arc<dyn RandomAccessFile>>,
+        start_ts: u64,
+        end_ts: u64,
+    ) -> Result<Self> {
+        let retained_after_ts = match files.first() {
+            Some(file) => Wal::read_start_ts(&**file)?,
+            None => None,
+        };
+        let mut iter = Self {
+            files: files.into(),
+            batches: VecDeque::new(),
+            end_ts,
+        };
+        let mut earliest_ts = None;
+        while let Some(batch) = iter.next_batch()? {
+            earliest_ts.get_or_insert(batch.commit_ts);
+            if batch.commit_ts > start_ts {
+                iter.batches.push_front(batch);
+                break;
+            }
+        }
+        // Commit timestamps are not always consecutive, so the earliest retained batch only tells whether
+        // updates are discarded in a WAL created before start timestamps are recorded.
+        let retained_after_ts = retained_after_ts
+            .unwrap_or_else(|| earliest_ts.unwrap_or(end_ts + 1).saturating_sub(1));
+        if start_ts < retained_after_ts {
+            bail!(
+                "updates after ts {} are discarded, only updates after ts {} are retained",
+                start_ts,
+                retained_after_ts
+            );
+        }
+        Ok(iter)
+    }
+
+    fn next_batch(&mut self) -> Result<Option<WalBatch>> {
+        loop {
+            if let Some(batch) = self.batches.pop_front() {
+                if batch.commit_ts > self.end_ts {
+                    self.batches.clear();
+                    self.files.clear();
+                    return Ok(None);
+                }
+                return Ok(Some(batch));
+            }
+            let Some(file) = self.files.pop_front() else {
+                return Ok(None);
+            };
+            let buf = file.read_at(0, file.size()? as usize)?;
+            let (format, mut offset) = WalFormat::detect(&buf);
+            while offset < buf.len() {
+                let (record_len, data) = match format.decode_record(&buf[offset..]) {
+                    Ok(record) => record,
+                    // the WAL of the current memtable may end with a batch being written
+                    Err(_) if self.files.is_empty() => break,
+                    Err(e) => return Err(e),
+                };
+                offset += record_len;
+                // the start ts of the WAL is not a batch
+                let Some(commit_ts) = data.first().map(|(key, _)| key.ts()) else {
+                    continue;
+                };
+                let records = data
+                    .into_iter()
+                    .map(|(key, value)| {
+                        if value.is_empty() {
+                            WriteBatchRecord::Del(key.into_inner())
+                        } else {
+                            WriteBatchRecord::Put(key.into_inner(), value)
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                match self.batches.back_mut() {
+                    // a WAL written before batch records has a record for each key of a batch
+                    Some(batch) if format == WalFormat::Legacy && batch.commit_ts == commit_ts => {
+                        batch.records.extend(records)
+                    }
+                    _ => self.batches.push_back(WalBatch { commit_ts, records }),
+                }
+            }
+        }
+    }
+}
+
+impl Iterator for WalBatchIterator {
+    type Item = Result<WalBatch>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_batch().transpose()
     }
 }
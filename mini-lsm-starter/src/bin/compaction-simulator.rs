@@ -242,6 +242,7 @@ fn main() {
                     size_ratio_percent,
                     level0_file_num_compaction_trigger,
                     max_levels,
+                    level0_file_num_intra_compaction_trigger: None,
                 });
             let mut storage = MockStorage::new();
             for i in 0..max_levels {
@@ -435,6 +436,7 @@ fn main() {
                 level_size_multiplier,
                 max_levels,
                 base_level_size_mb,
+                level0_file_num_intra_compaction_trigger: None,
             });
 
             let mut storage = MockStorage::new();
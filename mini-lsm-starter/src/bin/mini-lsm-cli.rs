@@ -317,8 +317,6 @@ fn main() -> Result<()> {
     let lsm = MiniLsm::open(
         args.path,
         LsmStorageOptions {
-            block_size: 4096,
-            target_sst_size: 2 << 20, // 2MB
             num_memtable_limit: 3,
             compaction_options: match args.compaction {
                 CompactionStrategy::None => CompactionOptions::NoCompaction,
@@ -327,6 +325,7 @@ fn main() -> Result<()> {
                         size_ratio_percent: 200,
                         level0_file_num_compaction_trigger: 2,
                         max_levels: 4,
+                        level0_file_num_intra_compaction_trigger: None,
                     })
                 }
                 CompactionStrategy::Tiered => CompactionOptions::Tiered(TieredCompactionOptions {
@@ -341,11 +340,13 @@ fn main() -> Result<()> {
                         max_levels: 4,
                         base_level_size_mb: 128,
                         level_size_multiplier: 2,
+                        level0_file_num_intra_compaction_trigger: None,
                     })
                 }
             },
             enable_wal: args.enable_wal,
             serializable: args.serializable,
+            ..LsmStorageOptions::default_for_week1_test()
         },
     )?;
 
@@ -20,6 +20,9 @@ pub struct LeveledCompactionOptions {
     pub level0_file_num_compaction_trigger: usize,
     pub max_levels: usize,
     pub base_level_size_mb: usize,
+    /// Merge L0 SSTs into a single L0 SST when the number of L0 SSTs reaches this number, which
+    /// should be larger than `level0_file_num_compaction_trigger`. `None` disables intra-L0 compaction.
+    pub level0_file_num_intra_compaction_trigger: Option<usize>,
 }
 
 pub struct LeveledCompactionController {
@@ -311,6 +311,7 @@ pub fn check_compaction_ratio(storage: Arc<MiniLsm>) {
             size_ratio_percent,
             level0_file_num_compaction_trigger,
             max_levels,
+            ..
         }) => {
             assert!(l0_sst_num < level0_file_num_compaction_trigger);
             assert!(level_size.len() <= max_levels);
@@ -17,6 +17,7 @@ fn test_integration() {
                 level0_file_num_compaction_trigger: 2,
                 max_levels: 3,
                 size_ratio_percent: 200,
+                level0_file_num_intra_compaction_trigger: None,
             },
         )),
     )
@@ -18,6 +18,7 @@ fn test_integration() {
                 level_size_multiplier: 2,
                 base_level_size_mb: 1,
                 max_levels: 4,
+                level0_file_num_intra_compaction_trigger: None,
             },
         )),
     )
@@ -16,6 +16,7 @@ fn test_integration_leveled() {
         level0_file_num_compaction_trigger: 2,
         max_levels: 3,
         base_level_size_mb: 1,
+        level0_file_num_intra_compaction_trigger: None,
     }))
 }
 
@@ -35,6 +36,7 @@ fn test_integration_simple() {
         size_ratio_percent: 200,
         level0_file_num_compaction_trigger: 2,
         max_levels: 3,
+        level0_file_num_intra_compaction_trigger: None,
     }));
 }
 